@@ -1,9 +1,22 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
-use cxx::{CxxString, CxxVector};
 use tracing::{debug, error};
 
+/// Integration calls that are waiting for or holding the state
+static INTEGRATION_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Integrations give up if the state is not available within this time
+static INTEGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
 macro_rules! declare_api_cmd {
     ($t:ident) => {
         impl<T> ApiImpl for $t<T> {
@@ -70,7 +83,7 @@ declare_api_cmd!(ApiQryBool1);
 
 pub struct ApiControl<State> {
     pub name: &'static str,
-    pub exec: fn(&mut State, i32, f32, &str, &CxxVector<CxxString>) -> i32,
+    pub exec: fn(&mut State, i32, f32, &str, &[String]) -> i32,
     pub default: i32,
 }
 declare_api_cmd!(ApiControl);
@@ -190,15 +203,54 @@ impl<T> ApiBuilder<T> {
         self.qry_bool_1.push(cmd);
         self
     }
+    /// The registered spelling of a command, query or control name, matched like the calls
+    pub fn registered_name(&self, name: &str) -> Option<&'static str> {
+        [self.init.name]
+            .into_iter()
+            .chain(self.cmd.iter().map(|x| x.name))
+            .chain(self.cmd_1.iter().map(|x| x.name))
+            .chain(self.cmd_2.iter().map(|x| x.name))
+            .chain(self.qry_str.iter().map(|x| x.name))
+            .chain(self.qry_str_1.iter().map(|x| x.name))
+            .chain(self.qry_lst.iter().map(|x| x.name))
+            .chain(self.qry_lst_1.iter().map(|x| x.name))
+            .chain(self.qry_bool.iter().map(|x| x.name))
+            .chain(self.qry_bool_1.iter().map(|x| x.name))
+            .chain(self.control.iter().map(|x| x.name))
+            .find(|x| cmd_matches(x, name))
+    }
+}
+
+/// Decides how an api call waits for the state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiCaller {
+    /// Papyrus natives never block on another native call, but they wait for integrations
+    Game,
+    /// Http, osc, mqtt and player sync wait for the state (for a limited time)
+    Integration,
 }
 
 pub trait Api<T> {
     fn state(&mut self) -> Arc<Mutex<Option<T>>>;
+    fn caller(&self) -> ApiCaller {
+        ApiCaller::Game
+    }
     fn fns(&self) -> ApiBuilder<T>;
     fn destroy(&mut self) -> ApiCmd0<T>;
+    /// The registered spelling of `name`, including the destroy command
+    fn registered_name(&mut self, name: &str) -> Option<&'static str> {
+        let destroy = self.destroy().name;
+        if cmd_matches(destroy, name) {
+            return Some(destroy);
+        }
+        self.fns().registered_name(name)
+    }
+    /// Invoked on the state before every api function
+    fn prepare(&self, _state: &mut T) {}
     fn exec_cmd_0(&mut self, cmd: &str) -> bool {
         if cmd_matches(self.fns().init.name, cmd) {
-            if let Ok(mut guard) = self.state().try_lock() {
+            let state = self.state();
+            if let Some(mut guard) = lock_state(&state, self.caller()) {
                 match (self.fns().init.exec)() {
                     Ok(state) => {
                         guard.replace(state);
@@ -213,11 +265,13 @@ pub trait Api<T> {
         }
 
         if cmd_matches(self.destroy().name, cmd) {
-            if let Ok(mut guard) = self.state().try_lock() {
-                if guard.take().is_some() {
-                    let api: ApiCmd0<T> = self.destroy();
-                    return self.try_exec(|tk| (api.exec)(tk), false);
-                }
+            let state = self.state();
+            // the state is destroyed outside of the lock, so that integrations
+            // that wait for it don't block the shutdown
+            let taken = lock_state(&state, self.caller()).and_then(|mut guard| guard.take());
+            if let Some(mut tk) = taken {
+                let api: ApiCmd0<T> = self.destroy();
+                return (api.exec)(&mut tk);
             }
         }
 
@@ -289,7 +343,7 @@ pub trait Api<T> {
         arg0: i32,
         arg1: f32,
         arg2: &str,
-        arg3: &CxxVector<CxxString>,
+        arg3: &[String],
     ) -> i32 {
         let c = self.fns().control;
         if let Some(api) = self.get_qry(c, qry) {
@@ -321,20 +375,23 @@ pub trait Api<T> {
         R: std::fmt::Debug
     {
         let tele = &self.state();
-        if let Ok(mut guard) = tele.try_lock() {
+        let caller = self.caller();
+        let mut result = None;
+        if let Some(mut guard) = lock_state(tele, caller) {
             match guard.take() {
                 Some(mut tk) => {
-                    let result = func(&mut tk);
+                    self.prepare(&mut tk);
+                    let value = func(&mut tk);
                     guard.replace(tk);
-                    debug!("result: {:?}", result);
-                    return result;
+                    debug!("result: {:?}", value);
+                    result = Some(value);
                 }
                 None => error!("dispatch on 'None'"),
             }
         } else {
-            error!("failed locking mutex");
+            error!(?caller, "failed locking mutex");
         }
-        default
+        result.unwrap_or(default)
     }
 
     fn fail_dispatch<D>(&self, default: D) -> D {
@@ -343,6 +400,86 @@ pub trait Api<T> {
     }
 }
 
+/// Locks the state for the caller, integrations are counted until the guard is dropped
+fn lock_state<T>(state: &Mutex<Option<T>>, caller: ApiCaller) -> Option<ApiGuard<'_, T>> {
+    match caller {
+        ApiCaller::Game => lock_for_game(state).map(|guard| ApiGuard {
+            guard: Some(guard),
+            caller,
+        }),
+        ApiCaller::Integration => {
+            INTEGRATION_CALLS.fetch_add(1, Ordering::SeqCst);
+            let guard = lock_for_integration(state);
+            if guard.is_none() {
+                INTEGRATION_CALLS.fetch_sub(1, Ordering::SeqCst);
+            }
+            guard.map(|guard| ApiGuard {
+                guard: Some(guard),
+                caller,
+            })
+        }
+    }
+}
+
+/// Only `None` while it is dropped
+struct ApiGuard<'a, T> {
+    guard: Option<MutexGuard<'a, Option<T>>>,
+    caller: ApiCaller,
+}
+
+impl<T> Deref for ApiGuard<'_, T> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_ref().expect("guard is not dropped")
+    }
+}
+
+impl<T> DerefMut for ApiGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_mut().expect("guard is not dropped")
+    }
+}
+
+impl<T> Drop for ApiGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock first, the game waits as long as an integration is counted
+        self.guard.take();
+        if self.caller == ApiCaller::Integration {
+            INTEGRATION_CALLS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Fails if another native call holds the state, integrations only hold it
+/// briefly and are waited for
+fn lock_for_game<T>(state: &Mutex<Option<T>>) -> Option<MutexGuard<'_, Option<T>>> {
+    match state.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::WouldBlock) if INTEGRATION_CALLS.load(Ordering::SeqCst) > 0 => {
+            state.lock().ok()
+        }
+        // the integration may have finished in between
+        Err(TryLockError::WouldBlock) => state.try_lock().ok(),
+        Err(TryLockError::Poisoned(_)) => None,
+    }
+}
+
+/// Polls instead of blocking so that the game gets the state first and
+/// a disconnect (which drops the runtime of the integrations) can't deadlock
+fn lock_for_integration<T>(state: &Mutex<Option<T>>) -> Option<MutexGuard<'_, Option<T>>> {
+    let started = Instant::now();
+    loop {
+        match state.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::WouldBlock) if started.elapsed() < INTEGRATION_LOCK_TIMEOUT => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -444,5 +581,16 @@ mod tests {
         assert_eq!(api.exec_qry_lst_1("existing.query", "p")[0], "list1");
         assert!(api.exec_qry_bool("existing.query"));
         assert!(api.exec_qry_bool_1("existing.query", "something"));
+        assert_eq!(api.exec_control("move", 100, 1.0, "", &[String::from("clit")]), 2);
+    }
+
+    #[test]
+    fn registered_names() {
+        let mut api = TestApi::new();
+        assert_eq!(api.registered_name("Existing.Query"), Some("existing.query"));
+        assert_eq!(api.registered_name("VIBRATE"), Some("vibrate"));
+        assert_eq!(api.registered_name("ctor"), Some("ctor"));
+        assert_eq!(api.registered_name("dtor"), Some("dtor"));
+        assert_eq!(api.registered_name("non.existing.command"), None);
     }
 }
@@ -0,0 +1,365 @@
+use std::{
+    f64::consts::PI,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use funscript::{FSPoint, FScript};
+use lewton::inside_ogg::OggStreamReader;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::pattern::{read_pattern_name, script_length, write_pattern};
+
+/// Generated patterns are stored as `audio.<file name>.vibrator.funscript`
+pub static AUDIO_PATTERN_PREFIX: &str = "audio.";
+
+static DEFAULT_WINDOW_MS: u64 = 100;
+/// Levels below this (relative to the loudest window) are treated as silence
+static NOISE_FLOOR: f64 = 0.05;
+
+/// Options that may follow the audio file, i.e. `Data\Sound\voice.wav?low=80&high=250&window=50`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TkAudioAnalysis {
+    /// Band filter in Hz, applied if either bound is given
+    pub low_hz: Option<f64>,
+    pub high_hz: Option<f64>,
+    /// Length of the windows the amplitude envelope is computed for
+    pub window_ms: u64,
+}
+
+impl Default for TkAudioAnalysis {
+    fn default() -> Self {
+        Self {
+            low_hz: None,
+            high_hz: None,
+            window_ms: DEFAULT_WINDOW_MS,
+        }
+    }
+}
+
+impl TkAudioAnalysis {
+    /// Splits `input` into the audio file path and the analysis options
+    pub fn parse(input: &str) -> Result<(&str, TkAudioAnalysis), String> {
+        let Some((path, options)) = input.split_once('?') else {
+            return Ok((input.trim(), TkAudioAnalysis::default()));
+        };
+        let mut analysis = TkAudioAnalysis::default();
+        for option in options
+            .split('&')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+        {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            let number = value
+                .parse::<f64>()
+                .ok()
+                .filter(|x| *x > 0.0)
+                .ok_or_else(|| format!("Invalid {} '{}' in '{}'", key, value, input))?;
+            match key.to_lowercase().as_str() {
+                "low" => analysis.low_hz = Some(number),
+                "high" => analysis.high_hz = Some(number),
+                "window" => analysis.window_ms = number as u64,
+                _ => return Err(format!("Unknown option '{}' in '{}'", key, input)),
+            }
+        }
+        Ok((path.trim(), analysis))
+    }
+
+    /// Name of the cached pattern, different options result in different patterns.
+    /// Contains a hash of the full path, files with the same name in different
+    /// folders don't share a pattern
+    pub fn pattern_name(&self, audio_path: &str) -> String {
+        let path = Path::new(audio_path);
+        let stem = path.file_stem().and_then(|x| x.to_str()).unwrap_or("audio");
+        let full_path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let hash = Sha256::digest(full_path.to_string_lossy().as_bytes())
+            .iter()
+            .take(4)
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        let mut name = format!("{}{}_{}", AUDIO_PATTERN_PREFIX, stem, hash);
+        if self.low_hz.is_some() || self.high_hz.is_some() {
+            name.push_str(&format!(
+                "_{}-{}Hz",
+                self.low_hz.unwrap_or(0.0),
+                self.high_hz.map(|x| x.to_string()).unwrap_or_default()
+            ));
+        }
+        if self.window_ms != DEFAULT_WINDOW_MS {
+            name.push_str(&format!("_{}ms", self.window_ms));
+        }
+        name
+    }
+
+    /// Vibration pattern that follows the amplitude envelope of the samples
+    pub fn envelope(&self, samples: &[f32], sample_rate: u32) -> FScript {
+        let filtered;
+        let samples = if self.low_hz.is_some() || self.high_hz.is_some() {
+            let nyquist = sample_rate as f64 / 2.0;
+            let low = self.low_hz.unwrap_or(20.0).min(nyquist * 0.9);
+            let high = self
+                .high_hz
+                .unwrap_or(nyquist * 0.9)
+                .clamp(low + 1.0, nyquist * 0.95);
+            filtered = band_pass(samples, sample_rate, low, high);
+            &filtered
+        } else {
+            samples
+        };
+        let window = ((sample_rate as u64 * self.window_ms) / 1000).max(1) as usize;
+        let levels = samples
+            .chunks(window)
+            .map(|chunk| {
+                let sum = chunk.iter().map(|x| (*x as f64).powi(2)).sum::<f64>();
+                (sum / chunk.len() as f64).sqrt()
+            })
+            .collect::<Vec<f64>>();
+        let peak = levels.iter().cloned().fold(0.0, f64::max);
+        let mut actions = levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let relative = if peak > 0.0 { level / peak } else { 0.0 };
+                FSPoint {
+                    pos: if relative < NOISE_FLOOR {
+                        0
+                    } else {
+                        (relative * 100.0).round() as i32
+                    },
+                    at: (i as u64 * self.window_ms) as i32,
+                }
+            })
+            .collect::<Vec<FSPoint>>();
+        // stop at the end of the audio
+        actions.push(FSPoint {
+            pos: 0,
+            at: (samples.len() as u64 * 1000 / sample_rate.max(1) as u64) as i32,
+        });
+        FScript {
+            actions,
+            ..Default::default()
+        }
+    }
+}
+
+/// Name of the pattern for `input`, without reading the audio file
+pub fn get_audio_pattern_name(input: &str) -> Result<String, String> {
+    let (audio_path, analysis) = TkAudioAnalysis::parse(input)?;
+    Ok(analysis.pattern_name(audio_path))
+}
+
+/// Reads the pattern generated for `input` (see `TkAudioAnalysis`), the pattern is only
+/// regenerated if the audio file changed. Analyzing long files takes a while,
+/// so this should not be called on the game thread
+pub fn load_audio_pattern(pattern_path: &str, input: &str) -> Result<FScript, String> {
+    let (audio_path, analysis) = TkAudioAnalysis::parse(input)?;
+    let name = analysis.pattern_name(audio_path);
+    let cached = [pattern_path, &format!("{}.vibrator.funscript", name)]
+        .iter()
+        .collect::<PathBuf>();
+    if is_newer(&cached, Path::new(audio_path)) {
+        if let Ok(fscript) = read_pattern_name(pattern_path, &name, true) {
+            debug!(%name, "using cached audio pattern");
+            return Ok(fscript);
+        }
+    }
+    let (samples, sample_rate) = read_samples(Path::new(audio_path))?;
+    let fscript = analysis.envelope(&samples, sample_rate);
+    write_pattern(pattern_path, &name, true, &fscript)
+        .map_err(|err| format!("Could not write pattern '{}': {}", name, err))?;
+    info!(
+        %name,
+        length_ms = script_length(&fscript),
+        "generated audio pattern"
+    );
+    Ok(fscript)
+}
+
+/// Audio patterns play once, so the task ends with the audio
+pub fn audio_duration(fscript: &FScript, requested: Duration) -> Duration {
+    requested.min(Duration::from_millis(script_length(fscript)))
+}
+
+fn is_newer(path: &Path, than: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+    match (modified(path), modified(than)) {
+        (Some(cached), Some(audio)) => cached >= audio,
+        _ => false,
+    }
+}
+
+/// Mono samples from `-1.0` to `1.0` and the sample rate of a `.wav` or `.ogg` file
+pub fn read_samples(path: &Path) -> Result<(Vec<f32>, u32), String> {
+    let error = |err: String| format!("Could not read '{}': {}", path.display(), err);
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let (samples, channels, sample_rate) = match extension.as_str() {
+        "wav" => read_wav(path).map_err(error)?,
+        "ogg" => read_ogg(path).map_err(error)?,
+        _ => return Err(error(String::from("only .wav and .ogg are supported"))),
+    };
+    let mono = samples
+        .chunks(channels.max(1))
+        .map(|x| x.iter().sum::<f32>() / x.len() as f32)
+        .collect();
+    Ok((mono, sample_rate))
+}
+
+fn read_wav(path: &Path) -> Result<(Vec<f32>, usize, u32), String> {
+    let mut reader = hound::WavReader::open(path).map_err(|err| err.to_string())?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|err| err.to_string())?,
+        hound::SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample.max(1) - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|x| x.map(|x| x as f32 / scale))
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|err| err.to_string())?
+        }
+    };
+    Ok((samples, spec.channels as usize, spec.sample_rate))
+}
+
+fn read_ogg(path: &Path) -> Result<(Vec<f32>, usize, u32), String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut reader = OggStreamReader::new(file).map_err(|err| err.to_string())?;
+    let channels = reader.ident_hdr.audio_channels as usize;
+    let sample_rate = reader.ident_hdr.audio_sample_rate;
+    let mut samples = vec![];
+    while let Some(packet) = reader
+        .read_dec_packet_itl()
+        .map_err(|err| err.to_string())?
+    {
+        samples.extend(packet.into_iter().map(|x| x as f32 / 32768.0));
+    }
+    Ok((samples, channels, sample_rate))
+}
+
+/// Second order band pass (RBJ cookbook) between `low` and `high` Hz
+fn band_pass(samples: &[f32], sample_rate: u32, low: f64, high: f64) -> Vec<f32> {
+    let center = (low * high).sqrt();
+    let q = center / (high - low);
+    let w0 = 2.0 * PI * center / sample_rate as f64;
+    let alpha = w0.sin() / (2.0 * q);
+    let a0 = 1.0 + alpha;
+    let (b0, b2) = (alpha / a0, -alpha / a0);
+    let (a1, a2) = (-2.0 * w0.cos() / a0, (1.0 - alpha) / a0);
+    let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+    samples
+        .iter()
+        .map(|x| {
+            let x0 = *x as f64;
+            let y0 = b0 * x0 + b2 * x2 - a1 * y1 - a2 * y2;
+            (x2, x1) = (x1, x0);
+            (y2, y1) = (y1, y0);
+            y0 as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    static SAMPLE_RATE: u32 = 8000;
+
+    fn tone(frequency: f64, millis: u32) -> Vec<f32> {
+        (0..SAMPLE_RATE * millis / 1000)
+            .map(|i| (2.0 * PI * frequency * i as f64 / SAMPLE_RATE as f64).sin() as f32)
+            .collect()
+    }
+
+    fn write_wav(path: &Path, samples: &[f32]) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for sample in samples {
+            writer.write_sample((sample * 16000.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn position_at(fscript: &FScript, at: i32) -> i32 {
+        fscript.actions.iter().find(|x| x.at == at).unwrap().pos
+    }
+
+    #[test]
+    fn envelope_follows_amplitude() {
+        let mut samples = tone(200.0, 500);
+        samples.extend(tone(200.0, 500).iter().map(|x| x * 0.5));
+        samples.extend(vec![0.0; SAMPLE_RATE as usize / 2]);
+        let fscript = TkAudioAnalysis::default().envelope(&samples, SAMPLE_RATE);
+        assert_eq!(position_at(&fscript, 200), 100);
+        assert_eq!(position_at(&fscript, 700), 50);
+        assert_eq!(position_at(&fscript, 1200), 0);
+        assert_eq!(script_length(&fscript), 1500);
+    }
+
+    #[test]
+    fn band_filter_removes_other_frequencies() {
+        let mut samples = tone(150.0, 500);
+        samples.extend(tone(2000.0, 500));
+        let analysis = TkAudioAnalysis {
+            low_hz: Some(80.0),
+            high_hz: Some(250.0),
+            ..Default::default()
+        };
+        let fscript = analysis.envelope(&samples, SAMPLE_RATE);
+        assert!(position_at(&fscript, 300) > 90);
+        assert!(position_at(&fscript, 800) < 20);
+    }
+
+    #[test]
+    fn audio_pattern_is_cached() {
+        let dir = tempdir().unwrap();
+        let audio = dir.path().join("moan.wav");
+        write_wav(&audio, &tone(200.0, 1000));
+        let patterns = dir.path().join("patterns");
+        let patterns = patterns.to_str().unwrap();
+
+        let input = format!("{}?low=100&high=300", audio.display());
+        let name = get_audio_pattern_name(&input).unwrap();
+        let fscript = load_audio_pattern(patterns, &input).unwrap();
+        assert!(name.starts_with("audio.moan_"));
+        assert!(name.ends_with("_100-300Hz"));
+        assert_eq!(script_length(&fscript), 1000);
+        assert!(crate::pattern::get_pattern_names(patterns, true).contains(&name));
+
+        let cached = load_audio_pattern(patterns, &input).unwrap();
+        assert_eq!(cached.actions.len(), fscript.actions.len());
+        assert!(load_audio_pattern(patterns, "missing.wav").is_err());
+        assert!(load_audio_pattern(patterns, "moan.mp3").is_err());
+        assert!(get_audio_pattern_name("moan.wav?window=x").is_err());
+    }
+
+    #[test]
+    fn same_file_names_in_different_folders() {
+        let dir = tempdir().unwrap();
+        let analysis = TkAudioAnalysis::default();
+        let first = dir.path().join("a").join("moan.wav");
+        let second = dir.path().join("b").join("moan.wav");
+        assert_ne!(
+            analysis.pattern_name(first.to_str().unwrap()),
+            analysis.pattern_name(second.to_str().unwrap())
+        );
+        assert_eq!(
+            analysis.pattern_name(first.to_str().unwrap()),
+            analysis.pattern_name(first.to_str().unwrap())
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Drives Telekinesis without Skyrim, either interactively or from a script file.
+//!
+//! Every line calls one of the papyrus natives of `Tele_Api.psc`, followed by its
+//! whitespace separated arguments (use `"..."` for arguments with spaces):
+//!
+//! ```text
+//! Cmd connect
+//! Cmd start_scan
+//! Sleep 1.5
+//! Qry_Lst devices
+//! Cmd_2 device.settings.events "vib1 (Vibrate)" "clit,nipple*"
+//! Tele_Control vibrate 100 2.0 "" clit,nipple*
+//! Tele_Stop 1
+//! ```
+//!
+//! Events (`tk_qry_nxt_evt`) are printed as soon as they arrive.
+//!
+//! `--replay <file>` executes a recording (see `recorder.start`) with its original
+//! timing on the fake devices and reports every actuator that receives different
+//! values than recorded. `--connection` is ignored for replays.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
+
+use telekinesis_plug::cli::{read_recording, TkCli, TkConnectionType, SETTINGS_PATH};
+
+static USAGE: &str = "Usage: tele-cli [--script <file> | --replay <file> [--speedup <factor>]] [--settings <dir>] [--connection test|inprocess|<websocket>] [--log]";
+
+struct Options {
+    script: Option<String>,
+    replay: Option<String>,
+    speedup: f64,
+    settings_path: String,
+    connection: Option<TkConnectionType>,
+    log: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            script: None,
+            replay: None,
+            speedup: 1.0,
+            settings_path: String::from(SETTINGS_PATH),
+            connection: default_connection(),
+            log: false,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--script" => options.script = Some(value()?),
+                "--replay" => options.replay = Some(value()?),
+                "--speedup" => {
+                    options.speedup = value()?
+                        .parse()
+                        .map_err(|_| String::from("--speedup expects a number"))?
+                }
+                "--settings" => options.settings_path = value()?,
+                "--connection" => {
+                    options.connection = Some(match value()?.to_lowercase().as_str() {
+                        "test" => TkConnectionType::Test,
+                        "inprocess" => TkConnectionType::InProcess,
+                        endpoint => TkConnectionType::WebSocket(endpoint.into()),
+                    })
+                }
+                "--log" => options.log = true,
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Without hardware (and on CI) the fake devices of the `testing` feature are used
+#[cfg(feature = "testing")]
+fn default_connection() -> Option<TkConnectionType> {
+    Some(TkConnectionType::Test)
+}
+
+#[cfg(not(feature = "testing"))]
+fn default_connection() -> Option<TkConnectionType> {
+    None
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    if options.log {
+        TkCli::init_logging_stdout();
+    }
+
+    let mut cli = TkCli::new(&options.settings_path, options.connection.clone());
+    print_events(cli.handle());
+    if let Some(path) = &options.replay {
+        return replay_recording(&mut cli, options.speedup, path);
+    }
+
+    let input: Box<dyn BufRead> = match &options.script {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("Could not open script '{}': {}", path, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+    let interactive = options.script.is_none();
+    let mut failed = false;
+    prompt(interactive);
+    for line in input.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let args = split_args(&line);
+        if args.is_empty() || args[0].starts_with('#') {
+            prompt(interactive);
+            continue;
+        }
+        if args[0].eq_ignore_ascii_case("quit") || args[0].eq_ignore_ascii_case("exit") {
+            break;
+        }
+        match execute(&mut cli, &args) {
+            Ok(result) => println!("{}", result),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                failed = true;
+            }
+        }
+        prompt(interactive);
+    }
+    cli.disconnect();
+    if failed && !interactive {
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn replay_recording(cli: &mut TkCli, speedup: f64, path: &str) -> ExitCode {
+    let calls = match read_recording(path) {
+        Ok(calls) => calls,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mismatches = cli.replay(&calls, speedup, |call| {
+        println!("{:>8}ms {} {}", call.at_ms, call.call, call.args.join(" "));
+    });
+    for mismatch in &mismatches {
+        println!(
+            "mismatch on {}: sent {} instead of {}",
+            mismatch.output,
+            mismatch.actual.join(","),
+            mismatch.expected.join(",")
+        );
+    }
+    println!(
+        "replayed {} calls, {} outputs with mismatches",
+        calls.len(),
+        mismatches.len()
+    );
+    if mismatches.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn prompt(interactive: bool) {
+    if interactive {
+        print!("> ");
+        let _ = io::stdout().flush();
+    }
+}
+
+fn execute(cli: &mut TkCli, args: &[String]) -> Result<String, String> {
+    let arg = |i: usize| {
+        args.get(i)
+            .map(|x| x.as_str())
+            .ok_or_else(|| format!("{} expects {} argument(s)", args[0], i))
+    };
+    let number = |i: usize| -> Result<f32, String> {
+        arg(i)?
+            .parse::<f32>()
+            .map_err(|_| format!("'{}' is not a number", args[i]))
+    };
+    match args[0].to_lowercase().as_str() {
+        "cmd" => Ok(cli.cmd(arg(1)?).to_string()),
+        "cmd_1" => Ok(cli.cmd_1(arg(1)?, arg(2)?).to_string()),
+        "cmd_2" => Ok(cli.cmd_2(arg(1)?, arg(2)?, arg(3)?).to_string()),
+        "qry_str" => Ok(cli.qry_str(arg(1)?)),
+        "qry_str_1" => Ok(cli.qry_str_1(arg(1)?, arg(2)?)),
+        "qry_lst" => Ok(cli.qry_lst(arg(1)?).join("\n")),
+        "qry_lst_1" => Ok(cli.qry_lst_1(arg(1)?, arg(2)?).join("\n")),
+        "qry_bool" => Ok(cli.qry_bool(arg(1)?).to_string()),
+        "qry_bool_1" => Ok(cli.qry_bool_1(arg(1)?, arg(2)?).to_string()),
+        "tele_control" => {
+            let body_parts = match args.get(5) {
+                Some(body_parts) => body_parts.split(',').map(String::from).collect(),
+                None => vec![],
+            };
+            let handle = cli.control(
+                arg(1)?,
+                number(2)? as i32,
+                number(3)?,
+                args.get(4).map(|x| x.as_str()).unwrap_or(""),
+                &body_parts,
+            );
+            Ok(handle.to_string())
+        }
+        "tele_update" => Ok(cli.update(number(1)? as i32, number(2)? as i32).to_string()),
+        "tele_stop" => Ok(cli.stop(number(1)? as i32).to_string()),
+        "sleep" => {
+            thread::sleep(Duration::from_secs_f32(number(1)?.max(0.0)));
+            Ok(String::new())
+        }
+        _ => Err(format!("Unknown function '{}'", args[0])),
+    }
+}
+
+fn print_events(mut events: TkCli) {
+    thread::spawn(move || {
+        loop {
+            let next = events.next_events();
+            if next.is_empty() {
+                // not connected yet
+                thread::sleep(Duration::from_millis(100));
+            }
+            for event in next {
+                println!("[{}] {} {}", event.event_name, event.str_arg, event.num_arg);
+            }
+        }
+    });
+}
+
+/// Splits on whitespace, `"quoted text"` is kept as a single (possibly empty) argument
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut has_arg = false;
+    for c in line.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_quoted_args() {
+        assert_eq!(
+            split_args(r#"Tele_Control vibrate 100  2.0 "" "clit, nipple""#),
+            vec!["Tele_Control", "vibrate", "100", "2.0", "", "clit, nipple"]
+        );
+        assert!(split_args("   ").is_empty());
+    }
+
+    #[test]
+    fn connection_argument() {
+        let args = ["--connection", "Test", "--script", "a.txt"].map(String::from);
+        let options = Options::parse(args.into_iter()).unwrap();
+        assert_eq!(options.connection, Some(TkConnectionType::Test));
+        assert_eq!(options.script, Some(String::from("a.txt")));
+        assert!(Options::parse([String::from("--bogus")].into_iter()).is_err());
+    }
+}
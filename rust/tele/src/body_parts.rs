@@ -0,0 +1,373 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+use itertools::Itertools;
+use tracing::error;
+
+use crate::input::matches_wildcard;
+
+/// User defined groups, i.e. `genitals` -> `vaginal, clit, anal`
+pub type TkBodyPartGroups = BTreeMap<String, Vec<String>>;
+
+/// A single body part term:
+/// - `nipple*` wildcards
+/// - `!anal` exclusions
+/// - `vaginal+clit` requires all parts
+/// - `@genitals` group references
+/// - `nipples:0.5` weights (only meaningful for device body parts)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TkBodyPartExpr {
+    pub negated: bool,
+    pub all_of: Vec<String>,
+    pub weight: Option<f64>,
+}
+
+impl TkBodyPartExpr {
+    pub fn parse(input: &str) -> Result<TkBodyPartExpr, String> {
+        let input = input.trim().to_lowercase();
+        let (expr, weight) = split_weight(&input)?;
+        let (negated, rest) = match expr.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, expr),
+        };
+        let all_of = rest
+            .split('+')
+            .map(|x| x.trim().to_owned())
+            .collect::<Vec<String>>();
+        if all_of
+            .iter()
+            .any(|x| x.is_empty() || x == "@" || x.contains('!'))
+        {
+            return Err(format!("Invalid body part expression '{}'", input));
+        }
+        Ok(TkBodyPartExpr {
+            negated,
+            all_of,
+            weight,
+        })
+    }
+
+    /// Parses all valid expressions, invalid ones are returned as errors
+    pub fn parse_all(inputs: &[String]) -> (Vec<TkBodyPartExpr>, Vec<String>) {
+        let (exprs, errors): (Vec<_>, Vec<_>) = inputs
+            .iter()
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| TkBodyPartExpr::parse(x))
+            .partition_result();
+        (exprs, errors)
+    }
+
+    pub fn matches(&self, tags: &[String], groups: &TkBodyPartGroups) -> bool {
+        self.all_of
+            .iter()
+            .all(|atom| atom_matches(atom, tags, groups))
+    }
+}
+
+impl Display for TkBodyPartExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            if self.negated { "!" } else { "" },
+            self.all_of.join("+")
+        )?;
+        if let Some(weight) = self.weight {
+            write!(f, ":{}", weight)?;
+        }
+        Ok(())
+    }
+}
+
+/// Normalizes the expressions of a body part list, invalid ones are dropped
+pub fn sanitize_body_parts(list: &[String]) -> Vec<String> {
+    let (exprs, invalid) = TkBodyPartExpr::parse_all(list);
+    for err in invalid {
+        error!("ignored body part: {}", err);
+    }
+    exprs.iter().map(|x| x.to_string()).collect()
+}
+
+/// Devices match if any positive expression matches (or there are none)
+/// and no negated expression matches
+pub fn matches_body_parts(
+    exprs: &[TkBodyPartExpr],
+    tags: &[String],
+    groups: &TkBodyPartGroups,
+) -> bool {
+    let (excluded, included): (Vec<&TkBodyPartExpr>, Vec<&TkBodyPartExpr>) =
+        exprs.iter().partition(|x| x.negated);
+    (included.is_empty() || included.iter().any(|x| x.matches(tags, groups)))
+        && !excluded.iter().any(|x| x.matches(tags, groups))
+}
+
+/// Intensity weight of a device for the requested body parts: The highest weight
+/// of all device body parts that are selected, `1.0` for unweighted body parts
+pub fn body_part_weight(
+    exprs: &[TkBodyPartExpr],
+    tags: &[String],
+    groups: &TkBodyPartGroups,
+) -> f64 {
+    let included = exprs.iter().filter(|x| !x.negated).collect::<Vec<_>>();
+    tags.iter()
+        .filter_map(|tag| TkBodyPartExpr::parse(tag).ok())
+        .filter(|tag| {
+            let tag = vec![tag.all_of.join("+")];
+            included.is_empty()
+                || included
+                    .iter()
+                    .any(|x| x.all_of.iter().any(|atom| atom_matches(atom, &tag, groups)))
+        })
+        .map(|tag| tag.weight.unwrap_or(1.0))
+        .reduce(f64::max)
+        .unwrap_or(1.0)
+}
+
+/// Parses a group definition like `@genitals = vaginal,clit,anal`
+pub fn parse_group(definition: &str) -> Result<(String, Vec<String>), String> {
+    let (name, parts) = definition
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid group definition '{}'", definition))?;
+    let name = name.trim().trim_start_matches('@').trim().to_lowercase();
+    if name.is_empty() {
+        return Err(format!("Group name missing in '{}'", definition));
+    }
+    let parts = parts
+        .split(',')
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect();
+    Ok((name, parts))
+}
+
+/// Splits `name:weight`, weights must be positive numbers
+fn split_weight(input: &str) -> Result<(&str, Option<f64>), String> {
+    match input.rsplit_once(':') {
+        Some((expr, weight)) => match weight.trim().parse::<f64>() {
+            Ok(weight) if weight.is_finite() && weight >= 0.0 => Ok((expr.trim(), Some(weight))),
+            _ => Err(format!("Invalid body part weight in '{}'", input)),
+        },
+        None => Ok((input, None)),
+    }
+}
+
+fn atom_matches(atom: &str, tags: &[String], groups: &TkBodyPartGroups) -> bool {
+    let patterns = expand(atom, groups, 0);
+    let tags = tags
+        .iter()
+        .flat_map(|x| {
+            split_weight(x)
+                .map(|(x, _)| expand(x, groups, 0))
+                .unwrap_or_default()
+        })
+        .collect::<Vec<String>>();
+    patterns.iter().any(|pattern| {
+        tags.iter()
+            .any(|tag| matches_wildcard(pattern, tag) || matches_wildcard(tag, pattern))
+    })
+}
+
+/// Resolves (nested) group references, cyclic groups are cut off
+fn expand(atom: &str, groups: &TkBodyPartGroups, depth: usize) -> Vec<String> {
+    match atom.trim().strip_prefix('@') {
+        Some(group) if depth < 8 => groups
+            .get(group.trim())
+            .map(|parts| {
+                parts
+                    .iter()
+                    .flat_map(|x| expand(x, groups, depth + 1))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Some(_) => vec![],
+        None => vec![atom.trim().to_lowercase()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|x| String::from(*x)).collect()
+    }
+
+    fn exprs(inputs: &[&str]) -> Vec<TkBodyPartExpr> {
+        inputs
+            .iter()
+            .map(|x| TkBodyPartExpr::parse(x).unwrap())
+            .collect()
+    }
+
+    fn groups() -> TkBodyPartGroups {
+        let mut groups = TkBodyPartGroups::new();
+        groups.insert("genitals".into(), tags(&["vaginal", "clit", "anal"]));
+        groups.insert("loop".into(), tags(&["@loop"]));
+        groups
+    }
+
+    #[test]
+    fn exact_match() {
+        assert!(matches_body_parts(
+            &exprs(&["Clit"]),
+            &tags(&["clit"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(
+            &exprs(&["clit"]),
+            &tags(&["anal"]),
+            &groups()
+        ));
+    }
+
+    #[test]
+    fn empty_expressions_match_everything() {
+        assert!(matches_body_parts(&[], &tags(&["anal"]), &groups()));
+    }
+
+    #[test]
+    fn wildcards() {
+        let nipples = exprs(&["nipple*"]);
+        assert!(matches_body_parts(
+            &nipples,
+            &tags(&["nipple_left"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(&nipples, &tags(&["clit"]), &groups()));
+        assert!(matches_body_parts(
+            &exprs(&["nipple_right"]),
+            &tags(&["nipple*"]),
+            &groups()
+        ));
+    }
+
+    #[test]
+    fn exclusions() {
+        let not_anal = exprs(&["!anal"]);
+        assert!(matches_body_parts(&not_anal, &tags(&["clit"]), &groups()));
+        assert!(!matches_body_parts(
+            &not_anal,
+            &tags(&["clit", "anal"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(
+            &exprs(&["@genitals", "!anal"]),
+            &tags(&["anal"]),
+            &groups()
+        ));
+    }
+
+    #[test]
+    fn required_all() {
+        let both = exprs(&["vaginal+clit"]);
+        assert!(matches_body_parts(
+            &both,
+            &tags(&["clit", "vaginal"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(&both, &tags(&["clit"]), &groups()));
+    }
+
+    #[test]
+    fn groups_on_both_sides() {
+        assert!(matches_body_parts(
+            &exprs(&["@genitals"]),
+            &tags(&["clit"]),
+            &groups()
+        ));
+        assert!(matches_body_parts(
+            &exprs(&["anal"]),
+            &tags(&["@genitals"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(
+            &exprs(&["@unknown"]),
+            &tags(&["clit"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(
+            &exprs(&["@loop"]),
+            &tags(&["clit"]),
+            &groups()
+        ));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(TkBodyPartExpr::parse("!").is_err());
+        assert!(TkBodyPartExpr::parse("a++b").is_err());
+        assert!(TkBodyPartExpr::parse("a+!b").is_err());
+        assert_eq!(
+            TkBodyPartExpr::parse(" ! A + B ").unwrap().to_string(),
+            "!a+b"
+        );
+    }
+
+    #[test]
+    fn sanitize_drops_invalid() {
+        let parts = sanitize_body_parts(&tags(&[" Nipple* ", "!", "vaginal + clit", ""]));
+        assert_eq!(parts, tags(&["nipple*", "vaginal+clit"]));
+    }
+
+    #[test]
+    fn weights_are_parsed() {
+        assert_eq!(
+            TkBodyPartExpr::parse("Nipples : 0.5").unwrap().weight,
+            Some(0.5)
+        );
+        assert_eq!(TkBodyPartExpr::parse("clit").unwrap().weight, None);
+        assert!(TkBodyPartExpr::parse("clit:strong").is_err());
+        assert!(TkBodyPartExpr::parse("clit:-1").is_err());
+        assert_eq!(
+            sanitize_body_parts(&tags(&["Nipples : 0.5"])),
+            tags(&["nipples:0.5"])
+        );
+    }
+
+    #[test]
+    fn weighted_tags_match() {
+        assert!(matches_body_parts(
+            &exprs(&["nipple*"]),
+            &tags(&["nipples:0.5"]),
+            &groups()
+        ));
+        assert!(!matches_body_parts(
+            &exprs(&["!nipples"]),
+            &tags(&["nipples:0.5"]),
+            &groups()
+        ));
+    }
+
+    #[test]
+    fn weight_of_selected_body_parts() {
+        let device = tags(&["nipples:0.5", "clit:0.8", "anal"]);
+        assert_eq!(
+            body_part_weight(&exprs(&["nipples"]), &device, &groups()),
+            0.5
+        );
+        assert_eq!(
+            body_part_weight(&exprs(&["nipples", "clit"]), &device, &groups()),
+            0.8
+        );
+        assert_eq!(
+            body_part_weight(&exprs(&["@genitals"]), &device, &groups()),
+            1.0
+        );
+        assert_eq!(
+            body_part_weight(&[], &tags(&["nipples:0.5"]), &groups()),
+            0.5
+        );
+        assert_eq!(body_part_weight(&[], &[], &groups()), 1.0);
+    }
+
+    #[test]
+    fn group_definition() {
+        let (name, parts) = parse_group("@Genitals = vaginal, clit,anal").unwrap();
+        assert_eq!(name, "genitals");
+        assert_eq!(parts, tags(&["vaginal", "clit", "anal"]));
+        assert!(parse_group("genitals").is_err());
+        assert!(parse_group("@ = a").is_err());
+    }
+}
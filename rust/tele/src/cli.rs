@@ -0,0 +1,198 @@
+use std::{thread, time::Duration};
+
+use crate::{
+    api::ApiCaller,
+    logging::tk_init_logging_stdout,
+    recorder::{compare_outputs, replay},
+    settings::{TkSettings, SETTINGS_FILE},
+    TkApi,
+};
+
+pub use crate::{
+    recorder::{read_recording, TkRecordedCall, TkReplayMismatch},
+    settings::{TkConnectionType, SETTINGS_PATH},
+    SKSEModEvent,
+};
+
+/// Time to wait for the device output of the last replayed calls
+static REPLAY_SETTLE_MS: u64 = 500;
+
+/// The papyrus natives for `tele-cli`. Calls are recorded and measured like
+/// the calls of the game, `connect` uses the settings given on the command line
+pub struct TkCli {
+    api: TkApi,
+    settings_path: String,
+    connection: Option<TkConnectionType>,
+}
+
+impl TkCli {
+    pub fn new(settings_path: &str, connection: Option<TkConnectionType>) -> TkCli {
+        TkCli {
+            api: *crate::tk_new(),
+            settings_path: String::from(settings_path),
+            connection,
+        }
+    }
+
+    pub fn init_logging_stdout() -> bool {
+        tk_init_logging_stdout()
+    }
+
+    /// A second handle on the same connection, i.e. to wait for events on another thread
+    pub fn handle(&self) -> TkCli {
+        TkCli {
+            api: TkApi {
+                state: self.api.state.clone(),
+                caller: ApiCaller::Game,
+            },
+            settings_path: self.settings_path.clone(),
+            connection: self.connection.clone(),
+        }
+    }
+
+    /// Like the `connect` command, with the recorder and the integrations of the settings
+    pub fn connect(&mut self) -> bool {
+        let settings = self.settings();
+        self.api.tk_connect_with(settings)
+    }
+
+    /// The stored settings with the connection given on the command line
+    fn settings(&self) -> TkSettings {
+        let mut settings = TkSettings::try_read_or_default(&self.settings_path, SETTINGS_FILE);
+        if let Some(connection) = &self.connection {
+            settings.connection = connection.clone();
+        }
+        settings
+    }
+
+    /// Replays always use the fake devices, a recording must never move real hardware
+    fn replay_settings(&self) -> TkSettings {
+        let mut settings = self.settings();
+        settings.connection = TkConnectionType::Test;
+        settings
+    }
+
+    pub fn cmd(&mut self, cmd: &str) -> bool {
+        if cmd.eq_ignore_ascii_case("connect") {
+            return self.connect();
+        }
+        self.api.tk_cmd(cmd)
+    }
+
+    pub fn cmd_1(&mut self, cmd: &str, arg0: &str) -> bool {
+        self.api.tk_cmd_1(cmd, arg0)
+    }
+
+    pub fn cmd_2(&mut self, cmd: &str, arg0: &str, arg1: &str) -> bool {
+        self.api.tk_cmd_2(cmd, arg0, arg1)
+    }
+
+    pub fn qry_str(&mut self, qry: &str) -> String {
+        self.api.tk_qry_str(qry)
+    }
+
+    pub fn qry_str_1(&mut self, qry: &str, arg0: &str) -> String {
+        self.api.tk_qry_str_1(qry, arg0)
+    }
+
+    pub fn qry_lst(&mut self, qry: &str) -> Vec<String> {
+        self.api.tk_qry_lst(qry)
+    }
+
+    pub fn qry_lst_1(&mut self, qry: &str, arg0: &str) -> Vec<String> {
+        self.api.tk_qry_lst_1(qry, arg0)
+    }
+
+    pub fn qry_bool(&mut self, qry: &str) -> bool {
+        self.api.tk_qry_bool(qry)
+    }
+
+    pub fn qry_bool_1(&mut self, qry: &str, arg0: &str) -> bool {
+        self.api.tk_qry_bool_1(qry, arg0)
+    }
+
+    pub fn control(
+        &mut self,
+        qry: &str,
+        speed: i32,
+        time_sec: f32,
+        arg: &str,
+        body_parts: &[String],
+    ) -> i32 {
+        self.api
+            .tk_control_list(qry, speed, time_sec, arg, body_parts)
+    }
+
+    pub fn update(&mut self, handle: i32, speed: i32) -> bool {
+        self.api.tk_update(handle, speed)
+    }
+
+    pub fn stop(&mut self, handle: i32) -> bool {
+        self.api.tk_stop(handle)
+    }
+
+    /// Blocks until the next event, empty if not connected
+    pub fn next_events(&mut self) -> Vec<SKSEModEvent> {
+        self.api.tk_qry_nxt_evt()
+    }
+
+    pub fn disconnect(&mut self) -> bool {
+        self.api.tk_cmd("disconnect")
+    }
+
+    /// Executes a recording with its original timing on the fake devices and returns
+    /// the actuators that received different values than recorded
+    pub fn replay<F>(
+        &mut self,
+        calls: &[TkRecordedCall],
+        speedup: f64,
+        mut on_call: F,
+    ) -> Vec<TkReplayMismatch>
+    where
+        F: FnMut(&TkRecordedCall),
+    {
+        let mut outputs = None;
+        replay(calls, speedup, |call| {
+            on_call(call);
+            match call.args.first() {
+                Some(cmd) if call.call == "tk_cmd" && cmd.eq_ignore_ascii_case("connect") => {
+                    let settings = self.replay_settings();
+                    if self.api.tk_connect_with(settings) {
+                        outputs = Some(self.api.collect_outputs());
+                    }
+                }
+                _ => {
+                    self.api.replay_call(call);
+                }
+            }
+        });
+        thread::sleep(Duration::from_millis(REPLAY_SETTLE_MS));
+        let replayed = outputs
+            .and_then(|x| x.lock().ok().map(|x| x.clone()))
+            .unwrap_or_default();
+        self.disconnect();
+        compare_outputs(calls, &replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_never_connects_to_real_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        for connection in [
+            None,
+            Some(TkConnectionType::InProcess),
+            Some(TkConnectionType::WebSocket(String::from("127.0.0.1:12345"))),
+        ] {
+            let cli = TkCli::new(path, connection.clone());
+            assert_eq!(cli.replay_settings().connection, TkConnectionType::Test);
+            if let Some(connection) = connection {
+                assert_eq!(cli.settings().connection, connection);
+            }
+        }
+    }
+}
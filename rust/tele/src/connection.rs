@@ -1,7 +1,8 @@
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{self, Display},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use bp_scheduler::{
@@ -9,12 +10,12 @@ use bp_scheduler::{
     speed::Speed,
 };
 use buttplug::{
-    client::{ButtplugClient, ButtplugClientDevice, ButtplugClientEvent},
+    client::{ButtplugClient, ButtplugClientDevice, ButtplugClientEvent, ScalarValueCommand},
     core::message::ActuatorType,
 };
 use crossbeam_channel::Sender;
 use futures::StreamExt;
-use tokio::runtime::Handle;
+use tokio::{runtime::Handle, sync::broadcast};
 use tracing::{debug, error, info};
 
 use crate::*;
@@ -25,9 +26,15 @@ use crate::*;
 pub enum ConnectionCommand {
     Scan,
     StopScan,
-    StopAll,
+    ScanTimeout(u32),
+    ScanningFinished,
+    RescanMissing,
+    /// Stops a rescan once all enabled devices are connected
+    CheckMissing,
+    StopAll(Vec<Arc<Actuator>>),
     Disconect,
-    GetBattery
+    GetBattery,
+    GetRssi,
 }
 
 #[derive(Clone, Debug)]
@@ -42,31 +49,96 @@ pub enum Task {
 pub enum TkConnectionEvent {
     Connected(String),
     ConnectionFailure(String),
+    ScanStarted,
+    ScanFinished,
     DeviceAdded(Arc<ButtplugClientDevice>, Option<f64>),
     DeviceRemoved(Arc<ButtplugClientDevice>),
     BatteryLevel(Arc<ButtplugClientDevice>, Option<f64>),
+    BatteryLow(Arc<ButtplugClientDevice>, f64),
+    /// Device, sensor type, sensor index and value
+    SensorReading(Arc<ButtplugClientDevice>, TkSensorType, u32, i32),
+    SensorThreshold(Arc<ButtplugClientDevice>, TkSensorType, i32, bool),
+    ButtonPressed(Arc<ButtplugClientDevice>, bool),
     ActionStarted(Task, Vec<Arc<Actuator>>, Vec<String>, i32),
     ActionDone(Task, Duration, i32),
     ActionError(Arc<Actuator>, String),
 }
 
+/// Remembers when devices were polled and which ones are below
+/// their low battery level, to warn only once per crossing
+#[derive(Debug, Default)]
+struct BatteryMonitor {
+    last_poll: HashMap<u32, Instant>,
+    low: HashSet<u32>,
+}
+
+impl BatteryMonitor {
+    fn is_due(&self, device: &ButtplugClientDevice, interval: Duration) -> bool {
+        self.last_poll
+            .get(&device.index())
+            .map(|x| x.elapsed() >= interval)
+            .unwrap_or(true)
+    }
+
+    /// Returns true if the level just crossed below `low_level`
+    fn update(&mut self, device: &ButtplugClientDevice, level: f64, low_level: f64) -> bool {
+        self.last_poll.insert(device.index(), Instant::now());
+        if level >= low_level {
+            self.low.remove(&device.index());
+            return false;
+        }
+        self.low.insert(device.index())
+    }
+}
+
+/// Rescans stop after this time if the scan settings don't limit the scan duration
+static RESCAN_DURATION_SECS: u64 = 30;
+
 pub async fn handle_connection(
     event_sender: crossbeam_channel::Sender<TkConnectionEvent>,
     event_sender_internal: crossbeam_channel::Sender<TkConnectionEvent>,
+    event_broadcast: broadcast::Sender<TkConnectionEvent>,
     command_sender: tokio::sync::mpsc::Sender<ConnectionCommand>, // TODO: just use crossbeam?
     mut command_receiver: tokio::sync::mpsc::Receiver<ConnectionCommand>,
-    client: ButtplugClient,
+    client: Arc<ButtplugClient>,
     connection_type: TkConnectionType,
+    settings: TkSettings,
+    known_actuators: Arc<Mutex<Vec<String>>>,
 ) {
+    let scan_settings = settings.scan.clone();
+    let battery_settings = settings.battery.clone();
+    let battery_tick = battery_settings.min_poll_interval();
+    let battery_monitor = Arc::new(Mutex::new(BatteryMonitor::default()));
+    let added_battery_monitor = battery_monitor.clone();
+    let added_battery_settings = battery_settings.clone();
+    let sensor_settings = settings.sensors.clone();
+    let added_sensor_settings = sensor_settings.clone();
+    let sensor_monitor = Arc::new(Mutex::new(SensorMonitor::default()));
+    let added_sensor_monitor = sensor_monitor.clone();
+    let rssi_command_sender = command_sender.clone();
+    let scan_command_sender = command_sender.clone();
+    let timeout_command_sender = command_sender.clone();
+    let rescan_command_sender = command_sender.clone();
+    let finished_command_sender = command_sender.clone();
+    let added_command_sender = command_sender.clone();
     let sender_interla_clone = event_sender_internal.clone();
+    let (rssi_client_sender, rssi_internal_sender) = (event_sender.clone(), event_sender_internal.clone());
     let mut buttplug_events = client.event_stream();
     let sender_clone = event_sender.clone();
+    let broadcast_clone = event_broadcast.clone();
     let try_send_events = move |event: TkConnectionEvent| {
+        let _ = broadcast_clone.send(event.clone());
         try_send_event(&sender_clone, event.clone());
         try_send_event(&event_sender_internal, event);
     };
+    if scan_settings.on_connect {
+        let _ = command_sender.send(ConnectionCommand::Scan).await;
+    }
     Handle::current().spawn(async move {
         debug!("starting connection thread...");
+        let mut scanning = false;
+        let mut rescanning = false;
+        let mut scan_id: u32 = 0;
         loop {
             let next_cmd = command_receiver.recv().await;
             if let Some(cmd) = next_cmd {
@@ -81,6 +153,22 @@ pub async fn handle_connection(
                             let settings = connection_type.to_string();
                             info!(settings, "connection success");
                             try_send_events(TkConnectionEvent::Connected(settings.clone()));
+                            scan_id += 1;
+                            if !scanning {
+                                scanning = true;
+                                try_send_events(TkConnectionEvent::ScanStarted);
+                            }
+                            let duration_secs = scan_settings
+                                .duration_secs
+                                .or(rescanning.then_some(RESCAN_DURATION_SECS));
+                            if let Some(secs) = duration_secs {
+                                let sender = timeout_command_sender.clone();
+                                let id = scan_id;
+                                Handle::current().spawn(async move {
+                                    tokio::time::sleep(Duration::from_secs(secs)).await;
+                                    let _ = sender.send(ConnectionCommand::ScanTimeout(id)).await;
+                                });
+                            }
                         }
                     }
                     ConnectionCommand::StopScan => {
@@ -89,6 +177,40 @@ pub async fn handle_connection(
                             error!(error, "failed stop scan");
                             let err = TkConnectionEvent::ConnectionFailure(error);
                             try_send_events(err);
+                        } else if scanning {
+                            scanning = false;
+                            rescanning = false;
+                            try_send_events(TkConnectionEvent::ScanFinished);
+                        }
+                    }
+                    ConnectionCommand::ScanTimeout(id) => {
+                        if scanning && id == scan_id {
+                            info!("scan timed out");
+                            let _ = scan_command_sender.try_send(ConnectionCommand::StopScan);
+                        }
+                    }
+                    ConnectionCommand::ScanningFinished => {
+                        if scanning {
+                            scanning = false;
+                            rescanning = false;
+                            try_send_events(TkConnectionEvent::ScanFinished);
+                        }
+                    }
+                    ConnectionCommand::RescanMissing => {
+                        let missing = get_missing_actuators(&client, &known_actuators);
+                        if rescanning && missing.is_empty() {
+                            info!("all devices found, stopping rescan");
+                            let _ = scan_command_sender.try_send(ConnectionCommand::StopScan);
+                        } else if !scanning && !missing.is_empty() {
+                            info!(?missing, "rescanning for missing devices");
+                            rescanning = true;
+                            let _ = scan_command_sender.try_send(ConnectionCommand::Scan);
+                        }
+                    }
+                    ConnectionCommand::CheckMissing => {
+                        if rescanning && get_missing_actuators(&client, &known_actuators).is_empty() {
+                            info!("all devices found, stopping rescan");
+                            let _ = scan_command_sender.try_send(ConnectionCommand::StopScan);
                         }
                     }
                     ConnectionCommand::Disconect => {
@@ -98,19 +220,51 @@ pub async fn handle_connection(
                             .unwrap_or_else(|_| error!("failed to disconnect"));
                         break;
                     }
-                    ConnectionCommand::StopAll => {
-                        client
-                            .stop_all_devices()
-                            .await
-                            .unwrap_or_else(|_| error!("failed to stop all devices"));
+                    ConnectionCommand::StopAll(ignored) => {
+                        if ignored.is_empty() {
+                            client
+                                .stop_all_devices()
+                                .await
+                                .unwrap_or_else(|_| error!("failed to stop all devices"));
+                        } else {
+                            stop_all_except(&client, &ignored).await;
+                        }
                     }
                     ConnectionCommand::GetBattery => {
                         for device in client.devices() {
-                            if device.connected() && device.has_battery_level() {
-                                try_send_events(TkConnectionEvent::BatteryLevel(device.clone(),device.battery_level().await.ok()));
+                            let interval = battery_settings.poll_interval(device.name());
+                            let due = battery_monitor.lock().map(|x| x.is_due(&device, interval)).unwrap_or(true);
+                            if device.connected() && device.has_battery_level() && due {
+                                let level = device.battery_level().await.ok();
+                                try_send_events(TkConnectionEvent::BatteryLevel(device.clone(), level));
+                                if let Some(low) = check_battery(&battery_monitor, &battery_settings, &device, level) {
+                                    try_send_events(low);
+                                }
                             }
                         }
                     },
+                    ConnectionCommand::GetRssi => {
+                        for device in client.devices() {
+                            if !device.connected() || !device.has_rssi_level() {
+                                continue;
+                            }
+                            if let Ok(rssi) = device.rssi_level().await {
+                                let events = match sensor_monitor.lock() {
+                                    Ok(mut monitor) => monitor.update(
+                                        &device,
+                                        TkSensorType::Rssi,
+                                        rssi_sensor_index(&device),
+                                        rssi,
+                                        &sensor_settings,
+                                    ),
+                                    Err(_) => vec![],
+                                };
+                                for event in events {
+                                    send_sensor_event(&rssi_client_sender, &rssi_internal_sender, event);
+                                }
+                            }
+                        }
+                    }
                 }
             } else {
                 break;
@@ -119,10 +273,35 @@ pub async fn handle_connection(
         info!("stream closed");
     });
 
+    if let Some(secs) = scan_settings.rescan_interval_secs {
+        Handle::current().spawn(async move {
+            debug!("starting rescan thread");
+            loop {
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                if rescan_command_sender.send(ConnectionCommand::RescanMissing).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if added_sensor_settings.rssi_poll_interval_secs > 0 {
+        let interval = Duration::from_secs(added_sensor_settings.rssi_poll_interval_secs);
+        Handle::current().spawn(async move {
+            debug!("starting rssi thread");
+            loop {
+                tokio::time::sleep(interval).await;
+                if rssi_command_sender.send(ConnectionCommand::GetRssi).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     Handle::current().spawn(async move {
         debug!("starting battery thread");
         loop {
-            tokio::time::sleep(Duration::from_secs(300)).await;
+            tokio::time::sleep(battery_tick).await;
             let _ = command_sender.send(ConnectionCommand::GetBattery).await;
         }
     });
@@ -139,19 +318,42 @@ pub async fn handle_connection(
                 } else {
                     None
                 };
-                let added = TkConnectionEvent::DeviceAdded(device, battery);
+                let low = check_battery(&added_battery_monitor, &added_battery_settings, &device, battery);
+                inc_counter(DEVICE_CONNECTS, &[]);
+                let added = TkConnectionEvent::DeviceAdded(device.clone(), battery);
+                let _ = event_broadcast.send(added.clone());
                 try_send_event(&sender_interla_clone, added.clone());
                 try_send_event(&event_sender, added);
+                if let Some(low) = low {
+                    let _ = event_broadcast.send(low.clone());
+                    try_send_event(&sender_interla_clone, low.clone());
+                    try_send_event(&event_sender, low);
+                }
+                let _ = added_command_sender.try_send(ConnectionCommand::CheckMissing);
+                if added_sensor_settings.subscribe {
+                    let (client_sender, internal_sender) = (event_sender.clone(), sender_interla_clone.clone());
+                    Handle::current().spawn(subscribe_sensors(
+                        device.clone(),
+                        added_sensor_settings.clone(),
+                        added_sensor_monitor.clone(),
+                        move |event| send_sensor_event(&client_sender, &internal_sender, event),
+                    ));
+                }
             }
             ButtplugClientEvent::DeviceRemoved(device) => {
                 let name = device.name();
                 let index = device.index();
                 info!(name, index, "device disconnected");
 
+                inc_counter(DEVICE_DISCONNECTS, &[]);
                 let removed = TkConnectionEvent::DeviceRemoved(device);
+                let _ = event_broadcast.send(removed.clone());
                 try_send_event(&sender_interla_clone, removed.clone());
                 try_send_event(&event_sender, removed);
             }
+            ButtplugClientEvent::ScanningFinished => {
+                let _ = finished_command_sender.try_send(ConnectionCommand::ScanningFinished);
+            }
             ButtplugClientEvent::Error(err) => {
                 error!(?err, "client error event");
             }
@@ -160,6 +362,74 @@ pub async fn handle_connection(
     }
 }
 
+/// Enabled actuators (from the current settings) that are not connected
+fn get_missing_actuators(client: &ButtplugClient, known_actuators: &Mutex<Vec<String>>) -> Vec<String> {
+    let connected = get_actuators(client.devices());
+    match known_actuators.lock() {
+        Ok(known) => known
+            .iter()
+            .filter(|x| !connected.iter().any(|y| y.identifier() == x.as_str()))
+            .cloned()
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Stops every device except the ignored actuators, devices with ignored
+/// actuators only get their remaining scalar actuators set to 0
+async fn stop_all_except(client: &ButtplugClient, ignored: &[Arc<Actuator>]) {
+    for device in client.devices() {
+        if !ignored.iter().any(|x| x.device.index() == device.index()) {
+            device
+                .stop()
+                .await
+                .unwrap_or_else(|_| error!("failed to stop device"));
+            continue;
+        }
+        let scalars = get_actuators(vec![device.clone()])
+            .iter()
+            .filter(|x| x.actuator != ActuatorType::Position)
+            .filter(|x| !ignored.iter().any(|y| y.identifier() == x.identifier()))
+            .map(|x| (x.index_in_device, (0.0, x.actuator)))
+            .collect::<HashMap<u32, (f64, ActuatorType)>>();
+        if !scalars.is_empty() {
+            device
+                .scalar(&ScalarValueCommand::ScalarValueMap(scalars))
+                .await
+                .unwrap_or_else(|_| error!("failed to stop device"));
+        }
+    }
+}
+
+/// Readings are only relevant for the status (they arrive too often for papyrus events),
+/// all other sensor events go to both
+fn send_sensor_event(
+    client_sender: &Sender<TkConnectionEvent>,
+    internal_sender: &Sender<TkConnectionEvent>,
+    event: TkConnectionEvent,
+) {
+    if !matches!(event, TkConnectionEvent::SensorReading(_, _, _, _)) {
+        try_send_event(client_sender, event.clone());
+    }
+    try_send_event(internal_sender, event);
+}
+
+fn check_battery(
+    monitor: &Mutex<BatteryMonitor>,
+    settings: &TkBatterySettings,
+    device: &Arc<ButtplugClientDevice>,
+    level: Option<f64>,
+) -> Option<TkConnectionEvent> {
+    let level = level?;
+    let low_level = settings.low_level(device.name());
+    let crossed = monitor.lock().ok()?.update(device, level, low_level);
+    if crossed {
+        info!(name = device.name(), level, "battery low");
+        return Some(TkConnectionEvent::BatteryLow(device.clone(), level));
+    }
+    None
+}
+
 fn try_send_event(sender: &Sender<TkConnectionEvent>, evt: TkConnectionEvent) {
     sender
         .try_send(evt)
@@ -0,0 +1,113 @@
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use tracing::{info, Level};
+
+use crate::{
+    logging::{get_log_filter, get_log_tail},
+    pattern::get_pattern_names,
+    settings::SETTINGS_FILE,
+    telekinesis::Telekinesis,
+};
+
+/// Folder that `diagnostics.export` writes into
+pub static DIAGNOSTICS_FOLDER: &str = "Data\\SKSE\\Plugins\\Telekinesis.diagnostics";
+
+/// Writes settings (without credentials), status, recent logs, patterns and version
+/// into the folder `path` so that a bug report only needs a single attachment.
+/// Only the folder itself is created, its parent has to exist
+pub fn export_diagnostics(tk: &mut Telekinesis, path: &str) -> Result<(), String> {
+    if let Err(err) = fs::create_dir(path) {
+        if err.kind() != ErrorKind::AlreadyExists {
+            return Err(format!("Could not create '{}': {}", path, err));
+        }
+    }
+    if !tk.settings.redacted().try_write(path, SETTINGS_FILE) {
+        return Err(format!("Could not write settings to '{}'", path));
+    }
+    let files = [
+        ("version.txt", version_info(tk)),
+        ("status.txt", status_snapshot(tk)),
+        ("patterns.txt", pattern_list(tk)),
+        ("log.txt", get_log_tail(usize::MAX, Level::TRACE)),
+    ];
+    for (name, lines) in files {
+        let file = [path, name].iter().collect::<PathBuf>();
+        fs::write(&file, lines.join("\n"))
+            .map_err(|err| format!("Could not write '{}': {}", file.display(), err))?;
+    }
+    info!(path, "exported diagnostics");
+    Ok(())
+}
+
+fn version_info(tk: &Telekinesis) -> Vec<String> {
+    vec![
+        format!("library: {}", env!("CARGO_PKG_VERSION")),
+        format!("settings: {}", tk.settings.version),
+        format!("log filter: {}", get_log_filter()),
+    ]
+}
+
+fn status_snapshot(tk: &mut Telekinesis) -> Vec<String> {
+    tk.process_status();
+    let mut lines = vec![
+        format!("connection: {}", tk.status.connection_status()),
+        format!("scanning: {}", tk.status.is_scanning()),
+    ];
+    for status in tk.status.actuator_status() {
+        lines.push(format!(
+            "actuator: {} ({}), battery: {}",
+            status.actuator.identifier(),
+            status.connection_status,
+            status
+                .battery_level
+                .map(|x| format!("{}%", (x * 100.0) as i32))
+                .unwrap_or_else(|| String::from("-"))
+        ));
+    }
+    for actuator in tk.status.ignored_actuators() {
+        lines.push(format!("ignored: {}", actuator.identifier()));
+    }
+    for actuator_id in tk.status.get_known_actuator_ids() {
+        lines.push(format!("known: {}", actuator_id));
+    }
+    lines
+}
+
+fn pattern_list(tk: &Telekinesis) -> Vec<String> {
+    let vibrator = get_pattern_names(&tk.settings.pattern_path, true)
+        .into_iter()
+        .map(|x| format!("vibrator: {}", x));
+    let stroker = get_pattern_names(&tk.settings.pattern_path, false)
+        .into_iter()
+        .map(|x| format!("stroker: {}", x));
+    vibrator.chain(stroker).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_connection::connected_fakes, settings::TkSettings};
+    use bp_fakes::*;
+    use buttplug::core::message::ActuatorType;
+    use tempfile::tempdir;
+
+    #[test]
+    fn export_writes_all_files() {
+        let mut settings = TkSettings::default();
+        settings.http.token = String::from("http-secret");
+        let (mut tk, _) = connected_fakes(vec![scalar(1, "vib1", ActuatorType::Vibrate)], settings);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("diagnostics");
+        export_diagnostics(&mut tk, path.to_str().unwrap()).unwrap();
+
+        let status = fs::read_to_string(path.join("status.txt")).unwrap();
+        assert!(status.contains("actuator: vib1 (Vibrate)"));
+        assert!(path.join("patterns.txt").exists());
+        let settings = fs::read_to_string(path.join(SETTINGS_FILE)).unwrap();
+        assert!(!settings.contains("http-secret"));
+        assert!(path.join("version.txt").exists());
+        assert!(path.join("log.txt").exists());
+        assert!(export_diagnostics(&mut tk, path.join("a/b").to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use funscript::{FSPoint, FScript};
+
+use crate::{
+    connection::Task,
+    input::DeviceCommand,
+    pattern::{repeat_script, write_pattern},
+    trace::{trace_to_fscript, TkTraceEntry},
+};
+
+/// Number of tasks that can still be exported after they were started
+static TASK_HISTORY_SIZE: usize = 32;
+
+/// Length of exported tasks that run until they are stopped and don't have a pattern
+static UNLIMITED_SCALAR_MS: u64 = 1000;
+
+/// Commands of the most recently started tasks by handle
+#[derive(Debug, Default)]
+pub struct TkTaskHistory {
+    tasks: VecDeque<(i32, DeviceCommand)>,
+}
+
+impl TkTaskHistory {
+    pub fn push(&mut self, handle: i32, cmd: &DeviceCommand) {
+        while self.tasks.len() >= TASK_HISTORY_SIZE {
+            self.tasks.pop_front();
+        }
+        self.tasks.push_back((handle, cmd.clone()));
+    }
+
+    pub fn get(&self, handle: i32) -> Option<&DeviceCommand> {
+        self.tasks
+            .iter()
+            .find(|(x, _)| *x == handle)
+            .map(|(_, cmd)| cmd)
+    }
+}
+
+/// The output of the task as a script and whether it is a vibration pattern.
+/// Tasks that run until they are stopped are exported with a single repetition
+pub fn task_script(cmd: &DeviceCommand) -> Result<(FScript, bool), String> {
+    match (&cmd.task, &cmd.fscript) {
+        (Task::Scalar(speed), _) => {
+            let length = if cmd.duration == Duration::MAX {
+                UNLIMITED_SCALAR_MS
+            } else {
+                cmd.duration.as_millis() as u64
+            };
+            let pos = (speed.as_float() * 100.0).round() as i32;
+            let actions = vec![
+                FSPoint { pos, at: 0 },
+                FSPoint {
+                    pos,
+                    at: length.min(i32::MAX as u64) as i32,
+                },
+            ];
+            Ok((
+                FScript {
+                    actions,
+                    ..Default::default()
+                },
+                true,
+            ))
+        }
+        (Task::Pattern(speed, _, _), Some(fscript)) => {
+            let mut script = repeat_script(fscript, cmd.duration);
+            for point in script.actions.iter_mut() {
+                point.pos = (point.pos as f64 * speed.as_float()).round() as i32;
+            }
+            Ok((script, true))
+        }
+        (Task::Linear(_, _), Some(fscript)) => Ok((repeat_script(fscript, cmd.duration), false)),
+        (Task::LinearStroke(_, _), _) => Err(String::from(
+            "Strokes depend on the device settings, export the device output instead",
+        )),
+        (task, None) => Err(format!("Task {:?} has no pattern", task)),
+    }
+}
+
+/// Writes the output of a task into the pattern folder
+pub fn export_task(
+    pattern_path: &str,
+    pattern_name: &str,
+    cmd: &DeviceCommand,
+) -> Result<PathBuf, String> {
+    let (fscript, vibration_pattern) = task_script(cmd)?;
+    export_pattern(pattern_path, pattern_name, vibration_pattern, &fscript)
+}
+
+/// Writes the traced output of an actuator into the pattern folder
+pub fn export_output(
+    pattern_path: &str,
+    pattern_name: &str,
+    entries: &[TkTraceEntry],
+    linear: bool,
+) -> Result<PathBuf, String> {
+    if entries.is_empty() {
+        return Err(String::from(
+            "No output was traced, make sure that the trace is enabled",
+        ));
+    }
+    export_pattern(
+        pattern_path,
+        pattern_name,
+        !linear,
+        &trace_to_fscript(entries),
+    )
+}
+
+fn export_pattern(
+    pattern_path: &str,
+    pattern_name: &str,
+    vibration_pattern: bool,
+    fscript: &FScript,
+) -> Result<PathBuf, String> {
+    let name = pattern_name.trim();
+    if name.is_empty() || name.contains(['/', '\\', '?']) || name.contains("..") {
+        return Err(format!("Invalid pattern name '{}'", pattern_name));
+    }
+    write_pattern(pattern_path, name, vibration_pattern, fscript)
+        .map_err(|err| format!("Could not export pattern '{}': {}", name, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::{get_pattern_names, read_pattern_name};
+    use bp_scheduler::speed::Speed;
+    use buttplug::core::message::ActuatorType;
+    use tempfile::tempdir;
+
+    fn points(script: &FScript) -> Vec<(i32, i32)> {
+        script.actions.iter().map(|x| (x.at, x.pos)).collect()
+    }
+
+    fn command(task: Task, duration: Duration, fscript: Option<FScript>) -> DeviceCommand {
+        DeviceCommand {
+            task,
+            duration,
+            fscript,
+            body_parts: vec![],
+            actuator_types: vec![ActuatorType::Vibrate],
+        }
+    }
+
+    #[test]
+    fn pattern_tasks_are_scaled_and_repeated() {
+        let fscript = FScript {
+            actions: vec![FSPoint { pos: 0, at: 0 }, FSPoint { pos: 100, at: 1000 }],
+            ..Default::default()
+        };
+        let cmd = command(
+            Task::Pattern(Speed::new(50), ActuatorType::Vibrate, String::from("ramp")),
+            Duration::from_millis(1500),
+            Some(fscript),
+        );
+        let (script, vibration) = task_script(&cmd).unwrap();
+        assert!(vibration);
+        assert_eq!(
+            points(&script),
+            vec![(0, 0), (1000, 50), (1001, 0), (1500, 25)]
+        );
+    }
+
+    #[test]
+    fn scalar_tasks_are_constant() {
+        let cmd = command(Task::Scalar(Speed::new(40)), Duration::MAX, None);
+        let (script, _) = task_script(&cmd).unwrap();
+        assert_eq!(points(&script), vec![(0, 40), (1000, 40)]);
+        let stroke = command(
+            Task::LinearStroke(Speed::new(40), String::new()),
+            Duration::MAX,
+            None,
+        );
+        assert!(task_script(&stroke).is_err());
+    }
+
+    #[test]
+    fn exported_output_can_be_replayed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let entry = |at_ms: u64, value: f64| TkTraceEntry {
+            at_ms,
+            device_index: Some(1),
+            index: Some(0),
+            linear: false,
+            value,
+            duration_ms: None,
+        };
+        let entries = vec![entry(500, 0.2), entry(800, 1.0), entry(1500, 0.0)];
+        export_output(path, "scene", &entries, false).unwrap();
+        assert_eq!(get_pattern_names(path, true), vec!["scene"]);
+        assert_eq!(
+            points(&read_pattern_name(path, "scene", true).unwrap()),
+            vec![(0, 20), (300, 100), (1000, 0)]
+        );
+        assert!(export_output(path, "empty", &[], false).is_err());
+        assert!(export_output(path, "../scene", &entries, false).is_err());
+    }
+}
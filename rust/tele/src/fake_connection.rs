@@ -0,0 +1,53 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use bp_fakes::*;
+
+use crate::{
+    settings::{TkConnectionType, TkSettings},
+    telekinesis::Telekinesis,
+};
+
+/// Time the fake devices get to connect before a test fails
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to fake devices, they are still connecting when this returns
+pub fn connect_fakes(
+    devices: Vec<DeviceAdded>,
+    settings: TkSettings,
+) -> (Telekinesis, FakeConnectorCallRegistry) {
+    let (connector, call_registry) = FakeDeviceConnector::new(devices);
+    let tk = Telekinesis::connect_with(
+        || async move { connector },
+        Some(settings),
+        TkConnectionType::Test,
+    )
+    .unwrap();
+    (tk, call_registry)
+}
+
+/// Blocks until the connection events of `count` actuators were processed
+pub fn await_actuators(tk: &mut Telekinesis, count: usize) {
+    let started = Instant::now();
+    while tk.status.actuators().len() < count {
+        assert!(
+            started.elapsed() < CONNECT_TIMEOUT,
+            "{} actuators did not connect",
+            count
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Connects to fake devices with one actuator each and waits until all of them are connected
+pub fn connected_fakes(
+    devices: Vec<DeviceAdded>,
+    settings: TkSettings,
+) -> (Telekinesis, FakeConnectorCallRegistry) {
+    let count = devices.len();
+    let (mut tk, call_registry) = connect_fakes(devices, settings);
+    await_actuators(&mut tk, count);
+    (tk, call_registry)
+}
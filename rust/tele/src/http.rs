@@ -0,0 +1,672 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, error, info};
+
+use crate::{api::Api, input::parse_csv, metrics::render_metrics, telekinesis::Telekinesis, TkApi};
+
+static MAX_BODY_SIZE: usize = 64 * 1024;
+static MAX_LINE_LENGTH: u64 = 8 * 1024;
+static MAX_HEADERS: usize = 64;
+/// Time a client has to send the complete request
+static REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands that can be called over http, everything that takes a path, writes
+/// files or changes the connection is only available in game
+pub(crate) static HTTP_COMMANDS: [&str; 51] = [
+    "connection.status",
+    "connection.scanning",
+    "connection.inprocess.manager.enabled",
+    "start_scan",
+    "stop_scan",
+    "server.enabled",
+    "recorder.recording",
+    "log.level",
+    "log.tail",
+    "vibrate",
+    "scalar",
+    "vibrate.pattern",
+    "linear.pattern",
+    "linear.stroke",
+    "vibrate.direction",
+    "vibrate.sweep",
+    "event.fire",
+    "stop_all",
+    "sync.start",
+    "sync.stop",
+    "sync.status",
+    "devices",
+    "device.has_battery_level",
+    "device.get_battery_level",
+    "device.get_battery_trend",
+    "device.get_battery_remaining",
+    "device.sensor.rssi",
+    "device.sensor.pressure",
+    "device.sensor.button",
+    "device.actuator",
+    "device.actuator_type",
+    "device.actuator.index",
+    "device.settings.enable",
+    "device.settings.disable",
+    "device.settings.enabled",
+    "device.settings.events",
+    "device.settings.position",
+    "device.scalar.min_speed",
+    "device.scalar.max_speed",
+    "device.scalar.factor",
+    "device.linear.min_ms",
+    "device.linear.max_ms",
+    "device.linear.min_pos",
+    "device.linear.max_pos",
+    "device.linear.invert",
+    "device.linear.invert.enable",
+    "device.linear.invert.disable",
+    "device.connection.status",
+    "device.trace",
+    "patterns.vibrator",
+    "patterns.stroker",
+];
+
+/// Local http api for companion apps, overlays and browser based configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkHttpSettings {
+    pub enabled: bool,
+    pub bind_address: String,
+    pub port: u16,
+    /// Clients send it as `Authorization: Bearer <token>`, the api does not start without one
+    pub token: String,
+    /// Web pages that may call the api from a browser, i.e. `http://localhost:8080`
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for TkHttpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: String::from("127.0.0.1"),
+            port: 12346,
+            token: String::new(),
+            allowed_origins: vec![],
+        }
+    }
+}
+
+pub async fn run_http_server(settings: TkHttpSettings, api: TkApi) {
+    let address = format!("{}:{}", settings.bind_address, settings.port);
+    if settings.token.trim().is_empty() {
+        error!(
+            "Http api on {} is not started, it requires a token.",
+            address
+        );
+        return;
+    }
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not start http api on {}. Error: {}.", address, err);
+            return;
+        }
+    };
+    info!(address, "http api listening");
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let api = api.integration();
+                let settings = settings.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, api, settings).await {
+                        debug!(?peer, "http request failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => error!("Could not accept http connection. Error: {}.", err),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TkHttpRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    origin: Option<String>,
+    body: String,
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    mut api: TkApi,
+    settings: TkHttpSettings,
+) -> Result<(), String> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .unwrap_or_else(|_| Err(String::from("Request timed out")));
+    let mut headers = vec![];
+    let (status, content_type, body) = match request {
+        Ok(request) => {
+            if let Some(origin) = allowed_origin(&settings, &request) {
+                headers.push(format!("Access-Control-Allow-Origin: {}", origin));
+                headers.push(String::from("Vary: Origin"));
+            }
+            match check_request(&settings, &request) {
+                Err((status, err)) => (
+                    status,
+                    "application/json",
+                    json!({ "error": err }).to_string(),
+                ),
+                Ok(()) if request.method == "OPTIONS" => {
+                    headers.push(String::from("Access-Control-Allow-Methods: GET, POST"));
+                    headers.push(String::from(
+                        "Access-Control-Allow-Headers: Authorization, Content-Type",
+                    ));
+                    (204, "application/json", String::new())
+                }
+                Ok(()) if request.method == "GET" && route(&request.path) == "/metrics" => {
+                    let metrics = tokio::task::spawn_blocking(move || {
+                        api.try_exec(|tk| render_metrics(Some(tk)), render_metrics(None))
+                    })
+                    .await
+                    .map_err(|err| err.to_string())?;
+                    (200, "text/plain; version=0.0.4", metrics)
+                }
+                Ok(()) => {
+                    let (status, body) = tokio::task::spawn_blocking(move || {
+                        handle_request(&mut api, &request.method, &request.path, &request.body)
+                    })
+                    .await
+                    .map_err(|err| err.to_string())?;
+                    (status, "application/json", body.to_string())
+                }
+            }
+        }
+        Err(err) => (400, "application/json", json!({ "error": err }).to_string()),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+    );
+    for header in headers {
+        response.push_str(&header);
+        response.push_str("\r\n");
+    }
+    response.push_str("Connection: close\r\n\r\n");
+    response.push_str(&body);
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Browsers are only allowed to read responses for configured origins,
+/// requests from other web pages are rejected
+fn allowed_origin<'a>(settings: &TkHttpSettings, request: &'a TkHttpRequest) -> Option<&'a str> {
+    let origin = request.origin.as_deref()?;
+    settings
+        .allowed_origins
+        .iter()
+        .any(|x| x.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+/// Origin, token and content type, preflight requests (`OPTIONS`) carry no token
+fn check_request(settings: &TkHttpSettings, request: &TkHttpRequest) -> Result<(), (u16, String)> {
+    if request.origin.is_some() && allowed_origin(settings, request).is_none() {
+        return Err((403, String::from("Origin is not allowed")));
+    }
+    if request.method == "OPTIONS" {
+        return Ok(());
+    }
+    let token = request
+        .authorization
+        .as_deref()
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(|x| x.trim());
+    match token {
+        Some(token) if !settings.token.is_empty() && equals(token, settings.token.trim()) => {}
+        _ => return Err((401, String::from("Missing or invalid token"))),
+    }
+    let is_json = request
+        .content_type
+        .as_deref()
+        .and_then(|x| x.split(';').next())
+        .map(|x| x.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false);
+    if request.method == "POST" && !is_json {
+        return Err((415, String::from("Expected Content-Type: application/json")));
+    }
+    Ok(())
+}
+
+/// Compares without returning early, so the token can't be guessed from response times
+fn equals(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Result<TkHttpRequest, String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line).await?;
+    let mut request_line = line.split_whitespace();
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err(format!("Invalid request line '{}'", line.trim()));
+    };
+    let mut request = TkHttpRequest {
+        method: method.to_uppercase(),
+        path: path.to_owned(),
+        ..Default::default()
+    };
+
+    let mut content_length = 0;
+    for i in 0.. {
+        read_line(&mut reader, &mut line).await?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if i >= MAX_HEADERS {
+            return Err(format!("More than {} headers", MAX_HEADERS));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim().to_owned();
+            match name.trim().to_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "authorization" => request.authorization = Some(value),
+                "content-type" => request.content_type = Some(value),
+                "origin" => request.origin = Some(value),
+                _ => {}
+            }
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(format!("Body exceeds {} bytes", MAX_BODY_SIZE));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|err| err.to_string())?;
+    request.body = String::from_utf8_lossy(&body).into_owned();
+    Ok(request)
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> Result<(), String> {
+    line.clear();
+    let read = reader
+        .take(MAX_LINE_LENGTH)
+        .read_line(line)
+        .await
+        .map_err(|err| err.to_string())?;
+    if read == 0 {
+        return Err(String::from("Connection closed"));
+    }
+    if read as u64 >= MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(format!("Line exceeds {} bytes", MAX_LINE_LENGTH));
+    }
+    Ok(())
+}
+
+/// Path without query and trailing slash
+fn route(path: &str) -> &str {
+    path.split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('/')
+}
+
+/// - `GET /status`, `GET /settings`, `GET /tasks` (`GET /metrics` is served as text)
+/// - `POST /api/<function>` with the arguments as json string array, where
+///   function is one of the papyrus natives, i.e. `qry_lst_1` or `control`
+fn handle_request(api: &mut TkApi, method: &str, path: &str, body: &str) -> (u16, Value) {
+    let path = route(path);
+    let result = match (method, path) {
+        ("GET", "/status") => api.try_exec(|tk| Ok(status_json(tk)), Err(not_connected())),
+        ("GET", "/settings") => api.try_exec(
+            |tk| Ok(serde_json::to_value(tk.settings.redacted()).unwrap_or_default()),
+            Err(not_connected()),
+        ),
+        ("GET", "/tasks") => api.try_exec(|tk| Ok(tasks_json(tk)), Err(not_connected())),
+        ("POST", path) if path.starts_with("/api/") => {
+            match serde_json::from_str::<Vec<String>>(body) {
+                Ok(args) => call_api(api, &path["/api/".len()..], &args, &HTTP_COMMANDS)
+                    .map_err(|err| (400, err)),
+                Err(err) => Err((400, format!("Expected json string array: {}", err))),
+            }
+        }
+        _ => Err((404, format!("{} {} not found", method, path))),
+    };
+    match result {
+        Ok(value) => (200, value),
+        Err((status, err)) => (status, json!({ "error": err })),
+    }
+}
+
+/// Calls a papyrus native, commands that are not in `allowed` are rejected
+pub(crate) fn call_api(
+    api: &mut TkApi,
+    function: &str,
+    args: &[String],
+    allowed: &[&str],
+) -> Result<Value, String> {
+    let arg = |i: usize| {
+        args.get(i)
+            .map(|x| x.as_str())
+            .ok_or_else(|| format!("{} expects {} argument(s)", function, i + 1))
+    };
+    let number = |i: usize| -> Result<f32, String> {
+        arg(i)?
+            .parse::<f32>()
+            .map_err(|_| format!("'{}' is not a number", args[i]))
+    };
+    let function = function.to_lowercase();
+    if function != "update" && function != "stop" {
+        let name = arg(0)?;
+        if !allowed.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+            return Err(format!("'{}' is only available in game", name));
+        }
+    }
+    let name = args.first().map(|x| x.as_str()).unwrap_or_default();
+    Ok(match function.as_str() {
+        "cmd" => json!(api.exec_cmd_0(name)),
+        "cmd_1" => json!(api.exec_cmd_1(name, arg(1)?)),
+        "cmd_2" => json!(api.exec_cmd_2(name, arg(1)?, arg(2)?)),
+        "qry_str" => json!(api.exec_qry_str(name)),
+        "qry_str_1" => json!(api.exec_qry_str_1(name, arg(1)?)),
+        "qry_lst" => json!(api.exec_qry_lst(name)),
+        "qry_lst_1" => json!(api.exec_qry_lst_1(name, arg(1)?)),
+        "qry_bool" => json!(api.exec_qry_bool(name)),
+        "qry_bool_1" => json!(api.exec_qry_bool_1(name, arg(1)?)),
+        "control" => {
+            let body_parts = args.get(4).map(|x| parse_csv(x)).unwrap_or_default();
+            json!(api.exec_control(
+                name,
+                number(1)? as i32,
+                number(2)?,
+                args.get(3).map(|x| x.as_str()).unwrap_or(""),
+                &body_parts
+            ))
+        }
+        "update" => json!(api.exec_update(number(0)? as i32, number(1)? as i32)),
+        "stop" => json!(api.exec_stop(number(0)? as i32)),
+        _ => return Err(format!("Unknown function '{}'", function)),
+    })
+}
+
+fn status_json(tk: &mut Telekinesis) -> Value {
+    let connection = tk.status.connection_status().to_string();
+    let scanning = tk.status.is_scanning();
+    let actuators = tk
+        .status
+        .actuator_status()
+        .iter()
+        .map(|x| {
+            json!({
+                "id": x.actuator.identifier(),
+                "connection": x.connection_status.to_string(),
+                "battery_level": x.battery_level,
+                "enabled": tk.settings.get_enabled(x.actuator.identifier()),
+            })
+        })
+        .collect::<Vec<Value>>();
+    json!({
+        "connection": connection,
+        "scanning": scanning,
+        "actuators": actuators,
+    })
+}
+
+fn tasks_json(tk: &mut Telekinesis) -> Value {
+    tk.active_tasks()
+        .into_iter()
+        .map(|(handle, actuators)| json!({ "handle": handle, "actuators": actuators }))
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+fn not_connected() -> (u16, String) {
+    (503, String::from("Not connected"))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        415 => "Unsupported Media Type",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        api::ApiCaller, fake_connection::connected_fakes, settings::TkSettings,
+        telekinesis::ERROR_HANDLE,
+    };
+    use bp_fakes::*;
+    use buttplug::core::message::ActuatorType;
+
+    fn connected_api() -> TkApi {
+        let (tk, _) = connected_fakes(
+            vec![scalar(1, "vib1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+        TkApi {
+            state: Arc::new(Mutex::new(Some(tk))),
+            caller: ApiCaller::Integration,
+        }
+    }
+
+    #[test]
+    fn status_lists_actuators() {
+        let mut api = connected_api();
+        let (status, body) = handle_request(&mut api, "GET", "/status", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["connection"], "Connected");
+        assert_eq!(body["actuators"][0]["id"], "vib1 (Vibrate)");
+    }
+
+    #[test]
+    fn api_functions_are_mapped() {
+        let mut api = connected_api();
+        let (status, body) = handle_request(&mut api, "POST", "/api/qry_lst", r#"["devices"]"#);
+        assert_eq!(status, 200);
+        assert_eq!(body, json!(["vib1 (Vibrate)"]));
+
+        let (status, body) = handle_request(
+            &mut api,
+            "POST",
+            "/api/cmd_1",
+            r#"["device.settings.enable", "vib1 (Vibrate)"]"#,
+        );
+        assert_eq!((status, body), (200, json!(true)));
+    }
+
+    #[test]
+    fn http_calls_do_not_fail_game_calls() {
+        let mut integration = connected_api();
+        let mut game = TkApi {
+            state: integration.state.clone(),
+            caller: ApiCaller::Game,
+        };
+        let http = thread::spawn(move || {
+            (0..200).all(|_| {
+                let status = handle_request(&mut integration, "GET", "/status", "").0;
+                let metrics = integration.try_exec(|tk| render_metrics(Some(tk)), String::new());
+                status == 200 && !metrics.is_empty()
+            })
+        });
+        for _ in 0..200 {
+            let handle = game.tk_control_list("vibrate", 10, 0.1, "", &[]);
+            assert_ne!(handle, ERROR_HANDLE);
+            assert!(game.tk_stop(handle));
+        }
+        assert!(http.join().unwrap());
+    }
+
+    #[test]
+    fn connection_cannot_be_changed() {
+        let mut api = connected_api();
+        let (status, _) = handle_request(&mut api, "POST", "/api/cmd", r#"["disconnect"]"#);
+        assert_eq!(status, 400);
+        assert!(api.state.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn file_writing_commands_are_rejected() {
+        let mut api = connected_api();
+        for body in [r#"["diagnostics.export"]"#, r#"["settings.store"]"#] {
+            let (status, _) = handle_request(&mut api, "POST", "/api/cmd", body);
+            assert_eq!(status, 400);
+        }
+        let (status, _) = handle_request(
+            &mut api,
+            "POST",
+            "/api/cmd_2",
+            r#"["device.trace.export", "vib1 (Vibrate)", "x.csv"]"#,
+        );
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn settings_are_redacted() {
+        let mut api = connected_api();
+        api.state
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .settings
+            .http
+            .token = String::from("secret");
+        let (_, body) = handle_request(&mut api, "GET", "/settings", "");
+        assert_eq!(body["http"]["token"], "<redacted>");
+    }
+
+    fn request(method: &str, token: Option<&str>, content_type: Option<&str>) -> TkHttpRequest {
+        TkHttpRequest {
+            method: String::from(method),
+            path: String::from("/api/cmd"),
+            authorization: token.map(|x| format!("Bearer {}", x)),
+            content_type: content_type.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn requests_need_token_and_json() {
+        let settings = TkHttpSettings {
+            token: String::from("secret"),
+            allowed_origins: vec![String::from("http://localhost:8080")],
+            ..Default::default()
+        };
+        let json = Some("application/json; charset=utf-8");
+        assert_eq!(
+            check_request(&settings, &request("POST", Some("secret"), json)),
+            Ok(())
+        );
+        assert_eq!(
+            check_request(&settings, &request("GET", Some("secret"), None)),
+            Ok(())
+        );
+        assert_eq!(
+            check_request(&settings, &request("POST", None, json))
+                .unwrap_err()
+                .0,
+            401
+        );
+        assert_eq!(
+            check_request(&settings, &request("POST", Some("guess"), json))
+                .unwrap_err()
+                .0,
+            401
+        );
+        assert_eq!(
+            check_request(
+                &settings,
+                &request("POST", Some("secret"), Some("text/plain"))
+            )
+            .unwrap_err()
+            .0,
+            415
+        );
+
+        let from = |origin: &str| TkHttpRequest {
+            origin: Some(String::from(origin)),
+            ..request("OPTIONS", None, None)
+        };
+        assert_eq!(
+            check_request(&settings, &from("http://localhost:8080")),
+            Ok(())
+        );
+        assert_eq!(
+            check_request(&settings, &from("https://evil.example"))
+                .unwrap_err()
+                .0,
+            403
+        );
+        assert_eq!(
+            check_request(&TkHttpSettings::default(), &request("GET", Some(""), None))
+                .unwrap_err()
+                .0,
+            401
+        );
+    }
+
+    #[test]
+    fn request_size_is_limited() {
+        let parse = |raw: String| tokio_test::block_on(read_request(&mut raw.as_bytes()));
+        let request = parse(String::from(
+            "POST /api/cmd HTTP/1.1\r\nAuthorization: Bearer x\r\nContent-Length: 2\r\n\r\n[]",
+        ))
+        .unwrap();
+        assert_eq!(request.authorization.as_deref(), Some("Bearer x"));
+        assert_eq!(request.body, "[]");
+
+        let headers = "X-Header: 1\r\n".repeat(MAX_HEADERS + 1);
+        assert!(parse(format!("GET / HTTP/1.1\r\n{}\r\n", headers)).is_err());
+        let long = "a".repeat(MAX_LINE_LENGTH as usize);
+        assert!(parse(format!("GET /{} HTTP/1.1\r\n\r\n", long)).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let mut api = TkApi {
+            state: Arc::new(Mutex::new(None)),
+            caller: ApiCaller::Integration,
+        };
+        assert_eq!(handle_request(&mut api, "GET", "/status", "").0, 503);
+        assert_eq!(handle_request(&mut api, "GET", "/unknown", "").0, 404);
+        assert_eq!(handle_request(&mut api, "POST", "/api/cmd", "{}").0, 400);
+        assert_eq!(
+            handle_request(&mut api, "POST", "/api/bogus", r#"["x"]"#).0,
+            400
+        );
+    }
+}
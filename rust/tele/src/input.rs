@@ -6,13 +6,11 @@ use funscript::FScript;
 use tracing::{debug, error};
 
 use bp_scheduler::actuator::Actuator;
-use crate::{connection::Task, settings::TkDeviceSettings};
-
-pub fn sanitize_name_list(list: &[String]) -> Vec<String> {
-    list.iter()
-        .map(|e| e.to_lowercase().trim().to_owned())
-        .collect()
-}
+use crate::{
+    body_parts::{matches_body_parts, TkBodyPartExpr, TkBodyPartGroups},
+    connection::Task,
+    settings::TkDeviceSettings,
+};
 
 pub fn parse_csv(input: &str) -> Vec<String> {
     let mut list = vec![];
@@ -24,7 +22,29 @@ pub fn parse_csv(input: &str) -> Vec<String> {
     list
 }
 
-#[derive(Debug)]
+/// Case insensitive match where `*` matches any sequence of characters
+pub fn matches_wildcard(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let text = text.trim().to_lowercase();
+    let parts = pattern.split('*').collect::<Vec<&str>>();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || !text.ends_with(last) || text.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[derive(Debug, Clone)]
 pub struct DeviceCommand {
     pub task: Task,
     pub duration: Duration,
@@ -38,7 +58,7 @@ impl DeviceCommand {
         task: Task,
         actuator_type: &[ActuatorType],
         time_sec: f32,
-        body_parts: &CxxVector<CxxString>,
+        body_parts: &[String],
         fscript: Option<FScript>,
     ) -> Self {
         Self {
@@ -46,7 +66,7 @@ impl DeviceCommand {
             task,
             duration: get_duration_from_secs(time_sec),
             fscript,
-            body_parts: read_input_string(body_parts),
+            body_parts: body_parts.to_vec(),
         }
     }
 }
@@ -87,19 +107,16 @@ pub struct TkParams {}
 impl TkParams {
     pub fn filter_devices(
         actuators: &[Arc<Actuator>],
-        input_body_parts: &[String],
+        body_parts: &[TkBodyPartExpr],
         actuator_types: &[ActuatorType],
-        device_settings: &[TkDeviceSettings]
+        device_settings: &[TkDeviceSettings],
+        body_part_groups: &TkBodyPartGroups
         ) -> Vec<Arc<Actuator>> {
-        let body_parts = sanitize_name_list(input_body_parts);
         let selected_settings = device_settings.iter().filter( |setting| { 
             if ! setting.enabled {
                 return false;
             }
-            if body_parts.is_empty() {
-                return true;
-            }
-            setting.events.iter().any( |y| body_parts.contains(y) )
+            matches_body_parts(body_parts, &setting.events, body_part_groups)
         }).cloned().collect::<Vec<TkDeviceSettings>>();
 
         let selected = selected_settings.iter().map(|x| x.actuator_id.clone()).collect::<Vec<String>>();
@@ -1,13 +1,18 @@
 
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 use itertools::Itertools;
 
 use tracing::{
     instrument,
-    info
+    info,
+    error
 };
 
 use cxx::{CxxString, CxxVector};
+use tokio::sync::broadcast::error::RecvError;
 
 use buttplug::core::message::ActuatorType;
 
@@ -16,28 +21,63 @@ use bp_scheduler::{
     speed::*
 };
 
-use ffi::SKSEModEvent;
+pub use ffi::SKSEModEvent;
 use crate::{
     api::*,
+    audio::get_audio_pattern_name,
+    body_parts::*,
     pattern::*,
+    recorder::*,
     input::*,
+    logging::{get_log_filter, get_log_tail, parse_tail_query, set_log_filter},
+    metrics::*,
     telekinesis::*,
     connection::*,
-    settings::*
+    diagnostics::*,
+    http::run_http_server,
+    mqtt::run_mqtt_bridge,
+    osc::{run_osc_listener, run_osc_sender},
+    player_sync::*,
+    sensor::*,
+    server::{stop_preempted_tasks, TkServerPriority},
+    settings::*,
+    spatial::*,
+    trace::*,
+    websocket::*
 };
 
 mod api;
+mod audio;
+mod body_parts;
+pub mod cli;
 mod connection;
+mod diagnostics;
+mod export;
+#[cfg(test)]
+mod fake_connection;
+mod http;
 mod input;
 mod logging;
+mod metrics;
+mod mqtt;
+mod osc;
 mod pattern;
+mod pattern_format;
+mod player_sync;
+mod recorder;
+mod sensor;
+mod server;
 mod settings;
+mod spatial;
 mod status;
 pub mod telekinesis;
+mod trace;
+mod websocket;
 
 #[derive(Debug)]
 pub struct TkApi {
     pub state: Arc<Mutex<Option<Telekinesis>>>,
+    pub caller: ApiCaller,
 }
 
 /// Methods exposed as papyrus native functions
@@ -101,9 +141,10 @@ impl SKSEModEvent {
     }
 }
 
-fn tk_new() -> Box<TkApi> {
+pub fn tk_new() -> Box<TkApi> {
     Box::new(TkApi {
         state: Arc::new(Mutex::new(None)),
+        caller: ApiCaller::Game,
     })
 }
 
@@ -111,10 +152,16 @@ impl Api<Telekinesis> for TkApi {
     fn state(&mut self) -> Arc<Mutex<Option<Telekinesis>>> {
         self.state.clone()
     }
+    fn caller(&self) -> ApiCaller {
+        self.caller
+    }
 
     fn fns(&self) -> ApiBuilder<Telekinesis> {
         build_api()
     }
+    fn prepare(&self, tk: &mut Telekinesis) {
+        tk.process_status();
+    }
     fn destroy(&mut self) -> ApiCmd0<Telekinesis> {
         ApiCmd0 {
             name: "disconnect",
@@ -127,49 +174,103 @@ impl Api<Telekinesis> for TkApi {
 }
 
 impl TkApi {
+    /// A handle for the integrations (http, osc, mqtt, player sync) on the same state,
+    /// their calls wait for the state and never make a papyrus native fail
+    pub fn integration(&self) -> TkApi {
+        TkApi {
+            state: self.state.clone(),
+            caller: ApiCaller::Integration,
+        }
+    }
+
+    /// The command as metric label, names that are not registered are counted
+    /// as `unknown` so arbitrary arguments cannot grow the label set
+    fn command_label(&mut self, command: &str) -> &'static str {
+        self.registered_name(command).unwrap_or(UNKNOWN_COMMAND)
+    }
+
     #[instrument(skip(self))]
     fn tk_cmd(&mut self, cmd: &str) -> bool {
-        self.exec_cmd_0(cmd)
+        let started = Instant::now();
+        let result = self.exec_cmd_0(cmd);
+        if result && cmd.eq_ignore_ascii_case("connect") {
+            self.start_services();
+        }
+        record("tk_cmd", &[cmd], &result);
+        observe_native_call("tk_cmd", self.command_label(cmd), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_cmd_1(&mut self, cmd: &str, arg0: &str) -> bool {
-        self.exec_cmd_1(cmd, arg0)
+        let started = Instant::now();
+        let result = self.exec_cmd_1(cmd, arg0);
+        record("tk_cmd_1", &[cmd, arg0], &result);
+        observe_native_call("tk_cmd_1", self.command_label(cmd), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_cmd_2(&mut self, cmd: &str, arg0: &str, arg1: &str) -> bool {
-        self.exec_cmd_2(cmd, arg0, arg1)
+        let started = Instant::now();
+        let result = self.exec_cmd_2(cmd, arg0, arg1);
+        record("tk_cmd_2", &[cmd, arg0, arg1], &result);
+        observe_native_call("tk_cmd_2", self.command_label(cmd), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_qry_str(&mut self, qry: &str) -> String {
-        self.exec_qry_str(qry)
+        let started = Instant::now();
+        let result = self.exec_qry_str(qry);
+        record("tk_qry_str", &[qry], &result);
+        observe_native_call("tk_qry_str", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_qry_str_1(&mut self, qry: &str, arg0: &str) -> String {
-        self.exec_qry_str_1(qry, arg0)
+        let started = Instant::now();
+        let result = self.exec_qry_str_1(qry, arg0);
+        record("tk_qry_str_1", &[qry, arg0], &result);
+        observe_native_call("tk_qry_str_1", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_qry_lst(&mut self, qry: &str) -> Vec<String> {
-        self.exec_qry_lst(qry)
+        let started = Instant::now();
+        let result = self.exec_qry_lst(qry);
+        record("tk_qry_lst", &[qry], &result);
+        observe_native_call("tk_qry_lst", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_qry_lst_1(&mut self, qry: &str, arg0: &str) -> Vec<String> {
-        self.exec_qry_lst_1(qry, arg0)
+        let started = Instant::now();
+        let result = self.exec_qry_lst_1(qry, arg0);
+        record("tk_qry_lst_1", &[qry, arg0], &result);
+        observe_native_call("tk_qry_lst_1", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_qry_bool(&mut self, qry: &str) -> bool {
-        self.exec_qry_bool(qry)
+        let started = Instant::now();
+        let result = self.exec_qry_bool(qry);
+        record("tk_qry_bool", &[qry], &result);
+        observe_native_call("tk_qry_bool", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_qry_bool_1(&mut self, qry: &str, arg0: &str) -> bool {
-        self.exec_qry_bool_1(qry, arg0)
+        let started = Instant::now();
+        let result = self.exec_qry_bool_1(qry, arg0);
+        record("tk_qry_bool_1", &[qry, arg0], &result);
+        observe_native_call("tk_qry_bool_1", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
@@ -181,23 +282,68 @@ impl TkApi {
         arg2: &str,
         arg3: &CxxVector<CxxString>,
     ) -> i32 {
-        self.exec_control(qry, arg0, arg1, arg2, arg3)
+        self.tk_control_list(qry, arg0, arg1, arg2, &read_input_string(arg3))
+    }
+
+    /// `tk_control` with the body parts as a list
+    fn tk_control_list(&mut self, qry: &str, arg0: i32, arg1: f32, arg2: &str, body_parts: &[String]) -> i32 {
+        let started = Instant::now();
+        let result = self.exec_control(qry, arg0, arg1, arg2, body_parts);
+        record(
+            "tk_control",
+            &[qry, arg0.to_string().as_str(), arg1.to_string().as_str(), arg2, body_parts.join(",").as_str()],
+            &result,
+        );
+        observe_native_call("tk_control", self.command_label(qry), started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_update(&mut self, arg0: i32, arg1: i32) -> bool {
-        self.exec_update(arg0, arg1)
+        let started = Instant::now();
+        let result = self.exec_update(arg0, arg1);
+        record("tk_update", &[arg0.to_string().as_str(), arg1.to_string().as_str()], &result);
+        observe_native_call("tk_update", "", started.elapsed());
+        result
     }
 
     #[instrument(skip(self))]
     fn tk_stop(&mut self, arg0: i32) -> bool {
-        self.exec_stop(arg0)
+        let started = Instant::now();
+        let result = self.exec_stop(arg0);
+        record("tk_stop", &[arg0.to_string().as_str()], &result);
+        observe_native_call("tk_stop", "", started.elapsed());
+        result
+    }
+
+    /// `tk_cmd("connect")` with settings that are not read from the default path
+    fn tk_connect_with(&mut self, settings: TkSettings) -> bool {
+        let started = Instant::now();
+        let result = match connect_with(settings) {
+            Ok(tk) => match self.state.lock() {
+                Ok(mut guard) => {
+                    guard.replace(tk);
+                    true
+                }
+                Err(_) => false,
+            },
+            Err(err) => {
+                error!("error constructing state: {:?}", err);
+                false
+            }
+        };
+        if result {
+            self.start_services();
+        }
+        record("tk_cmd", &["connect"], &result);
+        observe_native_call("tk_cmd", "connect", started.elapsed());
+        result
     }
 
     /// Return type Vec cause cxx crate does not support Option
     /// and Result enforces try catch with some weird template
     /// I don't wanna get into
-    fn tk_qry_nxt_evt(&mut self) -> Vec<SKSEModEvent> {
+    pub fn tk_qry_nxt_evt(&mut self) -> Vec<SKSEModEvent> {
         let tele = &self.state();
         let mut receiver = None;
         if let Ok(mut guard) = tele.lock() {
@@ -210,6 +356,7 @@ impl TkApi {
         match receiver {
             Some(receiver) => {
                 if let Some(evt) = get_next_events_blocking(&receiver) {
+                    record("tk_qry_nxt_evt", &[], &evt);
                     return vec![evt];
                 }
                 vec![]
@@ -218,6 +365,93 @@ impl TkApi {
         }
     }
 
+    /// Starts the enabled integrations (http api, osc, mqtt, player sync) on the runtime of the connection
+    pub fn start_services(&mut self) -> bool {
+        let integration = self.integration();
+        self.try_exec(
+            |tk| {
+                let api = || integration.integration();
+                tk.spawn(record_outputs(tk.trace.subscribe()));
+                if tk.settings.http.enabled {
+                    tk.spawn(run_http_server(tk.settings.http.clone(), api()));
+                }
+                if tk.settings.osc.enabled {
+                    tk.spawn(run_osc_listener(tk.settings.osc.clone(), api()));
+                    if let Some(target) = tk.settings.osc.send_to.clone() {
+                        let outputs = tk.trace.subscribe();
+                        let events = tk.subscribe_events();
+                        tk.spawn(run_osc_sender(target, api(), outputs, events));
+                    }
+                }
+                if tk.settings.mqtt.enabled {
+                    let events = tk.subscribe_events();
+                    tk.spawn(run_mqtt_bridge(tk.settings.mqtt.clone(), api(), events));
+                }
+                if tk.settings.sync.enabled {
+                    tk.spawn(run_player_sync(tk.settings.sync.clone(), api(), tk.sync.clone()));
+                }
+                if tk.settings.server.enabled && tk.settings.server.priority == TkServerPriority::External {
+                    tk.spawn(stop_preempted_tasks(tk.device_access(), api()));
+                }
+                true
+            },
+            false,
+        )
+    }
+
+    /// Collects the device output of the connection as output lines, to compare
+    /// a replay with the recording
+    pub fn collect_outputs(&mut self) -> Arc<Mutex<Vec<TkRecordedCall>>> {
+        let outputs = Arc::new(Mutex::new(vec![]));
+        let collected = outputs.clone();
+        self.try_exec(
+            |tk| {
+                let mut receiver = tk.trace.subscribe();
+                tk.spawn(async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(entry) => {
+                                if let Ok(mut collected) = collected.lock() {
+                                    collected.push(output_call(entry.at_ms, &entry));
+                                }
+                            }
+                            Err(RecvError::Lagged(count)) => error!(count, "device output was not collected"),
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                });
+                true
+            },
+            false,
+        );
+        outputs
+    }
+
+    /// Executes a recorded native call, events are not replayed
+    pub fn replay_call(&mut self, call: &TkRecordedCall) -> Option<String> {
+        let arg = |i: usize| call.args.get(i).map(|x| x.as_str()).unwrap_or_default();
+        let number = |i: usize| arg(i).parse::<f32>().unwrap_or_default();
+        let result = match call.call.as_str() {
+            "tk_cmd" => format!("{:?}", self.tk_cmd(arg(0))),
+            "tk_cmd_1" => format!("{:?}", self.tk_cmd_1(arg(0), arg(1))),
+            "tk_cmd_2" => format!("{:?}", self.tk_cmd_2(arg(0), arg(1), arg(2))),
+            "tk_qry_str" => format!("{:?}", self.tk_qry_str(arg(0))),
+            "tk_qry_str_1" => format!("{:?}", self.tk_qry_str_1(arg(0), arg(1))),
+            "tk_qry_lst" => format!("{:?}", self.tk_qry_lst(arg(0))),
+            "tk_qry_lst_1" => format!("{:?}", self.tk_qry_lst_1(arg(0), arg(1))),
+            "tk_qry_bool" => format!("{:?}", self.tk_qry_bool(arg(0))),
+            "tk_qry_bool_1" => format!("{:?}", self.tk_qry_bool_1(arg(0), arg(1))),
+            "tk_control" => {
+                let body_parts = parse_csv(arg(4));
+                format!("{:?}", self.tk_control_list(arg(0), number(1) as i32, number(2), arg(3), &body_parts))
+            }
+            "tk_update" => format!("{:?}", self.tk_update(number(0) as i32, number(1) as i32)),
+            "tk_stop" => format!("{:?}", self.tk_stop(number(0) as i32)),
+            _ => return None,
+        };
+        Some(result)
+    }
+
     #[instrument]
     fn tk_destroy(&mut self) {
         self.destroy();
@@ -234,6 +468,8 @@ pub fn get_next_events_blocking(connection_events: &crossbeam_channel::Receiver<
             TkConnectionEvent::ConnectionFailure(err) => {
                 SKSEModEvent::from("Tele_ConnectionError", &err)
             }
+            TkConnectionEvent::ScanStarted => SKSEModEvent::from("Tele_ScanStarted", ""),
+            TkConnectionEvent::ScanFinished => SKSEModEvent::from("Tele_ScanFinished", ""),
             TkConnectionEvent::DeviceAdded(device, battery_level) => {
                 let mut evt = SKSEModEvent::from("Tele_DeviceAdded", device.name());
                 evt.num_arg = battery_level.unwrap_or(0.0);
@@ -265,21 +501,37 @@ pub fn get_next_events_blocking(connection_events: &crossbeam_channel::Receiver<
             TkConnectionEvent::BatteryLevel(device, battery_level) => {
                 SKSEModEvent::new("Tele_BatteryLevel", device.name(), battery_level.unwrap_or(0.0))
             },
+            TkConnectionEvent::BatteryLow(device, battery_level) => {
+                SKSEModEvent::new("Tele_BatteryLow", device.name(), battery_level)
+            },
+            // readings only update the status, see device.sensor.*
+            TkConnectionEvent::SensorReading(_, _, _, _) => return None,
+            TkConnectionEvent::SensorThreshold(device, sensor, value, above) => {
+                let str_arg = format!("{}:{}:{}", device.name(), sensor, if above { "above" } else { "below" });
+                SKSEModEvent::new("Tele_SensorThreshold", &str_arg, f64::from(value))
+            },
+            TkConnectionEvent::ButtonPressed(device, pressed) => {
+                let event_name = if pressed { "Tele_ButtonPressed" } else { "Tele_ButtonReleased" };
+                SKSEModEvent::from(event_name, device.name())
+            },
         };
         return Some(event);
     }
     None
 }
 
+/// Starts the recorder if it is enabled in the settings and connects
+fn connect_with(settings: TkSettings) -> Result<Telekinesis, anyhow::Error> {
+    if settings.recorder.enabled && !is_recording() {
+        start_recording(&settings.recorder.path);
+    }
+    Telekinesis::connect(settings)
+}
+
 pub fn build_api() -> ApiBuilder<Telekinesis> {
     ApiBuilder::new(ApiInit {
         name: "connect",
-        exec: || {
-            Telekinesis::connect(TkSettings::try_read_or_default(
-                SETTINGS_PATH,
-                SETTINGS_FILE,
-            ))
-        },
+        exec: || connect_with(TkSettings::try_read_or_default(SETTINGS_PATH, SETTINGS_FILE)),
     })
     // connection
     .def_cmd(ApiCmd0 {
@@ -292,10 +544,44 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
     .def_cmd1(ApiCmd1 {
         name: "connection.websocket",
         exec: |tk, value| {
+            if let Err(err) = TkWebSocketEndpoint::parse(value) {
+                error!("{}", err);
+                return false;
+            }
             tk.settings.connection = TkConnectionType::WebSocket(String::from(value));
             true
         },
     })
+    .def_cmd1(ApiCmd1 {
+        name: "connection.inprocess.manager.enable",
+        exec: |tk, manager| tk.settings.in_process.set_manager_enabled(manager, true),
+    })
+    .def_cmd1(ApiCmd1 {
+        name: "connection.inprocess.manager.disable",
+        exec: |tk, manager| tk.settings.in_process.set_manager_enabled(manager, false),
+    })
+    .def_qry_bool_1(ApiQryBool1 {
+        name: "connection.inprocess.manager.enabled",
+        exec: |tk, manager| tk.settings.in_process.get_manager_enabled(manager),
+    })
+    .def_cmd(ApiCmd0 {
+        name: "server.enable",
+        exec: |tk| {
+            tk.settings.server.enabled = true;
+            true
+        },
+    })
+    .def_cmd(ApiCmd0 {
+        name: "server.disable",
+        exec: |tk| {
+            tk.settings.server.enabled = false;
+            true
+        },
+    })
+    .def_qry_bool(ApiQryBool {
+        name: "server.enabled",
+        exec: |tk| tk.settings.server.enabled,
+    })
     .def_qry_str(ApiQryStr {
         name: "connection.status",
         default: "Not Connected",
@@ -310,6 +596,48 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         name: "stop_scan",
         exec: |tk| tk.stop_scan(),
     })
+    .def_qry_bool(ApiQryBool {
+        name: "connection.scanning",
+        exec: |tk| tk.status.is_scanning(),
+    })
+    .def_cmd(ApiCmd0 {
+        name: "recorder.start",
+        exec: |tk| start_recording(&tk.settings.recorder.path),
+    })
+    .def_cmd(ApiCmd0 {
+        name: "recorder.stop",
+        exec: |_| stop_recording(),
+    })
+    .def_qry_bool(ApiQryBool {
+        name: "recorder.recording",
+        exec: |_| is_recording(),
+    })
+    .def_cmd1(ApiCmd1 {
+        name: "log.level",
+        exec: |_, filter| set_log_filter(filter),
+    })
+    .def_qry_str(ApiQryStr {
+        name: "log.level",
+        default: "",
+        exec: |_| get_log_filter(),
+    })
+    .def_qry_lst_1(ApiQryList1 {
+        name: "log.tail",
+        exec: |_, query| {
+            let (count, level) = parse_tail_query(query);
+            get_log_tail(count, level)
+        },
+    })
+    .def_cmd(ApiCmd0 {
+        name: "diagnostics.export",
+        exec: |tk| match export_diagnostics(tk, DIAGNOSTICS_FOLDER) {
+            Ok(()) => true,
+            Err(err) => {
+                error!("{}", err);
+                false
+            }
+        },
+    })
     // controls
     .def_control(ApiControl {
         name: "vibrate",
@@ -326,7 +654,7 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
     })
     .def_control(ApiControl {
         name: "scalar",
-        exec: |tk, speed, time_sec, actuator_type, body_parts: &CxxVector<CxxString>| {
+        exec: |tk, speed, time_sec, actuator_type, body_parts| {
             let cmd = DeviceCommand::from_inputs(
                 Task::Scalar(Speed::new(speed.into())),
                 &[read_scalar_actuator(actuator_type)],
@@ -339,13 +667,14 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
     })
     .def_control(ApiControl {
         name: "vibrate.pattern",
-        exec: |tk, speed, time_sec, pattern_name, body_parts| match read_pattern(
+        exec: |tk, speed, time_sec, pattern_name, body_parts| match read_pattern_playback(
             &tk.settings.pattern_path,
             pattern_name,
             true,
+            get_duration_from_secs(time_sec),
         ) {
-            Some(fscript) => {
-                let cmd = DeviceCommand::from_inputs(
+            Some((fscript, duration)) => {
+                let mut cmd = DeviceCommand::from_inputs(
                     Task::Pattern(
                         Speed::new(speed.into()),
                         ActuatorType::Vibrate,
@@ -355,26 +684,52 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
                     time_sec,
                     body_parts,
                     Some(fscript));
+                cmd.duration = duration;
                 tk.dispatch_cmd(cmd)
             },
             None => ERROR_HANDLE,
         },
         default: ERROR_HANDLE,
     })
+    .def_control(ApiControl {
+        name: "vibrate.audio",
+        exec: |tk, speed, time_sec, audio_file, body_parts| match get_audio_pattern_name(audio_file) {
+            Ok(pattern_name) => {
+                let cmd = DeviceCommand::from_inputs(
+                    Task::Pattern(
+                        Speed::new(speed.into()),
+                        ActuatorType::Vibrate,
+                        pattern_name,
+                    ),
+                    &[ActuatorType::Vibrate],
+                    time_sec,
+                    body_parts,
+                    None);
+                tk.dispatch_audio(cmd, audio_file)
+            },
+            Err(err) => {
+                error!("{}", err);
+                ERROR_HANDLE
+            }
+        },
+        default: ERROR_HANDLE,
+    })
     .def_control(ApiControl {
         name: "linear.pattern",
-        exec: |tk, speed, time_sec, pattern_name, body_parts| match read_pattern(
+        exec: |tk, speed, time_sec, pattern_name, body_parts| match read_pattern_playback(
             &tk.settings.pattern_path,
             pattern_name,
             false,
+            get_duration_from_secs(time_sec),
         ) {
-            Some(fscript) => {
-                let cmd = DeviceCommand::from_inputs(
+            Some((fscript, duration)) => {
+                let mut cmd = DeviceCommand::from_inputs(
                     Task::Linear(Speed::new(speed.into()), pattern_name.into()),
                     &[ActuatorType::Position],
                     time_sec,
                     body_parts,
                     Some(fscript));
+                cmd.duration = duration;
                 tk.dispatch_cmd(cmd)
             },
             None => ERROR_HANDLE,
@@ -394,6 +749,51 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         },
         default: ERROR_HANDLE,
     })
+    .def_control(ApiControl {
+        name: "vibrate.direction",
+        exec: |tk, speed, time_sec, direction, body_parts| match TkSpatialEffect::parse_direction(direction) {
+            Ok(effect) => {
+                let cmd = DeviceCommand::from_inputs(
+                    Task::Scalar(Speed::new(speed.into())),
+                    &[ActuatorType::Vibrate],
+                    time_sec,
+                    body_parts,
+                    None);
+                tk.dispatch_spatial(cmd, effect)
+            },
+            Err(err) => {
+                error!("{}", err);
+                ERROR_HANDLE
+            }
+        },
+        default: ERROR_HANDLE,
+    })
+    .def_control(ApiControl {
+        name: "vibrate.sweep",
+        exec: |tk, speed, time_sec, sweep, body_parts| match TkSpatialEffect::parse_sweep(sweep) {
+            Ok(effect) => {
+                let cmd = DeviceCommand::from_inputs(
+                    Task::Scalar(Speed::new(speed.into())),
+                    &[ActuatorType::Vibrate],
+                    time_sec,
+                    body_parts,
+                    None);
+                tk.dispatch_spatial(cmd, effect)
+            },
+            Err(err) => {
+                error!("{}", err);
+                ERROR_HANDLE
+            }
+        },
+        default: ERROR_HANDLE,
+    })
+    .def_control(ApiControl {
+        name: "event.fire",
+        exec: |tk, intensity, time_sec, event, body_parts| {
+            tk.fire_event(event, intensity, time_sec, body_parts.to_vec())
+        },
+        default: ERROR_HANDLE,
+    })
     .def_update(ApiUpdate {
         exec: |tk, handle, speed| tk.update(handle, Speed::new(speed.into())),
     })
@@ -404,6 +804,26 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         name: "stop_all",
         exec: Telekinesis::stop_all,
     })
+    // player sync
+    .def_cmd1(ApiCmd1 {
+        name: "sync.start",
+        exec: |tk, pattern_name| match TkSyncTarget::read(&tk.settings.pattern_path, pattern_name) {
+            Ok(target) => tk.sync.start(target),
+            Err(err) => {
+                error!("{}", err);
+                false
+            }
+        },
+    })
+    .def_cmd(ApiCmd0 {
+        name: "sync.stop",
+        exec: |tk| tk.sync.stop(),
+    })
+    .def_qry_str(ApiQryStr {
+        name: "sync.status",
+        default: "Stopped",
+        exec: |tk| tk.sync.status(),
+    })
     // settings
     .def_cmd(ApiCmd0 {
         name: "settings.store",
@@ -433,6 +853,40 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         },
         default: ""
     })
+    .def_qry_str1(ApiQryStr1 { 
+        name: "device.get_battery_trend", 
+        exec: |tk, actuator_id| {
+            if let Some(actuator_status) = tk.status.get_actuator_status(actuator_id)  {
+                return actuator_status.battery_trend().map(|x| (x.round() as i32).to_string()).unwrap_or("".to_owned());
+            }
+            "".into()
+        },
+        default: ""
+    })
+    .def_qry_str1(ApiQryStr1 { 
+        name: "device.get_battery_remaining", 
+        exec: |tk, actuator_id| {
+            if let Some(actuator_status) = tk.status.get_actuator_status(actuator_id)  {
+                return actuator_status.battery_remaining().map(|x| (x.as_secs() / 60).to_string()).unwrap_or("".to_owned());
+            }
+            "".into()
+        },
+        default: ""
+    })
+    .def_qry_str1(ApiQryStr1 {
+        name: "device.sensor.rssi",
+        exec: |tk, actuator_id| tk.status.get_sensor(actuator_id, TkSensorType::Rssi).map(|x| x.to_string()).unwrap_or_default(),
+        default: ""
+    })
+    .def_qry_str1(ApiQryStr1 {
+        name: "device.sensor.pressure",
+        exec: |tk, actuator_id| tk.status.get_sensor(actuator_id, TkSensorType::Pressure).map(|x| x.to_string()).unwrap_or_default(),
+        default: ""
+    })
+    .def_qry_bool_1(ApiQryBool1 {
+        name: "device.sensor.button",
+        exec: |tk, actuator_id| tk.status.get_sensor(actuator_id, TkSensorType::Button).map(|x| x != 0).unwrap_or(false),
+    })
     .def_qry_str1(ApiQryStr1 {
         name: "device.actuator",
         default: "Not Connected",
@@ -499,6 +953,58 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         name: "device.settings.events",
         exec: |tk, actuator_id| tk.settings.get_events(actuator_id),
     })
+    .def_cmd2(ApiCmd2 {
+        name: "device.settings.position",
+        exec: |tk, actuator_id, position| {
+            if position.trim().is_empty() {
+                tk.settings.set_position(actuator_id, None);
+                return true;
+            }
+            match TkPosition::parse(position) {
+                Ok(position) => {
+                    tk.settings.set_position(actuator_id, Some(position));
+                    true
+                }
+                Err(err) => {
+                    error!("{}", err);
+                    false
+                }
+            }
+        },
+    })
+    .def_qry_str1(ApiQryStr1 {
+        name: "device.settings.position",
+        default: "",
+        exec: |tk, actuator_id| {
+            tk.settings
+                .get_position(actuator_id)
+                .map(|x| x.to_string())
+                .unwrap_or_default()
+        },
+    })
+    .def_cmd1(ApiCmd1 {
+        name: "body_parts.group",
+        exec: |tk, definition| match parse_group(definition) {
+            Ok((name, body_parts)) => {
+                tk.settings.body_part_groups.insert(name, body_parts);
+                true
+            }
+            Err(err) => {
+                error!("{}", err);
+                false
+            }
+        },
+    })
+    .def_qry_lst(ApiQryList {
+        name: "body_parts.groups",
+        exec: |tk| {
+            tk.settings
+                .body_part_groups
+                .iter()
+                .map(|(name, body_parts)| format!("@{} = {}", name, body_parts.join(",")))
+                .collect()
+        },
+    })
     .def_qry_str1(ApiQryStr1 {
         name: "device.scalar.min_speed",
         default: "",
@@ -645,7 +1151,55 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         default: "Not Connected",
         exec: |tk, actuator_id| tk.status.get_actuator_connection_status(actuator_id).to_string(),
     })
+    // output trace
+    .def_qry_lst_1(ApiQryList1 {
+        name: "device.trace",
+        exec: |tk, actuator_id| {
+            tk.get_trace(actuator_id)
+                .map(|entries| trace_to_csv_lines(&entries))
+                .unwrap_or_default()
+        },
+    })
+    .def_cmd2(ApiCmd2 {
+        name: "device.trace.export",
+        exec: |tk, actuator_id, file_name| match tk.get_trace(actuator_id) {
+            Some(entries) => match export_trace(&entries, TRACE_EXPORT_FOLDER, file_name) {
+                Ok(_) => true,
+                Err(err) => {
+                    error!("{}", err);
+                    false
+                }
+            },
+            None => false,
+        },
+    })
+    .def_cmd2(ApiCmd2 {
+        name: "pattern.export.output",
+        exec: |tk, actuator_id, pattern_name| match tk.export_output(actuator_id, pattern_name) {
+            Ok(_) => true,
+            Err(err) => {
+                error!("{}", err);
+                false
+            }
+        },
+    })
     // patterns
+    .def_cmd2(ApiCmd2 {
+        name: "pattern.export.task",
+        exec: |tk, handle, pattern_name| {
+            let result = handle
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid handle '{}'", handle))
+                .and_then(|handle| tk.export_task(handle, pattern_name));
+            match result {
+                Ok(_) => true,
+                Err(err) => {
+                    error!("{}", err);
+                    false
+                }
+            }
+        },
+    })
     .def_qry_lst(ApiQryList {
         name: "patterns.vibrator",
         exec: |tk| get_pattern_names(&tk.settings.pattern_path, true),
@@ -654,4 +1208,8 @@ pub fn build_api() -> ApiBuilder<Telekinesis> {
         name: "patterns.stroker",
         exec: |tk| get_pattern_names(&tk.settings.pattern_path, false),
     })
+    .def_cmd1(ApiCmd1 {
+        name: "audio.pattern",
+        exec: |tk, audio_file| tk.prepare_audio(audio_file),
+    })
 }
@@ -1,12 +1,16 @@
 use std::{
-    fs::File,
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::Mutex,
 };
 
+use tracing::{error, info, Level, Metadata};
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, EnvFilter, Registry};
 
-use tracing::{Level, info};
-
-use crate::settings::{TkSettings, SETTINGS_FILE, SETTINGS_PATH};
+use crate::settings::{TkLogLevel, TkLogSettings, TkSettings, SETTINGS_FILE, SETTINGS_PATH};
 
 #[cxx::bridge]
 mod ffi {
@@ -17,43 +21,355 @@ mod ffi {
     }
 }
 
+struct TkLogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: String,
+}
+
+static LOG_FILTER: Mutex<Option<TkLogFilter>> = Mutex::new(None);
+
+/// Most recent log lines, accessible from within the game
+static LOG_TAIL: Mutex<VecDeque<(Level, String)>> = Mutex::new(VecDeque::new());
+static LOG_TAIL_SIZE: usize = 1000;
+
 pub fn tk_log_info(message: String) {
     info!(message);
 }
 
 pub fn tk_init_logging_stdout() -> bool {
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(Level::DEBUG)
-        .with_ansi(false)
-        .with_thread_ids(true)
-        .finish();
-
-    if tracing::subscriber::set_global_default(subscriber).is_err() {
-        eprintln!("Setting global tracing subscriber failed.");
-        return false;
-    }
-    true
+    init_subscriber(&level_directive(&TkLogLevel::Debug), io::stdout)
 }
 
 pub fn tk_init_logging(file_path: String) -> bool {
     let settings = TkSettings::try_read_or_default(SETTINGS_PATH, SETTINGS_FILE);
-    let file = match File::create(file_path) {
+    let file = match TkLogFile::create(&file_path, &settings.logging) {
         Ok(file) => file,
         Err(err) => {
             eprintln!("Couldn't write to log file, no logs available: {:?}", err);
             return false;
         }
     };
-    let log_lvl : Level = settings.log_level.into();
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(log_lvl)
-        .with_ansi(false)
-        .with_writer(Mutex::new(file))
-        .with_thread_ids(true)
-        .finish();
+    let mut directives = vec![level_directive(&settings.log_level)];
+    for (module, level) in &settings.logging.modules {
+        directives.push(format!("{}={}", module, level_directive(level)));
+    }
+    init_subscriber(&directives.join(","), Mutex::new(file))
+}
+
+fn init_subscriber<W>(directives: &str, writer: W) -> bool
+where
+    W: for<'a> fmt::MakeWriter<'a> + Send + Sync + 'static,
+{
+    let directives = module_directives(directives);
+    let filter = match EnvFilter::try_new(&directives) {
+        Ok(filter) => filter,
+        Err(err) => {
+            eprintln!("Invalid log filter '{}': {}", directives, err);
+            EnvFilter::new(level_directive(&TkLogLevel::Debug))
+        }
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            fmt::layer()
+                .with_ansi(false)
+                .with_writer(writer)
+                .with_thread_ids(true),
+        )
+        .with(fmt::layer().with_ansi(false).with_writer(TkLogTail));
     if tracing::subscriber::set_global_default(subscriber).is_err() {
         eprintln!("Setting global tracing subscriber failed.");
         return false;
     }
+    if let Ok(mut log_filter) = LOG_FILTER.lock() {
+        log_filter.replace(TkLogFilter { handle, directives });
+    }
     true
 }
+
+/// Changes the log filter without a restart. Accepts a level (`debug`) optionally
+/// followed by module levels (`info,connection=trace`)
+pub fn set_log_filter(input: &str) -> bool {
+    let directives = module_directives(input);
+    let filter = match EnvFilter::try_new(&directives) {
+        Ok(filter) => filter,
+        Err(err) => {
+            error!("invalid log filter '{}': {}", input, err);
+            return false;
+        }
+    };
+    let Ok(mut log_filter) = LOG_FILTER.lock() else {
+        return false;
+    };
+    match log_filter.as_mut() {
+        Some(log_filter) if log_filter.handle.reload(filter).is_ok() => {
+            log_filter.directives = directives;
+            info!("changed log filter to '{}'", log_filter.directives);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn get_log_filter() -> String {
+    match LOG_FILTER.lock() {
+        Ok(log_filter) => log_filter
+            .as_ref()
+            .map(|x| x.directives.clone())
+            .unwrap_or_default(),
+        Err(_) => String::new(),
+    }
+}
+
+/// The last `count` log lines with the given level or above, oldest first
+pub fn get_log_tail(count: usize, level: Level) -> Vec<String> {
+    let Ok(tail) = LOG_TAIL.lock() else {
+        return vec![];
+    };
+    let mut lines = tail
+        .iter()
+        .rev()
+        .filter(|(line_level, _)| *line_level <= level)
+        .take(count)
+        .map(|(_, line)| line.clone())
+        .collect::<Vec<String>>();
+    lines.reverse();
+    lines
+}
+
+/// Parses `count[,level]` as used by `log.tail`, i.e. `50,warn`
+pub fn parse_tail_query(input: &str) -> (usize, Level) {
+    let mut args = input.split(',').map(|x| x.trim());
+    let count = args
+        .next()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(LOG_TAIL_SIZE);
+    let level = args
+        .next()
+        .and_then(|x| Level::from_str(x).ok())
+        .unwrap_or(Level::TRACE);
+    (count, level)
+}
+
+fn push_log_line(level: Level, line: String) {
+    if let Ok(mut tail) = LOG_TAIL.lock() {
+        while tail.len() >= LOG_TAIL_SIZE {
+            tail.pop_front();
+        }
+        tail.push_back((level, line));
+    }
+}
+
+/// Formatted events are collected into `LOG_TAIL` line by line
+struct TkLogTail;
+
+struct TkLogTailWriter {
+    level: Level,
+    buffer: Vec<u8>,
+}
+
+impl<'a> fmt::MakeWriter<'a> for TkLogTail {
+    type Writer = TkLogTailWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        TkLogTailWriter {
+            level: Level::INFO,
+            buffer: vec![],
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        TkLogTailWriter {
+            level: *meta.level(),
+            buffer: vec![],
+        }
+    }
+}
+
+impl Write for TkLogTailWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TkLogTailWriter {
+    fn drop(&mut self) {
+        for line in String::from_utf8_lossy(&self.buffer).lines() {
+            if !line.trim().is_empty() {
+                push_log_line(self.level, line.to_owned());
+            }
+        }
+    }
+}
+
+fn level_directive(level: &TkLogLevel) -> String {
+    Level::from(level.clone()).to_string().to_lowercase()
+}
+
+/// Module names without a path (`connection`) refer to modules of this library,
+/// other crates are referenced by their path (`buttplug::server`)
+fn module_directives(input: &str) -> String {
+    input
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((module, level)) if !module.contains("::") => {
+                format!(
+                    "{}::{}={}",
+                    env!("CARGO_CRATE_NAME"),
+                    module.trim(),
+                    level.trim().to_lowercase()
+                )
+            }
+            _ => directive.to_lowercase(),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Log file that keeps the files of previous sessions (`Telekinesis.1.log`, ...)
+/// and is rotated once it exceeds the size limit
+struct TkLogFile {
+    path: PathBuf,
+    keep_files: usize,
+    max_bytes: u64,
+    file: File,
+    written: u64,
+}
+
+impl TkLogFile {
+    fn create(path: &str, settings: &TkLogSettings) -> io::Result<TkLogFile> {
+        let path = PathBuf::from(path);
+        rotate_files(&path, settings.keep_files);
+        Ok(TkLogFile {
+            file: File::create(&path)?,
+            path,
+            keep_files: settings.keep_files,
+            max_bytes: settings.max_file_size_mb * 1024 * 1024,
+            written: 0,
+        })
+    }
+}
+
+impl Write for TkLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_bytes > 0
+            && self.written > 0
+            && self.written + buf.len() as u64 > self.max_bytes
+        {
+            self.file.flush()?;
+            rotate_files(&self.path, self.keep_files);
+            self.file = File::create(&self.path)?;
+            self.written = 0;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .unwrap_or("Telekinesis");
+    let name = match path.extension().and_then(|x| x.to_str()) {
+        Some(extension) => format!("{}.{}.{}", stem, n, extension),
+        None => format!("{}.{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// Shifts `path` to `.1`, `.1` to `.2`... and drops files beyond `keep_files`
+fn rotate_files(path: &Path, keep_files: usize) {
+    if keep_files == 0 {
+        return;
+    }
+    let _ = fs::remove_file(rotated_path(path, keep_files));
+    for n in (1..keep_files).rev() {
+        let _ = fs::rename(rotated_path(path, n), rotated_path(path, n + 1));
+    }
+    let _ = fs::rename(path, rotated_path(path, 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn module_directives_are_prefixed() {
+        assert_eq!(
+            module_directives("INFO, connection=trace,buttplug::server=debug"),
+            "info,telekinesis_plug::connection=trace,buttplug::server=debug"
+        );
+        assert!(EnvFilter::try_new(module_directives("debug,connection=trace")).is_ok());
+    }
+
+    #[test]
+    fn log_tail_filters_by_level() {
+        push_log_line(Level::DEBUG, String::from("tail test debug"));
+        push_log_line(Level::WARN, String::from("tail test warn 1"));
+        push_log_line(Level::ERROR, String::from("tail test error"));
+        push_log_line(Level::WARN, String::from("tail test warn 2"));
+        assert_eq!(
+            get_log_tail(2, Level::WARN),
+            vec!["tail test error", "tail test warn 2"]
+        );
+        assert_eq!(get_log_tail(1, Level::TRACE), vec!["tail test warn 2"]);
+        assert_eq!(parse_tail_query("50, warn"), (50, Level::WARN));
+        assert_eq!(parse_tail_query(""), (LOG_TAIL_SIZE, Level::TRACE));
+    }
+
+    #[test]
+    fn previous_logs_are_kept() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("Telekinesis.log");
+        let settings = TkLogSettings {
+            keep_files: 2,
+            ..Default::default()
+        };
+        for session in ["first", "second", "third"] {
+            let mut file = TkLogFile::create(path.to_str().unwrap(), &settings).unwrap();
+            write!(file, "{}", session).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Telekinesis.1.log")).unwrap(),
+            "second"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Telekinesis.2.log")).unwrap(),
+            "first"
+        );
+        assert!(!dir.path().join("Telekinesis.3.log").exists());
+    }
+
+    #[test]
+    fn log_is_rotated_at_size_limit() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("Telekinesis.log");
+        let mut file =
+            TkLogFile::create(path.to_str().unwrap(), &TkLogSettings::default()).unwrap();
+        file.max_bytes = 8;
+        file.write_all(b"12345").unwrap();
+        file.write_all(b"67890").unwrap();
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "67890");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Telekinesis.1.log")).unwrap(),
+            "12345"
+        );
+    }
+}
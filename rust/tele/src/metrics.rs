@@ -0,0 +1,330 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use buttplug::core::message::ActuatorType;
+
+use crate::{status::TkConnectionStatus, telekinesis::Telekinesis};
+
+pub const NATIVE_CALLS: &str = "telekinesis_native_calls_total";
+pub const NATIVE_CALL_DURATION: &str = "telekinesis_native_call_duration_seconds";
+pub const TASKS_STARTED: &str = "telekinesis_tasks_started_total";
+pub const TASKS_FAILED: &str = "telekinesis_tasks_failed_total";
+pub const DEVICE_CONNECTS: &str = "telekinesis_device_connects_total";
+pub const DEVICE_DISCONNECTS: &str = "telekinesis_device_disconnects_total";
+pub const DISPATCH_LATENCY: &str = "telekinesis_dispatch_latency_seconds";
+/// Command label of native calls with a name that is not registered in the api
+pub const UNKNOWN_COMMAND: &str = "unknown";
+
+/// Name, type and help of every metric, in the order they are rendered
+static METRIC_FAMILIES: [(&str, &str, &str); 14] = [
+    (
+        NATIVE_CALLS,
+        "counter",
+        "Calls of the papyrus natives by function and command",
+    ),
+    (
+        NATIVE_CALL_DURATION,
+        "histogram",
+        "Time spent in the papyrus natives",
+    ),
+    (
+        DISPATCH_LATENCY,
+        "histogram",
+        "Time from the native call that started a task to its first device command",
+    ),
+    (TASKS_STARTED, "counter", "Tasks that were started"),
+    (
+        TASKS_FAILED,
+        "counter",
+        "Tasks that ended with a device error",
+    ),
+    (DEVICE_CONNECTS, "counter", "Devices that connected"),
+    (DEVICE_DISCONNECTS, "counter", "Devices that disconnected"),
+    (
+        "telekinesis_tasks_active",
+        "gauge",
+        "Tasks that are currently running",
+    ),
+    (
+        "telekinesis_dispatch_queue_depth",
+        "gauge",
+        "Tasks that were started and did not send a device command yet",
+    ),
+    (
+        "telekinesis_connection_command_queue_depth",
+        "gauge",
+        "Connection commands (stop all, scan, battery) waiting to be executed",
+    ),
+    (
+        "telekinesis_event_queue_depth",
+        "gauge",
+        "Events waiting to be fetched by the game",
+    ),
+    (
+        "telekinesis_actuator_output",
+        "gauge",
+        "Last level that was sent to the actuator",
+    ),
+    (
+        "telekinesis_actuator_connected",
+        "gauge",
+        "1 if the actuator is connected",
+    ),
+    (
+        "telekinesis_battery_level",
+        "gauge",
+        "Battery level of the device from 0 to 1",
+    ),
+];
+
+/// Upper bounds in seconds, a native call that takes longer than a frame (~16ms) stalls the game
+const DURATION_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.016, 0.05, 0.1, 1.0];
+
+#[derive(Debug, Default, Clone)]
+struct TkHistogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Metric name and rendered labels, i.e. `function="tk_cmd"`
+type TkMetricKey = (&'static str, String);
+
+#[derive(Debug)]
+struct TkMetrics {
+    counters: BTreeMap<TkMetricKey, u64>,
+    histograms: BTreeMap<TkMetricKey, TkHistogram>,
+}
+
+static METRICS: Mutex<TkMetrics> = Mutex::new(TkMetrics {
+    counters: BTreeMap::new(),
+    histograms: BTreeMap::new(),
+});
+
+pub fn inc_counter(name: &'static str, labels: &[(&str, &str)]) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        *metrics
+            .counters
+            .entry((name, render_labels(labels)))
+            .or_default() += 1;
+    }
+}
+
+pub fn observe_duration(name: &'static str, labels: &[(&str, &str)], duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    if let Ok(mut metrics) = METRICS.lock() {
+        let histogram = metrics
+            .histograms
+            .entry((name, render_labels(labels)))
+            .or_default();
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+}
+
+/// Counts the call and its duration, the command is the first argument of the native
+pub fn observe_native_call(function: &str, command: &str, duration: Duration) {
+    inc_counter(
+        NATIVE_CALLS,
+        &[("function", function), ("command", command)],
+    );
+    observe_duration(NATIVE_CALL_DURATION, &[("function", function)], duration);
+}
+
+/// All metrics in the prometheus text format, gauges are read from the connection
+/// if there is one
+pub fn render_metrics(tk: Option<&mut Telekinesis>) -> String {
+    let mut samples: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    if let Ok(metrics) = METRICS.lock() {
+        for ((name, labels), value) in &metrics.counters {
+            samples
+                .entry(*name)
+                .or_default()
+                .push(sample(name, labels, *value as f64));
+        }
+        for ((name, labels), histogram) in &metrics.histograms {
+            let lines = samples.entry(*name).or_default();
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                let le = format!("le=\"{}\"", bound);
+                lines.push(sample(
+                    &format!("{}_bucket", name),
+                    &join_labels(labels, &le),
+                    count as f64,
+                ));
+            }
+            lines.push(sample(
+                &format!("{}_bucket", name),
+                &join_labels(labels, "le=\"+Inf\""),
+                histogram.count as f64,
+            ));
+            lines.push(sample(&format!("{}_sum", name), labels, histogram.sum));
+            lines.push(sample(
+                &format!("{}_count", name),
+                labels,
+                histogram.count as f64,
+            ));
+        }
+    }
+    if let Some(tk) = tk {
+        for (name, labels, value) in gauges(tk) {
+            samples
+                .entry(name)
+                .or_default()
+                .push(sample(name, &labels, value));
+        }
+    }
+
+    let mut output = String::new();
+    for (name, kind, help) in METRIC_FAMILIES {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+        for line in samples.remove(name).unwrap_or_default() {
+            let _ = writeln!(output, "{}", line);
+        }
+    }
+    output
+}
+
+fn gauges(tk: &mut Telekinesis) -> Vec<(&'static str, String, f64)> {
+    let mut gauges = vec![
+        (
+            "telekinesis_tasks_active",
+            String::new(),
+            tk.active_tasks().len() as f64,
+        ),
+        (
+            "telekinesis_dispatch_queue_depth",
+            String::new(),
+            tk.trace.pending_dispatches() as f64,
+        ),
+        (
+            "telekinesis_connection_command_queue_depth",
+            String::new(),
+            tk.pending_commands() as f64,
+        ),
+        (
+            "telekinesis_event_queue_depth",
+            String::new(),
+            tk.connection_events.len() as f64,
+        ),
+    ];
+    let mut batteries = BTreeMap::new();
+    for status in tk.status.actuator_status().clone() {
+        let actuator = &status.actuator;
+        let labels = render_labels(&[("actuator", actuator.identifier())]);
+        let level = tk.trace.get_level(
+            actuator.device.index(),
+            actuator.index_in_device,
+            actuator.actuator == ActuatorType::Position,
+        );
+        gauges.push(("telekinesis_actuator_output", labels.clone(), level));
+        let connected = status.connection_status == TkConnectionStatus::Connected;
+        gauges.push((
+            "telekinesis_actuator_connected",
+            labels,
+            if connected { 1.0 } else { 0.0 },
+        ));
+        if let Some(battery) = status.battery_level {
+            batteries.insert(actuator.device.name().to_string(), battery);
+        }
+    }
+    for (device, battery) in batteries {
+        gauges.push((
+            "telekinesis_battery_level",
+            render_labels(&[("device", &device)]),
+            battery,
+        ));
+    }
+    gauges
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn join_labels(labels: &str, label: &str) -> String {
+    if labels.is_empty() {
+        label.to_owned()
+    } else {
+        format!("{},{}", labels, label)
+    }
+}
+
+fn sample(name: &str, labels: &str, value: f64) -> String {
+    if labels.is_empty() {
+        format!("{} {}", name, value)
+    } else {
+        format!("{}{{{}}} {}", name, labels, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{api::ApiCaller, fake_connection::connected_fakes, settings::TkSettings, TkApi};
+    use bp_fakes::*;
+
+    #[test]
+    fn counters_and_histograms_are_rendered() {
+        observe_native_call("tk_metrics_test", "vib\"rate", Duration::from_millis(2));
+        observe_native_call("tk_metrics_test", "vib\"rate", Duration::from_secs(2));
+        let output = render_metrics(None);
+        assert!(output.contains("# TYPE telekinesis_native_calls_total counter"));
+        assert!(output.contains(
+            r#"telekinesis_native_calls_total{function="tk_metrics_test",command="vib\"rate"} 2"#
+        ));
+        assert!(output.contains(
+            r#"telekinesis_native_call_duration_seconds_bucket{function="tk_metrics_test",le="0.005"} 1"#
+        ));
+        assert!(output.contains(
+            r#"telekinesis_native_call_duration_seconds_bucket{function="tk_metrics_test",le="+Inf"} 2"#
+        ));
+        assert!(output.contains(
+            r#"telekinesis_native_call_duration_seconds_count{function="tk_metrics_test"} 2"#
+        ));
+    }
+
+    #[test]
+    fn unregistered_commands_are_counted_as_unknown() {
+        let mut api = TkApi {
+            state: Arc::new(Mutex::new(None)),
+            caller: ApiCaller::Game,
+        };
+        api.tk_qry_str("metrics.test.unregistered");
+        api.tk_qry_str_1("Device.Get_Battery_Level", "vib1 (Vibrate)");
+        let output = render_metrics(None);
+        assert!(output.contains(r#"function="tk_qry_str",command="unknown""#));
+        assert!(!output.contains("metrics.test.unregistered"));
+        assert!(output.contains(r#"function="tk_qry_str_1",command="device.get_battery_level""#));
+    }
+
+    #[test]
+    fn gauges_are_read_from_connection() {
+        let (mut tk, _) = connected_fakes(
+            vec![scalar(1, "vib1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+
+        let output = render_metrics(Some(&mut tk));
+        assert!(output.contains("telekinesis_tasks_active 0"));
+        assert!(output.contains("telekinesis_dispatch_queue_depth 0"));
+        assert!(output.contains(r#"telekinesis_actuator_connected{actuator="vib1 (Vibrate)"} 1"#));
+        assert!(output.contains(r#"telekinesis_actuator_output{actuator="vib1 (Vibrate)"} 0"#));
+    }
+}
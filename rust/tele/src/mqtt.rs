@@ -0,0 +1,371 @@
+use std::{env, time::Duration};
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tracing::{debug, error, info};
+
+use crate::{connection::TkConnectionEvent, http::call_api, TkApi};
+
+/// Publishes device status, battery levels and task events to a mqtt broker
+/// and accepts api calls on `<topic_prefix>/cmd/<function>`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkMqttSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    /// Environment variable with the password, so it is not stored in the settings
+    pub password_env: String,
+    pub topic_prefix: String,
+    /// Delay before reconnecting after the broker connection was lost
+    pub reconnect_secs: u64,
+}
+
+impl Default for TkMqttSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: String::from("127.0.0.1"),
+            port: 1883,
+            client_id: String::from("telekinesis"),
+            username: None,
+            password_env: String::from("TELEKINESIS_MQTT_PASSWORD"),
+            topic_prefix: String::from("telekinesis"),
+            reconnect_secs: 5,
+        }
+    }
+}
+
+/// Commands that can be called through the broker: device control and status,
+/// but nothing that changes settings, takes a path or writes files
+static MQTT_COMMANDS: [&str; 20] = [
+    "vibrate",
+    "scalar",
+    "vibrate.pattern",
+    "linear.pattern",
+    "linear.stroke",
+    "vibrate.direction",
+    "vibrate.sweep",
+    "event.fire",
+    "stop_all",
+    "sync.start",
+    "sync.stop",
+    "sync.status",
+    "devices",
+    "connection.status",
+    "device.get_battery_level",
+    "device.get_battery_trend",
+    "device.get_battery_remaining",
+    "device.connection.status",
+    "patterns.vibrator",
+    "patterns.stroker",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+struct TkMqttMessage {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+impl TkMqttMessage {
+    fn new(topic: String, payload: String, retain: bool) -> Self {
+        TkMqttMessage {
+            topic,
+            payload,
+            retain,
+        }
+    }
+}
+
+pub async fn run_mqtt_bridge(
+    settings: TkMqttSettings,
+    api: TkApi,
+    events: broadcast::Receiver<TkConnectionEvent>,
+) {
+    let prefix = settings.topic_prefix.trim_end_matches('/').to_owned();
+    let status_topic = format!("{}/status", prefix);
+    let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        &status_topic,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &settings.username {
+        let password = env::var(&settings.password_env).unwrap_or_default();
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    tokio::spawn(publish_events(client.clone(), prefix.clone(), events));
+
+    let command_topic = format!("{}/cmd/+", prefix);
+    let reconnect = Duration::from_secs(settings.reconnect_secs.max(1));
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!(host = %settings.host, port = settings.port, "connected to mqtt broker");
+                if let Err(err) = client.try_subscribe(&command_topic, QoS::AtLeastOnce) {
+                    error!("Could not subscribe to {}. Error: {}.", command_topic, err);
+                }
+                let _ = client.try_publish(&status_topic, QoS::AtLeastOnce, true, "online");
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let Some(function) = command_function(&prefix, &publish.topic) else {
+                    continue;
+                };
+                let function = function.to_owned();
+                let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                let api = api.integration();
+                let result_topic = format!("{}/result/{}", prefix, function);
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let result = tokio::task::spawn_blocking(move || {
+                        handle_command(api, &function, &payload)
+                    })
+                    .await
+                    .unwrap_or_else(|err| json!({ "error": err.to_string() }));
+                    if let Err(err) = client
+                        .publish(result_topic, QoS::AtMostOnce, false, result.to_string())
+                        .await
+                    {
+                        debug!("mqtt publish failed: {}", err);
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!(
+                    "Lost connection to mqtt broker {}:{}. Error: {}.",
+                    settings.host, settings.port, err
+                );
+                // the next poll reconnects
+                tokio::time::sleep(reconnect).await;
+            }
+        }
+    }
+}
+
+async fn publish_events(
+    client: AsyncClient,
+    prefix: String,
+    mut events: broadcast::Receiver<TkConnectionEvent>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        for message in event_messages(&prefix, &event) {
+            if let Err(err) = client
+                .publish(
+                    message.topic,
+                    QoS::AtLeastOnce,
+                    message.retain,
+                    message.payload,
+                )
+                .await
+            {
+                debug!("mqtt publish failed: {}", err);
+            }
+        }
+    }
+}
+
+/// `<prefix>/cmd/<function>` -> `<function>`
+fn command_function<'a>(prefix: &str, topic: &'a str) -> Option<&'a str> {
+    let function = topic.strip_prefix(prefix)?.strip_prefix("/cmd/")?;
+    if function.is_empty() || function.contains('/') {
+        return None;
+    }
+    Some(function)
+}
+
+/// The payload is a json string array with the arguments, like in the http api
+fn handle_command(mut api: TkApi, function: &str, payload: &str) -> Value {
+    let result = serde_json::from_str::<Vec<String>>(payload)
+        .map_err(|err| format!("Expected json string array: {}", err))
+        .and_then(|args| call_api(&mut api, function, &args, &MQTT_COMMANDS));
+    match result {
+        Ok(value) => value,
+        Err(err) => json!({ "error": err }),
+    }
+}
+
+/// Device topics use the device name, with the mqtt wildcards and separators replaced
+fn device_topic(prefix: &str, device: &str, name: &str) -> String {
+    let device = device.replace(['/', '+', '#'], "_");
+    format!("{}/device/{}/{}", prefix, device, name)
+}
+
+fn event_messages(prefix: &str, event: &TkConnectionEvent) -> Vec<TkMqttMessage> {
+    let battery = |device: &str, level: f64| {
+        TkMqttMessage::new(
+            device_topic(prefix, device, "battery"),
+            ((level * 100.0).round() as i32).to_string(),
+            true,
+        )
+    };
+    match event {
+        TkConnectionEvent::DeviceAdded(device, level) => {
+            let mut messages = vec![TkMqttMessage::new(
+                device_topic(prefix, device.name(), "connected"),
+                String::from("true"),
+                true,
+            )];
+            messages.extend(level.map(|x| battery(device.name(), x)));
+            messages
+        }
+        TkConnectionEvent::DeviceRemoved(device) => vec![TkMqttMessage::new(
+            device_topic(prefix, device.name(), "connected"),
+            String::from("false"),
+            true,
+        )],
+        TkConnectionEvent::BatteryLevel(device, level) => level
+            .map(|x| battery(device.name(), x))
+            .into_iter()
+            .collect(),
+        TkConnectionEvent::BatteryLow(device, level) => vec![TkMqttMessage::new(
+            device_topic(prefix, device.name(), "battery_low"),
+            ((level * 100.0).round() as i32).to_string(),
+            false,
+        )],
+        TkConnectionEvent::ActionStarted(task, actuators, body_parts, handle) => {
+            vec![TkMqttMessage::new(
+                format!("{}/task/started", prefix),
+                json!({
+                    "handle": handle,
+                    "task": task.to_string(),
+                    "actuators": actuators.iter().map(|x| x.identifier()).collect::<Vec<&str>>(),
+                    "body_parts": body_parts,
+                })
+                .to_string(),
+                false,
+            )]
+        }
+        TkConnectionEvent::ActionDone(task, duration, handle) => vec![TkMqttMessage::new(
+            format!("{}/task/done", prefix),
+            json!({
+                "handle": handle,
+                "task": task.to_string(),
+                "duration_ms": duration.as_millis() as u64,
+            })
+            .to_string(),
+            false,
+        )],
+        TkConnectionEvent::ActionError(actuator, err) => vec![TkMqttMessage::new(
+            format!("{}/task/error", prefix),
+            json!({
+                "actuator": actuator.identifier(),
+                "error": err,
+            })
+            .to_string(),
+            false,
+        )],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        api::ApiCaller,
+        connection::Task,
+        fake_connection::{await_actuators, connect_fakes, connected_fakes},
+        settings::TkSettings,
+    };
+    use bp_fakes::*;
+    use bp_scheduler::speed::Speed;
+    use buttplug::core::message::ActuatorType;
+
+    #[test]
+    fn command_topics() {
+        assert_eq!(
+            command_function("telekinesis", "telekinesis/cmd/control"),
+            Some("control")
+        );
+        assert_eq!(command_function("telekinesis", "telekinesis/cmd/"), None);
+        assert_eq!(command_function("telekinesis", "telekinesis/cmd/a/b"), None);
+        assert_eq!(command_function("telekinesis", "other/cmd/control"), None);
+    }
+
+    #[test]
+    fn device_and_battery_events_are_published() {
+        let (mut tk, _) = connect_fakes(
+            vec![scalar(1, "vib/1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+        let mut events = tk.subscribe_events();
+        await_actuators(&mut tk, 1);
+
+        let connected = TkMqttMessage::new(
+            String::from("tk/device/vib_1/connected"),
+            String::from("true"),
+            true,
+        );
+        let started = Instant::now();
+        let mut messages = vec![];
+        while !messages.contains(&connected) && started.elapsed() < Duration::from_secs(5) {
+            match events.try_recv() {
+                Ok(event) => messages.extend(event_messages("tk", &event)),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        assert!(messages.contains(&connected));
+    }
+
+    #[test]
+    fn task_events_are_published() {
+        let messages = event_messages(
+            "tk",
+            &TkConnectionEvent::ActionDone(
+                Task::Scalar(Speed::new(50)),
+                Duration::from_millis(1500),
+                7,
+            ),
+        );
+        assert_eq!(messages[0].topic, "tk/task/done");
+        let payload: Value = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!(payload["handle"], 7);
+        assert_eq!(payload["duration_ms"], 1500);
+    }
+
+    #[test]
+    fn commands_use_the_api() {
+        let (tk, _) = connected_fakes(
+            vec![scalar(1, "vib1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+        let state = Arc::new(Mutex::new(Some(tk)));
+        let api = || TkApi {
+            state: state.clone(),
+            caller: ApiCaller::Integration,
+        };
+
+        assert_eq!(
+            handle_command(api(), "qry_lst", r#"["devices"]"#),
+            json!(["vib1 (Vibrate)"])
+        );
+        assert!(handle_command(api(), "qry_lst", "devices")["error"].is_string());
+        assert!(handle_command(api(), "cmd", r#"["diagnostics.export"]"#)["error"].is_string());
+        assert!(handle_command(
+            api(),
+            "cmd_2",
+            r#"["device.settings.events", "vib1 (Vibrate)", "x"]"#
+        )["error"]
+            .is_string());
+    }
+}
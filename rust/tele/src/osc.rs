@@ -0,0 +1,604 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, sync::broadcast};
+use tracing::{debug, error, info};
+
+use buttplug::core::message::ActuatorType;
+
+use bp_scheduler::speed::Speed;
+
+use crate::{
+    api::Api, connection::TkConnectionEvent, input::matches_wildcard, settings::TkEventAction,
+    telekinesis::ERROR_HANDLE, trace::TkTraceEntry, TkApi,
+};
+
+static OSC_STOP_ADDRESS: &str = "/tele/stop";
+static OSC_OUTPUT_ADDRESS: &str = "/tele/output";
+/// Interval for retrying the stops that failed because the api was busy
+static OSC_STOP_RETRY: Duration = Duration::from_millis(100);
+
+/// Controls the devices with OSC messages and optionally sends their output
+/// levels and task events to another OSC receiver
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkOscSettings {
+    pub enabled: bool,
+    pub bind_address: String,
+    pub port: u16,
+    /// Receiver of output levels and task events, i.e. `127.0.0.1:9000`
+    pub send_to: Option<String>,
+    pub rules: Vec<TkOscRule>,
+}
+
+impl Default for TkOscSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: String::from("127.0.0.1"),
+            port: 9001,
+            send_to: None,
+            rules: vec![
+                TkOscRule {
+                    address: String::from("/tele/vibrate/*"),
+                    action: TkEventAction::Vibrate,
+                    body_parts: vec![],
+                },
+                TkOscRule {
+                    address: String::from("/tele/stroke/*"),
+                    action: TkEventAction::LinearStroke,
+                    body_parts: vec![],
+                },
+            ],
+        }
+    }
+}
+
+/// Maps an OSC address (supports `*`) to an action. Without body parts,
+/// the last segment of the address is used, i.e. `clit` for `/tele/vibrate/clit`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TkOscRule {
+    pub address: String,
+    pub action: TkEventAction,
+    #[serde(default)]
+    pub body_parts: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TkOscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TkOscMessage {
+    pub address: String,
+    pub args: Vec<TkOscArg>,
+}
+
+impl TkOscMessage {
+    pub fn new(address: &str, args: Vec<TkOscArg>) -> Self {
+        TkOscMessage {
+            address: String::from(address),
+            args,
+        }
+    }
+
+    /// Speed in percent of the first numeric argument, floats are
+    /// expected from `0.0` to `1.0` and ints from `0` to `100`
+    pub fn speed(&self) -> Option<i32> {
+        self.args
+            .iter()
+            .find_map(|x| match x {
+                TkOscArg::Float(value) => Some((value * 100.0).round() as i32),
+                TkOscArg::Int(value) => Some(*value),
+                TkOscArg::Str(_) => None,
+            })
+            .map(|x| x.clamp(0, 100))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        write_str(&mut buf, &self.address);
+        let tags = self
+            .args
+            .iter()
+            .map(|x| match x {
+                TkOscArg::Int(_) => 'i',
+                TkOscArg::Float(_) => 'f',
+                TkOscArg::Str(_) => 's',
+            })
+            .collect::<String>();
+        write_str(&mut buf, &format!(",{}", tags));
+        for arg in &self.args {
+            match arg {
+                TkOscArg::Int(value) => buf.extend_from_slice(&value.to_be_bytes()),
+                TkOscArg::Float(value) => buf.extend_from_slice(&value.to_be_bytes()),
+                TkOscArg::Str(value) => write_str(&mut buf, value),
+            }
+        }
+        buf
+    }
+
+    /// Decodes a message or all messages of a bundle
+    pub fn decode_packet(buf: &[u8]) -> Result<Vec<TkOscMessage>, String> {
+        if buf.starts_with(b"#bundle\0") {
+            let mut messages = vec![];
+            let mut pos = 16; // tag and time tag
+            while pos < buf.len() {
+                let size = read_i32(buf, &mut pos)? as u32 as usize;
+                let element = pos
+                    .checked_add(size)
+                    .and_then(|end| buf.get(pos..end))
+                    .ok_or_else(|| String::from("Bundle element exceeds packet"))?;
+                messages.extend(TkOscMessage::decode_packet(element)?);
+                pos += size;
+            }
+            return Ok(messages);
+        }
+        let mut pos = 0;
+        let address = read_str(buf, &mut pos)?;
+        if !address.starts_with('/') {
+            return Err(format!("Invalid address '{}'", address));
+        }
+        let tags = if pos < buf.len() {
+            read_str(buf, &mut pos)?
+        } else {
+            String::from(",")
+        };
+        let mut args = vec![];
+        for tag in tags.chars().skip(1) {
+            args.push(match tag {
+                'i' => TkOscArg::Int(read_i32(buf, &mut pos)?),
+                'f' => TkOscArg::Float(f32::from_bits(read_i32(buf, &mut pos)? as u32)),
+                's' => TkOscArg::Str(read_str(buf, &mut pos)?),
+                'T' => TkOscArg::Int(100),
+                'F' => TkOscArg::Int(0),
+                _ => return Err(format!("Unsupported argument type '{}'", tag)),
+            });
+        }
+        Ok(vec![TkOscMessage { address, args }])
+    }
+}
+
+/// Strings are null terminated and padded to 4 bytes
+fn write_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+fn read_str(buf: &[u8], pos: &mut usize) -> Result<String, String> {
+    let rest = buf.get(*pos..).unwrap_or_default();
+    let len = rest
+        .iter()
+        .position(|x| *x == 0)
+        .ok_or_else(|| String::from("Unterminated string"))?;
+    let value = String::from_utf8_lossy(&rest[..len]).into_owned();
+    *pos += (len + 4) / 4 * 4;
+    Ok(value)
+}
+
+fn read_i32(buf: &[u8], pos: &mut usize) -> Result<i32, String> {
+    let bytes = buf
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| String::from("Missing argument"))?;
+    *pos += 4;
+    Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub async fn run_osc_listener(settings: TkOscSettings, mut api: TkApi) {
+    let address = format!("{}:{}", settings.bind_address, settings.port);
+    let socket = match UdpSocket::bind(&address).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Could not listen for osc on {}. Error: {}.", address, err);
+            return;
+        }
+    };
+    info!(address, "osc listening");
+    let mut handles = HashMap::new();
+    let mut stops = TkOscStops::default();
+    let mut retry = tokio::time::interval(OSC_STOP_RETRY);
+    let mut buf = vec![0; 65536];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = retry.tick(), if !stops.is_empty() => {
+                stops.retry(&mut api);
+                continue;
+            }
+        };
+        let len = match received {
+            Ok((len, _)) => len,
+            Err(err) => {
+                debug!("osc receive failed: {}", err);
+                continue;
+            }
+        };
+        match TkOscMessage::decode_packet(&buf[..len]) {
+            Ok(messages) => {
+                for message in messages {
+                    handle_osc_message(
+                        &mut api,
+                        &settings.rules,
+                        &mut handles,
+                        &mut stops,
+                        &message,
+                    );
+                }
+            }
+            Err(err) => debug!("invalid osc packet: {}", err),
+        }
+    }
+}
+
+/// Stops that failed because the api was busy, they are retried until they
+/// succeeded instead of leaving the devices running
+#[derive(Debug, Default)]
+struct TkOscStops {
+    all: bool,
+    handles: Vec<i32>,
+}
+
+impl TkOscStops {
+    fn is_empty(&self) -> bool {
+        !self.all && self.handles.is_empty()
+    }
+
+    fn stop_all(&mut self, api: &mut TkApi) {
+        // covers the pending stops of single tasks
+        self.handles.clear();
+        self.all = true;
+        self.retry(api);
+    }
+
+    fn stop(&mut self, api: &mut TkApi, handle: i32) {
+        self.handles.push(handle);
+        self.retry(api);
+    }
+
+    fn retry(&mut self, api: &mut TkApi) {
+        if self.all {
+            self.all = !api.try_exec(|tk| tk.stop_all(), false);
+            if self.all {
+                return;
+            }
+            self.handles.clear();
+        }
+        self.handles
+            .retain(|handle| !api.try_exec(|tk| tk.stop(*handle), false));
+        if !self.is_empty() {
+            debug!(stops = ?self, "osc stops pending");
+        }
+    }
+}
+
+/// Starts a task for the first message of an address, following messages change
+/// its speed and `0` stops it. Returns the handle of the running task
+fn handle_osc_message(
+    api: &mut TkApi,
+    rules: &[TkOscRule],
+    handles: &mut HashMap<String, i32>,
+    stops: &mut TkOscStops,
+    message: &TkOscMessage,
+) -> Option<i32> {
+    // pending stops go first, they must not end the tasks started by this message
+    if !stops.is_empty() {
+        stops.retry(api);
+    }
+    if message.address.eq_ignore_ascii_case(OSC_STOP_ADDRESS) {
+        handles.clear();
+        stops.stop_all(api);
+        return None;
+    }
+    let Some(rule) = rules
+        .iter()
+        .find(|x| matches_wildcard(&x.address, &message.address))
+    else {
+        debug!(address = %message.address, "no osc rule");
+        return None;
+    };
+    let speed = message.speed().unwrap_or(0);
+    if let Some(handle) = handles.get(&message.address).copied() {
+        if speed <= 0 {
+            handles.remove(&message.address);
+            stops.stop(api, handle);
+            return None;
+        }
+        // `None` if the api is busy, the task keeps running with the previous speed
+        match api.try_exec(|tk| Some(tk.update(handle, Speed::new(speed.into()))), None) {
+            Some(false) => debug!(handle, "osc task finished, restarting"),
+            _ => return Some(handle),
+        }
+    }
+    if speed <= 0 {
+        return None;
+    }
+    let body_parts = if rule.body_parts.is_empty() {
+        message
+            .address
+            .rsplit('/')
+            .take(1)
+            .map(String::from)
+            .collect()
+    } else {
+        rule.body_parts.clone()
+    };
+    let handle = api.try_exec(
+        |tk| {
+            tk.dispatch_action(
+                &rule.action,
+                Speed::new(speed.into()),
+                Duration::MAX,
+                body_parts,
+            )
+        },
+        ERROR_HANDLE,
+    );
+    if handle == ERROR_HANDLE {
+        handles.remove(&message.address);
+        return None;
+    }
+    handles.insert(message.address.clone(), handle);
+    Some(handle)
+}
+
+pub async fn run_osc_sender(
+    target: String,
+    mut api: TkApi,
+    mut outputs: broadcast::Receiver<TkTraceEntry>,
+    mut events: broadcast::Receiver<TkConnectionEvent>,
+) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Could not create osc sender. Error: {}.", err);
+            return;
+        }
+    };
+    if let Err(err) = socket.connect(&target).await {
+        error!("Could not send osc to {}. Error: {}.", target, err);
+        return;
+    }
+    info!(target, "sending osc");
+    let mut actuator_ids = HashMap::new();
+    loop {
+        let messages = tokio::select! {
+            output = outputs.recv() => match output {
+                Ok(entry) => output_messages(&mut api, &mut actuator_ids, &entry),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            event = events.recv() => match event {
+                Ok(event) => event_message(&event).into_iter().collect(),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        for message in messages {
+            if let Err(err) = socket.send(&message.encode()).await {
+                debug!("osc send failed: {}", err);
+            }
+        }
+    }
+}
+
+type TkActuatorKey = (u32, u32, bool);
+
+/// `/tele/output <actuator id> <level>` for every actuator affected by the command
+fn output_messages(
+    api: &mut TkApi,
+    actuator_ids: &mut HashMap<TkActuatorKey, String>,
+    entry: &TkTraceEntry,
+) -> Vec<TkOscMessage> {
+    let known = actuator_ids
+        .keys()
+        .any(|(device, index, linear)| entry.applies_to(*device, *index, *linear));
+    if !known {
+        // devices are only looked up once they are used
+        if let Some(ids) = api.try_exec(
+            |tk| {
+                Some(
+                    tk.status
+                        .actuators()
+                        .iter()
+                        .map(|x| {
+                            (
+                                (
+                                    x.device.index(),
+                                    x.index_in_device,
+                                    x.actuator == ActuatorType::Position,
+                                ),
+                                x.identifier().to_owned(),
+                            )
+                        })
+                        .collect::<HashMap<TkActuatorKey, String>>(),
+                )
+            },
+            None,
+        ) {
+            *actuator_ids = ids;
+        }
+    }
+    actuator_ids
+        .iter()
+        .filter(|((device, index, linear), _)| entry.applies_to(*device, *index, *linear))
+        .map(|(_, actuator_id)| {
+            TkOscMessage::new(
+                OSC_OUTPUT_ADDRESS,
+                vec![
+                    TkOscArg::Str(actuator_id.clone()),
+                    TkOscArg::Float(entry.value as f32),
+                ],
+            )
+        })
+        .collect()
+}
+
+fn event_message(event: &TkConnectionEvent) -> Option<TkOscMessage> {
+    match event {
+        TkConnectionEvent::ActionStarted(task, actuators, _, handle) => Some(TkOscMessage::new(
+            "/tele/task/started",
+            vec![
+                TkOscArg::Int(*handle),
+                TkOscArg::Str(task.to_string()),
+                TkOscArg::Str(
+                    actuators
+                        .iter()
+                        .map(|x| x.identifier())
+                        .collect::<Vec<&str>>()
+                        .join(","),
+                ),
+            ],
+        )),
+        TkConnectionEvent::ActionDone(task, _, handle) => Some(TkOscMessage::new(
+            "/tele/task/done",
+            vec![TkOscArg::Int(*handle), TkOscArg::Str(task.to_string())],
+        )),
+        TkConnectionEvent::ActionError(actuator, err) => Some(TkOscMessage::new(
+            "/tele/task/error",
+            vec![
+                TkOscArg::Str(actuator.identifier().to_owned()),
+                TkOscArg::Str(err.clone()),
+            ],
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::{api::ApiCaller, fake_connection::connected_fakes, settings::TkSettings};
+    use bp_fakes::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let message = TkOscMessage::new(
+            "/tele/vibrate/clit",
+            vec![
+                TkOscArg::Float(0.7),
+                TkOscArg::Int(3),
+                TkOscArg::Str(String::from("abc")),
+            ],
+        );
+        let encoded = message.encode();
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(
+            TkOscMessage::decode_packet(&encoded).unwrap(),
+            vec![message]
+        );
+    }
+
+    #[test]
+    fn decode_bundle() {
+        let first = TkOscMessage::new("/a", vec![TkOscArg::Int(1)]);
+        let second = TkOscMessage::new("/b", vec![]);
+        let mut bundle = b"#bundle\0".to_vec();
+        bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        for message in [&first, &second] {
+            let encoded = message.encode();
+            bundle.extend_from_slice(&(encoded.len() as i32).to_be_bytes());
+            bundle.extend_from_slice(&encoded);
+        }
+        assert_eq!(
+            TkOscMessage::decode_packet(&bundle).unwrap(),
+            vec![first, second]
+        );
+        assert!(TkOscMessage::decode_packet(b"no address").is_err());
+    }
+
+    #[test]
+    fn speed_of_floats_and_ints() {
+        let speed = |arg| TkOscMessage::new("/x", vec![arg]).speed();
+        assert_eq!(speed(TkOscArg::Float(0.7)), Some(70));
+        assert_eq!(speed(TkOscArg::Float(2.0)), Some(100));
+        assert_eq!(speed(TkOscArg::Int(30)), Some(30));
+        assert_eq!(speed(TkOscArg::Str(String::from("30"))), None);
+    }
+
+    #[test]
+    fn messages_start_update_and_stop_tasks() {
+        let (mut tk, call_registry) = connected_fakes(
+            vec![scalar(1, "vib1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+        tk.settings.set_enabled("vib1 (Vibrate)", true);
+        tk.settings
+            .set_events("vib1 (Vibrate)", &[String::from("clit")]);
+        let mut api = TkApi {
+            state: Arc::new(Mutex::new(Some(tk))),
+            caller: ApiCaller::Integration,
+        };
+        let rules = TkOscSettings::default().rules;
+        let mut handles = HashMap::new();
+        let mut stops = TkOscStops::default();
+        let mut send = |address: &str, value: f32| {
+            let message = TkOscMessage::new(address, vec![TkOscArg::Float(value)]);
+            handle_osc_message(&mut api, &rules, &mut handles, &mut stops, &message)
+        };
+
+        let handle = send("/tele/vibrate/clit", 0.5).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(send("/tele/vibrate/clit", 1.0), Some(handle));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(send("/tele/vibrate/clit", 0.0), None);
+        assert_eq!(send("/tele/unknown", 1.0), None);
+        thread::sleep(Duration::from_millis(200));
+
+        let calls = call_registry.get_device(1);
+        calls[0].assert_strenth(0.5);
+        calls.last().unwrap().assert_strenth(0.0);
+    }
+
+    #[test]
+    fn stops_are_retried_while_the_api_is_busy() {
+        let (mut tk, call_registry) = connected_fakes(
+            vec![scalar(1, "vib1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+        tk.settings.set_enabled("vib1 (Vibrate)", true);
+        tk.settings
+            .set_events("vib1 (Vibrate)", &[String::from("clit")]);
+        let state = Arc::new(Mutex::new(Some(tk)));
+        let mut api = TkApi {
+            state: state.clone(),
+            caller: ApiCaller::Integration,
+        };
+        let rules = TkOscSettings::default().rules;
+        let mut handles = HashMap::new();
+        let mut stops = TkOscStops::default();
+        let vibrate = TkOscMessage::new("/tele/vibrate/clit", vec![TkOscArg::Float(0.5)]);
+        let stop = TkOscMessage::new(OSC_STOP_ADDRESS, vec![]);
+
+        let handle = handle_osc_message(&mut api, &rules, &mut handles, &mut stops, &vibrate);
+        assert!(handle.is_some());
+        thread::sleep(Duration::from_millis(200));
+
+        let guard = state.lock().unwrap();
+        assert_eq!(
+            handle_osc_message(&mut api, &rules, &mut handles, &mut stops, &stop),
+            None
+        );
+        assert!(!stops.is_empty());
+        drop(guard);
+
+        stops.retry(&mut api);
+        assert!(stops.is_empty());
+        thread::sleep(Duration::from_millis(200));
+        call_registry
+            .get_device(1)
+            .last()
+            .unwrap()
+            .assert_strenth(0.0);
+    }
+}
@@ -1,8 +1,11 @@
-use std::{path::PathBuf, time::Instant, fs};
+use std::{path::PathBuf, time::{Duration, Instant}, fs};
 use anyhow::anyhow;
 use tracing::{error, debug};
 
-use funscript::FScript;
+use funscript::{FSPoint, FScript};
+use serde_json::json;
+
+use crate::pattern_format::TkPatternFormat;
 
 pub fn get_pattern_names(pattern_path: &str, vibration_patterns: bool) -> Vec<String> {
     match get_pattern_paths(pattern_path) {
@@ -22,6 +25,7 @@ struct TkPatternFile {
     path: PathBuf,
     is_vibration: bool,
     name: String,
+    format: TkPatternFormat,
 }
 
 fn get_pattern_paths(pattern_path: &str) -> Result<Vec<TkPatternFile>, anyhow::Error> {
@@ -37,21 +41,15 @@ fn get_pattern_paths(pattern_path: &str) -> Result<Vec<TkPatternFile>, anyhow::E
             .ok_or_else(|| anyhow!("No file name"))?
             .to_str()
             .ok_or_else(|| anyhow!("Invalid unicode"))?;
-        if !file_name.to_lowercase().ends_with(".funscript") {
+        let Some((format, is_vibration, name)) = TkPatternFormat::from_file_name(file_name) else {
             continue;
-        }
-
-        let is_vibration = file_name.to_lowercase().ends_with(".vibrator.funscript");
-        let removal: usize = if is_vibration {
-            file_name.len() - ".vibrator.funscript".len()
-        } else {
-            file_name.len() - ".funscript".len()
         };
 
         patterns.push(TkPatternFile {
             path: path_clone,
             is_vibration,
-            name: String::from(&file_name[0..removal]),
+            name,
+            format,
         })
     }
     Ok(patterns)
@@ -89,7 +87,422 @@ pub fn read_pattern_name(
         })
         .ok_or_else(|| anyhow!("Pattern '{}' not found", pattern_name))?;
 
-    let fs = funscript::load_funscript(pattern.path.to_str().unwrap())?;
+    let fscript = match pattern.format {
+        TkPatternFormat::Funscript => funscript::load_funscript(pattern.path.to_str().unwrap())?,
+        format => format.parse(&fs::read_to_string(&pattern.path)?)?,
+    };
     debug!("Read pattern {} in {:?}", pattern_name, now.elapsed());
-    Ok(fs)
+    Ok(fscript)
+}
+
+/// Content of a `.funscript` file with the actions of the script
+pub fn funscript_json(fscript: &FScript) -> serde_json::Value {
+    let actions = fscript
+        .actions
+        .iter()
+        .map(|x| json!({ "at": x.at, "pos": x.pos }))
+        .collect::<Vec<_>>();
+    json!({
+        "version": "1.0",
+        "inverted": false,
+        "range": 100,
+        "actions": actions
+    })
+}
+
+/// Writes `<name>.funscript` or `<name>.vibrator.funscript` into the pattern folder,
+/// so that it can be played like any other pattern
+pub fn write_pattern(
+    pattern_path: &str,
+    pattern_name: &str,
+    vibration_pattern: bool,
+    fscript: &FScript,
+) -> Result<PathBuf, anyhow::Error> {
+    let extension = if vibration_pattern {
+        ".vibrator.funscript"
+    } else {
+        ".funscript"
+    };
+    let path = [pattern_path, &format!("{}{}", pattern_name, extension)]
+        .iter()
+        .collect::<PathBuf>();
+    fs::create_dir_all(pattern_path)?;
+    fs::write(&path, funscript_json(fscript).to_string())?;
+    debug!("Wrote pattern {}", path.display());
+    Ok(path)
+}
+
+/// Upper limit for `loops`, more repetitions are played with `loops=inf`
+static MAX_LOOPS: u32 = 1000;
+
+/// Playback options that may follow the pattern name, i.e.
+/// `Sine?start=2&end=10&loops=3&rate=1.5&pingpong` (times in seconds)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TkPatternPlayback {
+    pub start_ms: u64,
+    pub end_ms: Option<u64>,
+    /// `None` repeats the pattern until the task ends
+    pub loops: Option<u32>,
+    /// Playback rate, independent of the intensity
+    pub rate: f64,
+    /// Plays the pattern forward and then backwards
+    pub ping_pong: bool,
+}
+
+impl Default for TkPatternPlayback {
+    fn default() -> Self {
+        Self {
+            start_ms: 0,
+            end_ms: None,
+            loops: None,
+            rate: 1.0,
+            ping_pong: false,
+        }
+    }
+}
+
+impl TkPatternPlayback {
+    /// Splits `input` into the pattern name and its playback options
+    pub fn parse(input: &str) -> Result<(&str, TkPatternPlayback), String> {
+        let Some((name, options)) = input.split_once('?') else {
+            return Ok((input, TkPatternPlayback::default()));
+        };
+        let mut playback = TkPatternPlayback::default();
+        let seconds = |value: &str| -> Result<u64, String> {
+            match value.parse::<f64>() {
+                Ok(secs) if secs >= 0.0 => Ok((secs * 1000.0) as u64),
+                _ => Err(format!("Invalid time '{}' in '{}'", value, input)),
+            }
+        };
+        for option in options.split('&').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            match key.to_lowercase().as_str() {
+                "start" => playback.start_ms = seconds(value)?,
+                "end" => playback.end_ms = Some(seconds(value)?),
+                "loops" if value.eq_ignore_ascii_case("inf") => playback.loops = None,
+                "loops" => {
+                    playback.loops = match value.parse::<u32>() {
+                        Ok(loops) if loops > 0 => Some(loops.min(MAX_LOOPS)),
+                        _ => return Err(format!("Invalid loops '{}' in '{}'", value, input)),
+                    }
+                }
+                "rate" => {
+                    playback.rate = match value.parse::<f64>() {
+                        Ok(rate) if rate > 0.0 => rate.clamp(0.1, 10.0),
+                        _ => return Err(format!("Invalid rate '{}' in '{}'", value, input)),
+                    }
+                }
+                "pingpong" => playback.ping_pong = value.is_empty() || value == "true",
+                _ => return Err(format!("Unknown option '{}' in '{}'", key, input)),
+            }
+        }
+        Ok((name.trim(), playback))
+    }
+
+    /// The part of the pattern that is played, with rate, ping-pong and loops
+    /// applied. `None` if the range does not contain any actions
+    pub fn apply(&self, script: &FScript) -> Option<FScript> {
+        let end = self
+            .end_ms
+            .unwrap_or(u64::MAX)
+            .min(script_length(script));
+        if end <= self.start_ms || script.actions.is_empty() {
+            return None;
+        }
+        let (start, end) = (self.start_ms as i32, end as i32);
+        let mut cycle = vec![FSPoint {
+            pos: interpolate(&script.actions, start),
+            at: 0,
+        }];
+        cycle.extend(
+            script
+                .actions
+                .iter()
+                .filter(|x| x.at > start && x.at < end)
+                .map(|x| FSPoint {
+                    pos: x.pos,
+                    at: x.at - start,
+                }),
+        );
+        cycle.push(FSPoint {
+            pos: interpolate(&script.actions, end),
+            at: end - start,
+        });
+        for point in cycle.iter_mut() {
+            point.at = (point.at as f64 / self.rate).round() as i32;
+        }
+        if self.ping_pong {
+            let length = cycle.last().map(|x| x.at).unwrap_or(0);
+            let back = cycle
+                .iter()
+                .rev()
+                .skip(1)
+                .map(|x| FSPoint {
+                    pos: x.pos,
+                    at: 2 * length - x.at,
+                })
+                .collect::<Vec<FSPoint>>();
+            cycle.extend(back);
+        }
+        let length = cycle.last().map(|x| x.at).unwrap_or(0);
+        let mut actions = cycle.clone();
+        for n in 1..self.loops.unwrap_or(1).min(MAX_LOOPS) {
+            // scripts that would end after i32::MAX ms are cut
+            let Some(offset) = length.checked_mul(n as i32) else {
+                break;
+            };
+            if length.checked_add(offset).is_none() {
+                break;
+            }
+            actions.extend(cycle.iter().skip(1).map(|x| FSPoint {
+                pos: x.pos,
+                at: x.at + offset,
+            }));
+        }
+        Some(FScript {
+            actions,
+            ..Default::default()
+        })
+    }
+
+    /// Finite loops end with the pattern, even if the task would run longer
+    pub fn duration(&self, applied: &FScript, requested: Duration) -> Duration {
+        match self.loops {
+            Some(_) => requested.min(Duration::from_millis(script_length(applied))),
+            None => requested,
+        }
+    }
+}
+
+/// Time of the last action in ms
+pub fn script_length(script: &FScript) -> u64 {
+    script.actions.last().map(|x| x.at.max(0) as u64).unwrap_or(0)
+}
+
+/// The script as it is played by a task that runs for `duration`, a single
+/// repetition if the duration is unlimited
+pub fn repeat_script(script: &FScript, duration: Duration) -> FScript {
+    let length = script_length(script).min(i32::MAX as u64) as i32;
+    if duration == Duration::MAX || length == 0 {
+        return script.clone();
+    }
+    let end = duration.as_millis().min(i32::MAX as u128) as i32;
+    let mut actions: Vec<FSPoint> = vec![];
+    for offset in (0..).map(|n| n * length).take_while(|x| *x < end) {
+        for point in &script.actions {
+            let mut at = point.at + offset;
+            if let Some(last) = actions.last() {
+                // the start of a repetition follows right after the end of the previous one
+                if at <= last.at {
+                    if last.pos == point.pos {
+                        continue;
+                    }
+                    at = last.at + 1;
+                }
+            }
+            if at < end {
+                actions.push(FSPoint { pos: point.pos, at });
+            }
+        }
+    }
+    let within = end - ((end - 1) / length) * length;
+    actions.push(FSPoint {
+        pos: interpolate(&script.actions, within),
+        at: end,
+    });
+    FScript {
+        actions,
+        ..Default::default()
+    }
+}
+
+/// Position at `at` ms, interpolated between the surrounding actions
+pub fn interpolate(actions: &[FSPoint], at: i32) -> i32 {
+    match actions.iter().position(|x| x.at >= at) {
+        Some(0) => actions[0].pos,
+        Some(i) => {
+            let (a, b) = (&actions[i - 1], &actions[i]);
+            let t = (at - a.at) as f64 / (b.at - a.at).max(1) as f64;
+            (a.pos as f64 + (b.pos - a.pos) as f64 * t).round() as i32
+        }
+        None => actions.last().map(|x| x.pos).unwrap_or(0),
+    }
+}
+
+/// Reads a pattern with optional playback options (see `TkPatternPlayback`) and
+/// returns it with the duration the task should run
+pub fn read_pattern_playback(
+    pattern_path: &str,
+    input: &str,
+    vibration_pattern: bool,
+    requested: Duration,
+) -> Option<(FScript, Duration)> {
+    let (pattern_name, playback) = match TkPatternPlayback::parse(input) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            error!("{}", err);
+            return None;
+        }
+    };
+    let fscript = read_pattern(pattern_path, pattern_name, vibration_pattern)?;
+    if playback == TkPatternPlayback::default() {
+        return Some((fscript, requested));
+    }
+    let Some(applied) = playback.apply(&fscript) else {
+        error!("Pattern '{}' has no actions in the selected range", input);
+        return None;
+    };
+    let duration = playback.duration(&applied, requested);
+    Some((applied, duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(points: &[(i32, i32)]) -> FScript {
+        FScript {
+            actions: points
+                .iter()
+                .map(|(at, pos)| FSPoint { pos: *pos, at: *at })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn points(script: &FScript) -> Vec<(i32, i32)> {
+        script.actions.iter().map(|x| (x.at, x.pos)).collect()
+    }
+
+    #[test]
+    fn written_patterns_are_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        write_pattern(path, "generated", true, &script(&[(0, 0), (100, 50)])).unwrap();
+        assert_eq!(get_pattern_names(path, true), vec!["generated"]);
+        assert_eq!(
+            points(&read_pattern_name(path, "generated", true).unwrap()),
+            vec![(0, 0), (100, 50)]
+        );
+    }
+
+    #[test]
+    fn other_formats_are_listed_with_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("Wave.vibrator.csv"), "0,0\n500,100").unwrap();
+        fs::write(dir.path().join("Pulse.lovense"), "V:1;F:v;S:100#20,0").unwrap();
+        fs::write(dir.path().join("Stroke.json"), "[[0, 0], [400, 100]]").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let mut vibrator = get_pattern_names(path, true);
+        vibrator.sort();
+        assert_eq!(vibrator, vec!["Pulse.lovense", "Wave.csv"]);
+        assert_eq!(get_pattern_names(path, false), vec!["Stroke.json"]);
+        assert_eq!(
+            points(&read_pattern_name(path, "wave.csv", true).unwrap()),
+            vec![(0, 0), (500, 100)]
+        );
+        assert_eq!(
+            points(&read_pattern_name(path, "Stroke.json", false).unwrap()),
+            vec![(0, 0), (400, 100)]
+        );
+    }
+
+    #[test]
+    fn repeat_until_duration() {
+        let sine = script(&[(0, 0), (1000, 100)]);
+        assert_eq!(
+            points(&repeat_script(&sine, Duration::from_millis(2500))),
+            vec![(0, 0), (1000, 100), (1001, 0), (2000, 100), (2001, 0), (2500, 50)]
+        );
+        assert_eq!(points(&repeat_script(&sine, Duration::MAX)), points(&sine));
+    }
+
+    #[test]
+    fn parse_playback_options() {
+        assert_eq!(
+            TkPatternPlayback::parse("Sine"),
+            Ok(("Sine", TkPatternPlayback::default()))
+        );
+        assert_eq!(
+            TkPatternPlayback::parse("Sine?start=1.5&end=3&loops=2&rate=2&pingpong"),
+            Ok((
+                "Sine",
+                TkPatternPlayback {
+                    start_ms: 1500,
+                    end_ms: Some(3000),
+                    loops: Some(2),
+                    rate: 2.0,
+                    ping_pong: true,
+                }
+            ))
+        );
+        assert!(TkPatternPlayback::parse("Sine?rate=0").is_err());
+        assert!(TkPatternPlayback::parse("Sine?loops=0").is_err());
+        assert_eq!(
+            TkPatternPlayback::parse("Sine?loops=4294967295")
+                .unwrap()
+                .1
+                .loops,
+            Some(MAX_LOOPS)
+        );
+        assert!(TkPatternPlayback::parse("Sine?speed=2").is_err());
+    }
+
+    #[test]
+    fn range_and_rate() {
+        let playback = TkPatternPlayback {
+            start_ms: 500,
+            end_ms: Some(1500),
+            rate: 2.0,
+            ..Default::default()
+        };
+        let applied = playback
+            .apply(&script(&[(0, 0), (1000, 100), (2000, 0)]))
+            .unwrap();
+        assert_eq!(points(&applied), vec![(0, 50), (250, 100), (500, 50)]);
+        assert_eq!(
+            playback.duration(&applied, Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn ping_pong_loops() {
+        let playback = TkPatternPlayback {
+            loops: Some(2),
+            ping_pong: true,
+            ..Default::default()
+        };
+        let applied = playback.apply(&script(&[(0, 0), (1000, 100)])).unwrap();
+        assert_eq!(
+            points(&applied),
+            vec![(0, 0), (1000, 100), (2000, 0), (3000, 100), (4000, 0)]
+        );
+        assert_eq!(
+            playback.duration(&applied, Duration::MAX),
+            Duration::from_secs(4)
+        );
+        assert!(TkPatternPlayback {
+            start_ms: 2000,
+            ..Default::default()
+        }
+        .apply(&script(&[(0, 0), (1000, 100)]))
+        .is_none());
+    }
+
+    #[test]
+    fn long_loops_do_not_overflow() {
+        let playback = TkPatternPlayback {
+            loops: Some(u32::MAX),
+            rate: 0.1,
+            ..Default::default()
+        };
+        let applied = playback
+            .apply(&script(&[(0, 0), (i32::MAX / 4, 100)]))
+            .unwrap();
+        assert!(applied.actions.windows(2).all(|x| x[0].at <= x[1].at));
+        assert!(applied.actions.len() < 2 * MAX_LOOPS as usize);
+    }
 }
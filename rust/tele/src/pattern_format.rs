@@ -0,0 +1,288 @@
+use std::fmt::{self, Display};
+
+use anyhow::anyhow;
+use funscript::{FSPoint, FScript};
+use serde_json::{Map, Value};
+
+/// File formats that can be used as patterns, all of them are converted into funscripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TkPatternFormat {
+    /// `<name>.funscript` or `<name>.vibrator.funscript`
+    Funscript,
+    /// `time_ms,value` lines in `<name>.csv` or `<name>.vibrator.csv`
+    Csv,
+    /// Lovense pattern string `V:1;F:v;S:100#` followed by levels from 0 to 20, always vibration
+    Lovense,
+    /// XToys or buttplug style json points in `<name>.json` or `<name>.vibrator.json`
+    Json,
+}
+
+static FORMATS: [(&str, TkPatternFormat); 4] = [
+    (".funscript", TkPatternFormat::Funscript),
+    (".csv", TkPatternFormat::Csv),
+    (".lovense", TkPatternFormat::Lovense),
+    (".json", TkPatternFormat::Json),
+];
+
+/// Time between lovense levels if the pattern does not specify it
+static LOVENSE_DEFAULT_STEP_MS: u32 = 100;
+static LOVENSE_MAX_LEVEL: f64 = 20.0;
+
+/// Largest value of funscript positions, XToys points and csv `value` columns
+static PERCENT_MAX: f64 = 100.0;
+/// Largest value of buttplug style points and csv `scalar` columns
+static SCALAR_MAX: f64 = 1.0;
+
+impl Display for TkPatternFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TkPatternFormat::Funscript => write!(f, "funscript"),
+            TkPatternFormat::Csv => write!(f, "csv"),
+            TkPatternFormat::Lovense => write!(f, "lovense"),
+            TkPatternFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl TkPatternFormat {
+    /// Format, whether it is a vibration pattern and the pattern name of a file.
+    /// Funscripts keep their plain name, other formats are listed with their type,
+    /// i.e. `Wave.vibrator.csv` is the vibration pattern `Wave.csv`
+    pub fn from_file_name(file_name: &str) -> Option<(TkPatternFormat, bool, String)> {
+        let lower = file_name.to_lowercase();
+        let (extension, format) = FORMATS.iter().find(|(x, _)| lower.ends_with(x))?;
+        let stem = &file_name[0..file_name.len() - extension.len()];
+        let is_vibration = stem.to_lowercase().ends_with(".vibrator");
+        let stem = if is_vibration {
+            &stem[0..stem.len() - ".vibrator".len()]
+        } else {
+            stem
+        };
+        let name = match format {
+            TkPatternFormat::Funscript => String::from(stem),
+            _ => format!("{}.{}", stem, format),
+        };
+        let is_vibration = is_vibration || *format == TkPatternFormat::Lovense;
+        Some((*format, is_vibration, name))
+    }
+
+    /// Converts the file content, funscripts are read by the funscript crate instead
+    pub fn parse(&self, content: &str) -> Result<FScript, anyhow::Error> {
+        match self {
+            TkPatternFormat::Funscript => Err(anyhow!("Funscripts are loaded from file")),
+            TkPatternFormat::Csv => read_csv_pattern(content),
+            TkPatternFormat::Lovense => read_lovense_pattern(content),
+            TkPatternFormat::Json => read_json_pattern(content),
+        }
+    }
+}
+
+/// `time_ms,value` with values from 0 to 100 and `#` comments. The header
+/// `time_ms,scalar` marks values from 0.0 to 1.0
+fn read_csv_pattern(content: &str) -> Result<FScript, anyhow::Error> {
+    let mut points = vec![];
+    let mut max = PERCENT_MAX;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split(',').map(|x| x.trim().parse::<f64>());
+        match (columns.next(), columns.next()) {
+            (Some(Ok(time)), Some(Ok(value))) => points.push((time, value / max * 100.0)),
+            _ if points.is_empty() => {
+                let value_column = line.split(',').nth(1).unwrap_or_default().trim();
+                if value_column.eq_ignore_ascii_case("scalar") {
+                    max = SCALAR_MAX;
+                }
+            }
+            _ => return Err(anyhow!("Invalid line {}: '{}'", i + 1, line)),
+        }
+    }
+    to_fscript(points)
+}
+
+/// `V:1;F:v;S:100#10,12,20,...`, the interval `S` in ms is optional
+fn read_lovense_pattern(content: &str) -> Result<FScript, anyhow::Error> {
+    let (header, levels) = content.split_once('#').unwrap_or(("", content));
+    let mut step_ms = LOVENSE_DEFAULT_STEP_MS;
+    for field in header.split(';').map(|x| x.trim()) {
+        if let Some(step) = field.strip_prefix("S:") {
+            step_ms = step
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or_else(|| anyhow!("Invalid interval '{}'", field))?;
+        }
+    }
+    let mut points = vec![];
+    for level in levels
+        .split([',', ';', '\n', '\r'])
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+    {
+        // steps of patterns with several features look like `10:5`, the first one is used
+        let first = level.split(':').next().unwrap_or(level);
+        let value = first
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Invalid level '{}'", level))?;
+        let at = points.len() as u32 * step_ms;
+        points.push((at as f64, value / LOVENSE_MAX_LEVEL * 100.0));
+    }
+    // the last level is held for one interval
+    if let Some((_, last)) = points.last().cloned() {
+        points.push(((points.len() as u32 * step_ms) as f64, last));
+    }
+    to_fscript(points)
+}
+
+/// A list of XToys style `[time_ms, value]` pairs or `{ "at": .., "pos": .. }` objects
+/// with values from 0 to 100, or buttplug style `{ "time": .., "value": .. }` objects
+/// (also `t` and `v`) with values from 0.0 to 1.0. The list is either at the top level
+/// or in `actions`, `pattern`, `data` or `points`, next to it `"max"` can set the
+/// largest value for all points
+fn read_json_pattern(content: &str) -> Result<FScript, anyhow::Error> {
+    let json: Value = serde_json::from_str(content)?;
+    let max = json
+        .get("max")
+        .map(|x| {
+            x.as_f64()
+                .filter(|x| *x > 0.0)
+                .ok_or_else(|| anyhow!("Invalid max {}", x))
+        })
+        .transpose()?;
+    let list = match &json {
+        Value::Object(object) => ["actions", "pattern", "data", "points"]
+            .iter()
+            .find_map(|x| object.get(*x))
+            .ok_or_else(|| anyhow!("No pattern found"))?,
+        _ => &json,
+    };
+    let list = list
+        .as_array()
+        .ok_or_else(|| anyhow!("Pattern is not a list"))?;
+    let field = |object: &Map<String, Value>, names: &[&str]| {
+        names
+            .iter()
+            .find_map(|x| object.get(*x))
+            .and_then(|x| x.as_f64())
+    };
+    let mut points = vec![];
+    for point in list {
+        let parsed = match point {
+            Value::Array(pair) if pair.len() >= 2 => pair[0]
+                .as_f64()
+                .zip(pair[1].as_f64().map(|x| (x, PERCENT_MAX))),
+            Value::Object(object) => field(object, &["at", "time", "t"]).zip(
+                field(object, &["pos"])
+                    .map(|x| (x, PERCENT_MAX))
+                    .or_else(|| field(object, &["value", "v"]).map(|x| (x, SCALAR_MAX))),
+            ),
+            _ => None,
+        };
+        let (time, (value, point_max)) =
+            parsed.ok_or_else(|| anyhow!("Invalid point {}", point))?;
+        points.push((time, value / max.unwrap_or(point_max) * 100.0));
+    }
+    to_fscript(points)
+}
+
+/// Points with values from 0 to 100, values outside are clamped
+fn to_fscript(mut points: Vec<(f64, f64)>) -> Result<FScript, anyhow::Error> {
+    if points.is_empty() {
+        return Err(anyhow!("Pattern is empty"));
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(FScript {
+        actions: points
+            .into_iter()
+            .map(|(time, value)| FSPoint {
+                pos: value.round().clamp(0.0, 100.0) as i32,
+                at: time.max(0.0).round() as i32,
+            })
+            .collect(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(script: &FScript) -> Vec<(i32, i32)> {
+        script.actions.iter().map(|x| (x.at, x.pos)).collect()
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(
+            TkPatternFormat::from_file_name("Wave.Vibrator.funscript"),
+            Some((TkPatternFormat::Funscript, true, String::from("Wave")))
+        );
+        assert_eq!(
+            TkPatternFormat::from_file_name("Wave.csv"),
+            Some((TkPatternFormat::Csv, false, String::from("Wave.csv")))
+        );
+        assert_eq!(
+            TkPatternFormat::from_file_name("Wave.vibrator.json"),
+            Some((TkPatternFormat::Json, true, String::from("Wave.json")))
+        );
+        assert_eq!(
+            TkPatternFormat::from_file_name("Pulse.lovense"),
+            Some((
+                TkPatternFormat::Lovense,
+                true,
+                String::from("Pulse.lovense")
+            ))
+        );
+        assert_eq!(TkPatternFormat::from_file_name("readme.txt"), None);
+    }
+
+    #[test]
+    fn csv_patterns() {
+        let script = read_csv_pattern("time_ms,value\n0,0\n# peak\n500, 100\n1000,20\n").unwrap();
+        assert_eq!(points(&script), vec![(0, 0), (500, 100), (1000, 20)]);
+        let script = read_csv_pattern("time_ms,scalar\n0,0.5\n250,1.0").unwrap();
+        assert_eq!(points(&script), vec![(0, 50), (250, 100)]);
+        // values are never guessed, a quiet pattern stays quiet
+        let script = read_csv_pattern("0,1\n250,0").unwrap();
+        assert_eq!(points(&script), vec![(0, 1), (250, 0)]);
+        assert!(read_csv_pattern("0,0\n100,x").is_err());
+        assert!(read_csv_pattern("time_ms,value").is_err());
+    }
+
+    #[test]
+    fn lovense_patterns() {
+        let script = read_lovense_pattern("V:1;F:v;S:200#0,10,20;").unwrap();
+        assert_eq!(
+            points(&script),
+            vec![(0, 0), (200, 50), (400, 100), (600, 100)]
+        );
+        let script = read_lovense_pattern("V:1;F:vr;#4:20,10:0").unwrap();
+        assert_eq!(points(&script), vec![(0, 20), (100, 50), (200, 50)]);
+        assert!(read_lovense_pattern("V:1;F:v;S:0#1,2").is_err());
+    }
+
+    #[test]
+    fn json_patterns() {
+        let xtoys = read_json_pattern(r#"{ "pattern": [[0, 0], [1000, 100]] }"#).unwrap();
+        assert_eq!(points(&xtoys), vec![(0, 0), (1000, 100)]);
+        let buttplug =
+            read_json_pattern(r#"[{ "time": 500, "value": 1.0 }, { "time": 0, "value": 0.25 }]"#)
+                .unwrap();
+        assert_eq!(points(&buttplug), vec![(0, 25), (500, 100)]);
+        let actions = read_json_pattern(
+            r#"{ "actions": [{ "at": 0, "pos": 10 }, { "at": 100, "pos": 90 }] }"#,
+        )
+        .unwrap();
+        assert_eq!(points(&actions), vec![(0, 10), (100, 90)]);
+        let quiet = read_json_pattern(r#"{ "pattern": [[0, 1], [1000, 0]] }"#).unwrap();
+        assert_eq!(points(&quiet), vec![(0, 1), (1000, 0)]);
+        let levels = read_json_pattern(r#"{ "max": 20, "points": [[0, 10], [100, 20]] }"#).unwrap();
+        assert_eq!(points(&levels), vec![(0, 50), (100, 100)]);
+        assert!(read_json_pattern(r#"{ "max": 0, "points": [[0, 10]] }"#).is_err());
+        assert!(read_json_pattern(r#"{ "name": "empty" }"#).is_err());
+        assert!(read_json_pattern(r#"[{ "at": 0 }]"#).is_err());
+    }
+}
@@ -0,0 +1,704 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use buttplug::core::message::ActuatorType;
+use funscript::FScript;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info};
+
+use bp_scheduler::speed::Speed;
+
+use crate::{
+    api::Api,
+    connection::Task,
+    input::DeviceCommand,
+    pattern::{read_pattern_name, script_length, TkPatternPlayback},
+    telekinesis::Telekinesis,
+    TkApi,
+};
+
+/// Plays funscripts in sync with the playback position of an external media player
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkSyncSettings {
+    pub enabled: bool,
+    pub source: TkSyncSource,
+    pub poll_interval_ms: u64,
+    /// Playback is restarted at the player position once it is off by more than this
+    pub max_drift_ms: u64,
+    /// Added to the player position, positive values play the script earlier
+    pub offset_ms: i64,
+}
+
+impl Default for TkSyncSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            source: TkSyncSource::MpcHc(String::from("http://127.0.0.1:13579")),
+            poll_interval_ms: 100,
+            max_drift_ms: 300,
+            offset_ms: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TkSyncSource {
+    /// Web interface of MPC-HC/MPC-BE, i.e. `http://127.0.0.1:13579`
+    MpcHc(String),
+    /// Web interface of VLC, i.e. `http://127.0.0.1:8080`
+    Vlc { url: String, password: String },
+    /// Websocket that sends the playback position as
+    /// `{ "position_ms": 1234, "playing": true }`
+    WebSocket(String),
+}
+
+/// Playback position of the external player
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TkPlayerClock {
+    pub position_ms: u64,
+    pub playing: bool,
+}
+
+/// Funscripts that follow the player, one for strokers and/or one for vibrators
+#[derive(Debug, Clone)]
+pub struct TkSyncTarget {
+    pub name: String,
+    pub linear: Option<FScript>,
+    pub vibrator: Option<FScript>,
+}
+
+impl TkSyncTarget {
+    /// Reads `<name>.funscript` and `<name>.vibrator.funscript`, at least one has to exist
+    pub fn read(pattern_path: &str, name: &str) -> Result<TkSyncTarget, String> {
+        let linear = read_pattern_name(pattern_path, name, false).ok();
+        let vibrator = read_pattern_name(pattern_path, name, true).ok();
+        if linear.is_none() && vibrator.is_none() {
+            return Err(format!("Pattern '{}' not found", name));
+        }
+        Ok(TkSyncTarget {
+            name: name.to_owned(),
+            linear,
+            vibrator,
+        })
+    }
+}
+
+/// Shared between the api and the sync loop
+#[derive(Debug, Clone, Default)]
+pub struct TkPlayerSync {
+    target: Arc<Mutex<Option<TkSyncTarget>>>,
+    status: Arc<Mutex<String>>,
+}
+
+impl TkPlayerSync {
+    pub fn start(&self, target: TkSyncTarget) -> bool {
+        info!(name = target.name, "syncing with player");
+        match self.target.lock() {
+            Ok(mut current) => {
+                current.replace(target);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn stop(&self) -> bool {
+        match self.target.lock() {
+            Ok(mut current) => current.take().is_some(),
+            Err(_) => false,
+        }
+    }
+
+    fn target(&self) -> Option<TkSyncTarget> {
+        self.target.lock().ok()?.clone()
+    }
+
+    fn set_status(&self, status: String) {
+        if let Ok(mut current) = self.status.lock() {
+            *current = status;
+        }
+    }
+
+    /// `Stopped`, `Unavailable` (player not reachable), `Paused(<name>)` or `Playing(<name>)`
+    pub fn status(&self) -> String {
+        match self.status.lock() {
+            Ok(status) if !status.is_empty() => status.clone(),
+            _ => String::from("Stopped"),
+        }
+    }
+}
+
+/// Tasks started for the current player position
+#[derive(Debug)]
+struct TkSyncPlayback {
+    name: String,
+    handles: Vec<i32>,
+    started: Instant,
+    started_ms: u64,
+}
+
+impl TkSyncPlayback {
+    fn expected_ms(&self) -> u64 {
+        self.started_ms + self.started.elapsed().as_millis() as u64
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TkSyncAction {
+    Keep,
+    Stop,
+    Start(u64),
+}
+
+fn next_action(
+    playback: Option<&TkSyncPlayback>,
+    name: &str,
+    clock: &TkPlayerClock,
+    max_drift_ms: u64,
+) -> TkSyncAction {
+    if !clock.playing {
+        return match playback {
+            Some(_) => TkSyncAction::Stop,
+            None => TkSyncAction::Keep,
+        };
+    }
+    match playback {
+        Some(playback)
+            if playback.name == name
+                && playback.expected_ms().abs_diff(clock.position_ms) <= max_drift_ms =>
+        {
+            TkSyncAction::Keep
+        }
+        _ => TkSyncAction::Start(clock.position_ms),
+    }
+}
+
+pub async fn run_player_sync(settings: TkSyncSettings, mut api: TkApi, sync: TkPlayerSync) {
+    let interval = Duration::from_millis(settings.poll_interval_ms.max(10));
+    let mut source = TkClockSource::new(&settings.source);
+    let mut playback: Option<TkSyncPlayback> = None;
+    info!(source = ?settings.source, "player sync enabled");
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(target) = sync.target() else {
+            stop_playback(&mut api, &mut playback);
+            sync.set_status(String::from("Stopped"));
+            continue;
+        };
+        let clock = match source.poll().await {
+            Ok(clock) => clock,
+            Err(err) => {
+                debug!("player not available: {}", err);
+                stop_playback(&mut api, &mut playback);
+                sync.set_status(String::from("Unavailable"));
+                continue;
+            }
+        };
+        let position_ms = clock.position_ms.saturating_add_signed(settings.offset_ms);
+        let clock = TkPlayerClock {
+            position_ms,
+            playing: clock.playing,
+        };
+        match next_action(
+            playback.as_ref(),
+            &target.name,
+            &clock,
+            settings.max_drift_ms,
+        ) {
+            TkSyncAction::Keep => {}
+            TkSyncAction::Stop => {
+                stop_playback(&mut api, &mut playback);
+            }
+            TkSyncAction::Start(position_ms) => {
+                if !stop_playback(&mut api, &mut playback) {
+                    continue;
+                }
+                debug!(name = target.name, position_ms, "starting synced playback");
+                // retried on the next poll if the api is busy
+                playback = api
+                    .try_exec(|tk| Some(start_scripts(tk, &target, position_ms)), None)
+                    .map(|handles| TkSyncPlayback {
+                        name: target.name.clone(),
+                        handles,
+                        started: Instant::now(),
+                        started_ms: position_ms,
+                    });
+            }
+        }
+        sync.set_status(match clock.playing {
+            true => format!("Playing({})", target.name),
+            false => format!("Paused({})", target.name),
+        });
+    }
+}
+
+/// Stops and removes the playback. It is kept if the api is not available,
+/// so that the stop is retried on the next poll
+fn stop_playback(api: &mut TkApi, playback: &mut Option<TkSyncPlayback>) -> bool {
+    let Some(current) = playback.as_ref() else {
+        return true;
+    };
+    let stopped = api.try_exec(
+        |tk| {
+            for handle in &current.handles {
+                tk.stop(*handle);
+            }
+            true
+        },
+        false,
+    );
+    if stopped {
+        playback.take();
+    }
+    stopped
+}
+
+/// Plays the scripts from `position_ms` until their end
+fn start_scripts(tk: &mut Telekinesis, target: &TkSyncTarget, position_ms: u64) -> Vec<i32> {
+    let mut handles = vec![];
+    if let Some((script, duration)) = target
+        .linear
+        .as_ref()
+        .and_then(|x| script_from(x, position_ms))
+    {
+        handles.push(tk.dispatch_cmd(DeviceCommand {
+            task: Task::Linear(Speed::max(), target.name.clone()),
+            duration,
+            fscript: Some(script),
+            body_parts: vec![],
+            actuator_types: vec![ActuatorType::Position],
+        }));
+    }
+    if let Some((script, duration)) = target
+        .vibrator
+        .as_ref()
+        .and_then(|x| script_from(x, position_ms))
+    {
+        handles.push(tk.dispatch_cmd(DeviceCommand {
+            task: Task::Pattern(Speed::max(), ActuatorType::Vibrate, target.name.clone()),
+            duration,
+            fscript: Some(script),
+            body_parts: vec![],
+            actuator_types: vec![ActuatorType::Vibrate],
+        }));
+    }
+    handles
+}
+
+/// The remaining part of `script` from `position_ms` on, starting at 0 with
+/// the interpolated position. `None` if the script already ended
+fn script_from(script: &FScript, position_ms: u64) -> Option<(FScript, Duration)> {
+    let remaining = TkPatternPlayback {
+        start_ms: position_ms,
+        ..Default::default()
+    }
+    .apply(script)?;
+    let duration = Duration::from_millis(script_length(&remaining));
+    Some((remaining, duration))
+}
+
+enum TkClockSource {
+    MpcHc(TkHttpSource),
+    Vlc(TkHttpSource, String),
+    /// Last clock received over the websocket, updated by a background task
+    WebSocket(Arc<Mutex<Option<(TkPlayerClock, Instant)>>>),
+}
+
+impl TkClockSource {
+    fn new(source: &TkSyncSource) -> Self {
+        match source {
+            TkSyncSource::MpcHc(url) => TkClockSource::MpcHc(TkHttpSource::new(url)),
+            TkSyncSource::Vlc { url, password } => {
+                TkClockSource::Vlc(TkHttpSource::new(url), password.clone())
+            }
+            TkSyncSource::WebSocket(url) => {
+                let clock = Arc::new(Mutex::new(None));
+                tokio::spawn(receive_timecodes(url.clone(), clock.clone()));
+                TkClockSource::WebSocket(clock)
+            }
+        }
+    }
+
+    async fn poll(&mut self) -> Result<TkPlayerClock, String> {
+        match self {
+            TkClockSource::MpcHc(http) => {
+                parse_mpc_variables(&http.get("/variables.html", None).await?)
+            }
+            TkClockSource::Vlc(http, password) => parse_vlc_status(
+                &http
+                    .get("/requests/status.json", Some(password.as_str()))
+                    .await?,
+            ),
+            TkClockSource::WebSocket(clock) => {
+                let (clock, received) = clock
+                    .lock()
+                    .map_err(|_| String::from("Poisoned"))?
+                    .ok_or_else(|| String::from("No timecode received"))?;
+                if !clock.playing {
+                    return Ok(clock);
+                }
+                Ok(TkPlayerClock {
+                    position_ms: clock.position_ms + received.elapsed().as_millis() as u64,
+                    playing: true,
+                })
+            }
+        }
+    }
+}
+
+async fn receive_timecodes(url: String, clock: Arc<Mutex<Option<(TkPlayerClock, Instant)>>>) {
+    loop {
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((mut stream, _)) => {
+                info!(url, "connected to timecode server");
+                while let Some(Ok(message)) = stream.next().await {
+                    let Message::Text(text) = message else {
+                        continue;
+                    };
+                    match parse_timecode(&text) {
+                        Ok(timecode) => {
+                            if let Ok(mut clock) = clock.lock() {
+                                clock.replace((timecode, Instant::now()));
+                            }
+                        }
+                        Err(err) => debug!("invalid timecode '{}': {}", text, err),
+                    }
+                }
+                info!(url, "timecode server disconnected");
+            }
+            Err(err) => error!(
+                "Could not connect to timecode server {}. Error: {}.",
+                url, err
+            ),
+        }
+        if let Ok(mut clock) = clock.lock() {
+            clock.take();
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+fn parse_timecode(text: &str) -> Result<TkPlayerClock, String> {
+    let json: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    Ok(TkPlayerClock {
+        position_ms: json["position_ms"]
+            .as_f64()
+            .ok_or_else(|| String::from("position_ms missing"))? as u64,
+        playing: json["playing"].as_bool().unwrap_or(true),
+    })
+}
+
+/// `<p id="position">12345</p>` and `<p id="state">2</p>` (2 is playing)
+fn parse_mpc_variables(html: &str) -> Result<TkPlayerClock, String> {
+    let variable = |id: &str| -> Result<i64, String> {
+        let start_tag = format!("id=\"{}\">", id);
+        let start = html
+            .find(&start_tag)
+            .ok_or_else(|| format!("{} missing", id))?
+            + start_tag.len();
+        let end = html[start..]
+            .find('<')
+            .ok_or_else(|| format!("{} missing", id))?;
+        html[start..start + end]
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} is not a number", id))
+    };
+    Ok(TkPlayerClock {
+        position_ms: variable("position")?.max(0) as u64,
+        playing: variable("state")? == 2,
+    })
+}
+
+/// `time` is in whole seconds, the more precise `position` (0 - 1) is used if the length is known
+fn parse_vlc_status(json: &str) -> Result<TkPlayerClock, String> {
+    let status: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let length = status["length"].as_f64().unwrap_or(0.0);
+    let seconds = match status["position"].as_f64() {
+        Some(position) if length > 0.0 => position * length,
+        _ => status["time"]
+            .as_f64()
+            .ok_or_else(|| String::from("time missing"))?,
+    };
+    Ok(TkPlayerClock {
+        position_ms: (seconds * 1000.0).max(0.0) as u64,
+        playing: status["state"].as_str() == Some("playing"),
+    })
+}
+
+/// Http client of a player web interface, connections are kept alive between polls
+struct TkHttpSource {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl TkHttpSource {
+    fn new(base_url: &str) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(1))
+            .build()
+            .unwrap_or_default();
+        TkHttpSource {
+            client,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// The body of a successful GET, `password` is sent with an empty user name
+    async fn get(&self, path: &str, password: Option<&str>) -> Result<String, String> {
+        let mut request = self.client.get(format!("{}{}", self.base_url, path));
+        if let Some(password) = password {
+            request = request.basic_auth("", Some(password));
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Unexpected response '{}'", response.status()));
+        }
+        response.text().await.map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::ApiCaller, fake_connection::connected_fakes, settings::TkSettings};
+    use bp_fakes::*;
+    use funscript::FSPoint;
+    use futures::SinkExt;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn script(points: &[(i32, i32)]) -> FScript {
+        FScript {
+            actions: points
+                .iter()
+                .map(|(at, pos)| FSPoint { pos: *pos, at: *at })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn script_starts_at_position() {
+        let (sliced, duration) =
+            script_from(&script(&[(0, 0), (1000, 100), (2000, 0)]), 500).unwrap();
+        let points: Vec<(i32, i32)> = sliced.actions.iter().map(|x| (x.at, x.pos)).collect();
+        assert_eq!(points, vec![(0, 50), (500, 100), (1500, 0)]);
+        assert_eq!(duration, Duration::from_millis(1500));
+        assert!(script_from(&script(&[(0, 0), (1000, 100)]), 1000).is_none());
+    }
+
+    #[test]
+    fn restarts_on_seek_and_stops_on_pause() {
+        let playing = |position_ms| TkPlayerClock {
+            position_ms,
+            playing: true,
+        };
+        let playback = TkSyncPlayback {
+            name: String::from("a"),
+            handles: vec![1],
+            started: Instant::now(),
+            started_ms: 1000,
+        };
+        assert_eq!(
+            next_action(None, "a", &playing(1000), 300),
+            TkSyncAction::Start(1000)
+        );
+        assert_eq!(
+            next_action(Some(&playback), "a", &playing(1100), 300),
+            TkSyncAction::Keep
+        );
+        assert_eq!(
+            next_action(Some(&playback), "a", &playing(5000), 300),
+            TkSyncAction::Start(5000)
+        );
+        assert_eq!(
+            next_action(Some(&playback), "b", &playing(1000), 300),
+            TkSyncAction::Start(1000)
+        );
+        let paused = TkPlayerClock {
+            position_ms: 1000,
+            playing: false,
+        };
+        assert_eq!(
+            next_action(Some(&playback), "a", &paused, 300),
+            TkSyncAction::Stop
+        );
+        assert_eq!(next_action(None, "a", &paused, 300), TkSyncAction::Keep);
+    }
+
+    #[test]
+    fn stop_is_kept_while_the_api_is_busy() {
+        let (tk, _) = connected_fakes(
+            vec![scalar(1, "vib1", ActuatorType::Vibrate)],
+            TkSettings::default(),
+        );
+        let mut api = TkApi {
+            state: Arc::new(Mutex::new(Some(tk))),
+            caller: ApiCaller::Integration,
+        };
+        let mut playback = Some(TkSyncPlayback {
+            name: String::from("a"),
+            handles: vec![1],
+            started: Instant::now(),
+            started_ms: 0,
+        });
+        let state = api.state.clone();
+        let guard = state.lock().unwrap();
+        assert!(!stop_playback(&mut api, &mut playback));
+        assert!(playback.is_some());
+        drop(guard);
+        assert!(stop_playback(&mut api, &mut playback));
+        assert!(playback.is_none());
+    }
+
+    #[test]
+    fn parse_player_status() {
+        assert_eq!(
+            parse_mpc_variables(r#"<p id="state">2</p><p id="position">12345</p>"#),
+            Ok(TkPlayerClock {
+                position_ms: 12345,
+                playing: true
+            })
+        );
+        assert_eq!(
+            parse_vlc_status(r#"{"state":"paused","time":10,"length":100,"position":0.1055}"#),
+            Ok(TkPlayerClock {
+                position_ms: 10550,
+                playing: false
+            })
+        );
+        assert_eq!(
+            parse_timecode(r#"{"position_ms":500,"playing":true}"#),
+            Ok(TkPlayerClock {
+                position_ms: 500,
+                playing: true
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_mock_mpc_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            let body = r#"<p id="state">1</p><p id="position">4200</p>"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let mut source = TkClockSource::new(&TkSyncSource::MpcHc(url));
+        assert_eq!(
+            source.poll().await,
+            Ok(TkPlayerClock {
+                position_ms: 4200,
+                playing: false
+            })
+        );
+    }
+
+    /// Reads the request head and returns its header lines
+    async fn read_request(stream: &mut BufReader<TcpStream>) -> Vec<String> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 || line == "\r\n" {
+                return lines;
+            }
+            lines.push(line.trim_end().to_owned());
+        }
+    }
+
+    #[tokio::test]
+    async fn poll_mock_vlc_server_with_chunked_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // both polls have to arrive on the same connection
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            for time in [10, 11] {
+                let request = read_request(&mut stream).await;
+                assert!(request
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case("authorization: Basic OnNlY3JldA==")));
+                let body = format!(r#"{{"state":"playing","time":{}}}"#, time);
+                let (first, second) = body.split_at(5);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                    first.len(),
+                    first,
+                    second.len(),
+                    second
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let mut source = TkClockSource::new(&TkSyncSource::Vlc {
+            url,
+            password: String::from("secret"),
+        });
+        for position_ms in [10000, 11000] {
+            assert_eq!(
+                source.poll().await,
+                Ok(TkPlayerClock {
+                    position_ms,
+                    playing: true
+                })
+            );
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn poll_mock_timecode_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut websocket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            websocket
+                .send(Message::Text(String::from("not a timecode")))
+                .await
+                .unwrap();
+            websocket
+                .send(Message::Text(String::from(
+                    r#"{"position_ms":2000,"playing":false}"#,
+                )))
+                .await
+                .unwrap();
+            // keep the connection open
+            let _ = websocket.next().await;
+        });
+        let mut source = TkClockSource::new(&TkSyncSource::WebSocket(url));
+        let mut clock = source.poll().await;
+        for _ in 0..50 {
+            if clock.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            clock = source.poll().await;
+        }
+        assert_eq!(
+            clock,
+            Ok(TkPlayerClock {
+                position_ms: 2000,
+                playing: false
+            })
+        );
+    }
+}
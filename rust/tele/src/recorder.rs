@@ -0,0 +1,385 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, info};
+
+use crate::trace::TkTraceEntry;
+
+pub static DEFAULT_RECORDING_FILE: &str = "Data\\SKSE\\Plugins\\Telekinesis.recording.jsonl";
+
+/// Opt-in recording of all native calls, i.e. to reproduce bug reports
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkRecorderSettings {
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for TkRecorderSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from(DEFAULT_RECORDING_FILE),
+        }
+    }
+}
+
+/// One native call, stored as a json line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TkRecordedCall {
+    /// Milliseconds since the recording was started
+    #[serde(rename = "t")]
+    pub at_ms: u64,
+    #[serde(rename = "fn")]
+    pub call: String,
+    pub args: Vec<String>,
+    #[serde(rename = "res")]
+    pub result: String,
+}
+
+/// Output of an actuator that differs between the recording and the replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TkReplayMismatch {
+    /// Arguments of the output lines, i.e. `device 1 scalar 0`
+    pub output: String,
+    pub expected: Vec<String>,
+    pub actual: Vec<String>,
+}
+
+/// Recorded calls are written by a separate thread, so that the game thread
+/// does not wait for the file
+struct TkRecorder {
+    started: Instant,
+    sender: Sender<TkRecordedCall>,
+    writer: JoinHandle<bool>,
+}
+
+static RECORDER: Mutex<Option<TkRecorder>> = Mutex::new(None);
+
+/// Calls of a replay are not recorded, it would replace the recording that is replayed
+static REPLAYING: AtomicBool = AtomicBool::new(false);
+
+/// Name of the lines with the device output, the native calls are named after the natives
+pub static OUTPUT_CALL: &str = "output";
+
+/// Starts recording into `path`, an existing recording is replaced
+pub fn start_recording(path: &str) -> bool {
+    if REPLAYING.load(Ordering::Relaxed) {
+        info!(path, "not recording during replay");
+        return false;
+    }
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!(path, "could not create recording: {}", err);
+            return false;
+        }
+    };
+    if let Ok(mut recorder) = RECORDER.lock() {
+        info!(path, "recording native calls");
+        let (sender, receiver) = mpsc::channel();
+        let previous = recorder.replace(TkRecorder {
+            started: Instant::now(),
+            sender,
+            writer: thread::spawn(move || write_calls(receiver, BufWriter::new(file))),
+        });
+        if let Some(previous) = previous {
+            previous.finish();
+        }
+        return true;
+    }
+    false
+}
+
+pub fn stop_recording() -> bool {
+    let recorder = match RECORDER.lock() {
+        Ok(mut recorder) => recorder.take(),
+        Err(_) => None,
+    };
+    match recorder {
+        Some(recorder) => {
+            info!("stopped recording");
+            recorder.finish()
+        }
+        None => false,
+    }
+}
+
+pub fn is_recording() -> bool {
+    RECORDER.lock().map(|x| x.is_some()).unwrap_or(false)
+}
+
+impl TkRecorder {
+    /// Waits until all calls are written
+    fn finish(self) -> bool {
+        drop(self.sender);
+        self.writer.join().unwrap_or(false)
+    }
+}
+
+/// Writes calls until the recording is stopped, the file is flushed whenever
+/// there are no more calls waiting
+fn write_calls(receiver: Receiver<TkRecordedCall>, mut writer: BufWriter<File>) -> bool {
+    let mut next = receiver.recv().ok();
+    while let Some(call) = next {
+        let written = serde_json::to_string(&call)
+            .map_err(|err| err.to_string())
+            .and_then(|json| writeln!(writer, "{}", json).map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            error!("failed recording call: {}", err);
+        }
+        next = match receiver.try_recv() {
+            Ok(call) => Some(call),
+            Err(_) => {
+                let _ = writer.flush();
+                receiver.recv().ok()
+            }
+        };
+    }
+    writer.flush().is_ok()
+}
+
+fn record_call(call: &str, args: Vec<String>, result: String) {
+    if REPLAYING.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(guard) = RECORDER.lock() else {
+        return;
+    };
+    let Some(recorder) = guard.as_ref() else {
+        return;
+    };
+    let _ = recorder.sender.send(TkRecordedCall {
+        at_ms: recorder.started.elapsed().as_millis() as u64,
+        call: call.into(),
+        args,
+        result,
+    });
+}
+
+/// Records a native call if a recording is running
+pub fn record<R: Debug>(call: &str, args: &[&str], result: &R) {
+    if !is_recording() {
+        return;
+    }
+    record_call(
+        call,
+        args.iter().map(|x| String::from(*x)).collect(),
+        format!("{:?}", result),
+    );
+}
+
+/// Records the device output while a recording is running, replays are compared by it
+pub async fn record_outputs(mut outputs: broadcast::Receiver<TkTraceEntry>) {
+    loop {
+        match outputs.recv().await {
+            Ok(entry) => {
+                let output = output_call(0, &entry);
+                record_call(OUTPUT_CALL, output.args, output.result);
+            }
+            Err(RecvError::Lagged(count)) => error!(count, "device output was not recorded"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// The output line of a traced device command
+pub fn output_call(at_ms: u64, entry: &TkTraceEntry) -> TkRecordedCall {
+    let arg = |x: Option<u32>| {
+        x.map(|x| x.to_string())
+            .unwrap_or_else(|| String::from("*"))
+    };
+    TkRecordedCall {
+        at_ms,
+        call: String::from(OUTPUT_CALL),
+        args: vec![
+            String::from("device"),
+            arg(entry.device_index),
+            String::from(if entry.linear { "linear" } else { "scalar" }),
+            arg(entry.index),
+        ],
+        result: format!("{:.2}", entry.value),
+    }
+}
+
+pub fn read_recording(path: &str) -> Result<Vec<TkRecordedCall>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Could not read '{}': {}", path, err))?;
+    content
+        .lines()
+        .filter(|x| !x.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str::<TkRecordedCall>(line)
+                .map_err(|err| format!("Invalid recording line {}: {}", i + 1, err))
+        })
+        .collect()
+}
+
+/// Executes the recorded native calls with their original timing (divided by `speedup`),
+/// nothing is recorded meanwhile. The output lines are compared with `compare_outputs`
+pub fn replay<F>(calls: &[TkRecordedCall], speedup: f64, mut execute: F)
+where
+    F: FnMut(&TkRecordedCall),
+{
+    REPLAYING.store(true, Ordering::Relaxed);
+    let started = Instant::now();
+    for call in calls.iter().filter(|x| x.call != OUTPUT_CALL) {
+        let due = Duration::from_secs_f64(call.at_ms as f64 / 1000.0 / speedup.max(0.001));
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+        execute(call);
+    }
+    REPLAYING.store(false, Ordering::Relaxed);
+}
+
+/// Compares the values that each actuator received, repeated values and the
+/// timing are ignored because they depend on the update interval
+pub fn compare_outputs(
+    recorded: &[TkRecordedCall],
+    replayed: &[TkRecordedCall],
+) -> Vec<TkReplayMismatch> {
+    let values = |calls: &[TkRecordedCall]| {
+        let mut outputs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for call in calls.iter().filter(|x| x.call == OUTPUT_CALL) {
+            let values = outputs.entry(call.args.join(" ")).or_default();
+            if values.last() != Some(&call.result) {
+                values.push(call.result.clone());
+            }
+        }
+        outputs
+    };
+    let (mut expected, mut actual) = (values(recorded), values(replayed));
+    let outputs = expected
+        .keys()
+        .chain(actual.keys())
+        .cloned()
+        .collect::<BTreeSet<String>>();
+    outputs
+        .into_iter()
+        .filter_map(|output| {
+            let expected = expected.remove(&output).unwrap_or_default();
+            let actual = actual.remove(&output).unwrap_or_default();
+            (expected != actual).then_some(TkReplayMismatch {
+                output,
+                expected,
+                actual,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nonparallel::nonparallel;
+    use tempfile::tempdir;
+
+    /// The recorder is global
+    static RECORDER_TEST: Mutex<()> = Mutex::new(());
+
+    fn call(at_ms: u64, call: &str, result: &str) -> TkRecordedCall {
+        TkRecordedCall {
+            at_ms,
+            call: call.into(),
+            args: vec![String::from("vibrate")],
+            result: result.into(),
+        }
+    }
+
+    #[test]
+    #[nonparallel(RECORDER_TEST)]
+    fn record_and_read() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("rec.jsonl");
+        let path = path.to_str().unwrap();
+        assert!(start_recording(path));
+        record("tk_control", &["vibrate", "100"], &1);
+        record("tk_qry_lst", &["devices"], &vec![String::from("vib1")]);
+        assert!(stop_recording());
+        record("tk_cmd", &["not recorded"], &true);
+
+        let calls = read_recording(path).unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].call, "tk_control");
+        assert_eq!(calls[0].args, vec!["vibrate", "100"]);
+        assert_eq!(calls[0].result, "1");
+        assert_eq!(calls[1].result, r#"["vib1"]"#);
+    }
+
+    #[test]
+    #[nonparallel(RECORDER_TEST)]
+    fn replay_is_not_recorded() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("rec.jsonl");
+        let path = path.to_str().unwrap();
+        let calls = vec![
+            call(0, "tk_control", "1"),
+            call(10, OUTPUT_CALL, "1.00"),
+            call(20, "tk_cmd", "true"),
+        ];
+        let mut executed = vec![];
+        replay(&calls, 1.0, |call| {
+            assert!(!start_recording(path));
+            record("tk_cmd", &["replayed"], &true);
+            executed.push(call.call.clone());
+        });
+        assert_eq!(executed, vec!["tk_control", "tk_cmd"]);
+        assert!(!is_recording());
+        assert!(start_recording(path));
+        assert!(stop_recording());
+    }
+
+    #[test]
+    fn outputs_are_compared_per_actuator() {
+        let entry = |device_index: u32, value: f64| TkTraceEntry {
+            at_ms: 0,
+            device_index: Some(device_index),
+            index: Some(0),
+            linear: false,
+            value,
+            duration_ms: None,
+        };
+        let recorded = vec![
+            call(0, "tk_control", "1"),
+            output_call(0, &entry(1, 0.5)),
+            output_call(100, &entry(1, 0.5)),
+            output_call(100, &entry(2, 1.0)),
+            output_call(200, &entry(1, 0.0)),
+        ];
+        assert_eq!(recorded[1].args, vec!["device", "1", "scalar", "0"]);
+        assert_eq!(recorded[1].result, "0.50");
+
+        let replayed = vec![
+            output_call(0, &entry(2, 1.0)),
+            output_call(0, &entry(1, 0.5)),
+            output_call(150, &entry(1, 0.0)),
+        ];
+        assert!(compare_outputs(&recorded, &replayed).is_empty());
+
+        let replayed = vec![
+            output_call(0, &entry(1, 0.5)),
+            output_call(0, &entry(1, 0.8)),
+        ];
+        let mismatches = compare_outputs(&recorded, &replayed);
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].output, "device 1 scalar 0");
+        assert_eq!(mismatches[0].expected, vec!["0.50", "0.00"]);
+        assert_eq!(mismatches[0].actual, vec!["0.50", "0.80"]);
+        assert!(mismatches[1].actual.is_empty());
+    }
+}
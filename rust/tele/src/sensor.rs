@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tracing::{debug, error, info};
+
+use buttplug::{
+    client::{ButtplugClientDevice, ButtplugClientDeviceEvent},
+    core::message::{ButtplugCurrentSpecServerMessage, SensorType},
+};
+
+use crate::connection::TkConnectionEvent;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TkSensorType {
+    Rssi,
+    Pressure,
+    Button,
+}
+
+impl TkSensorType {
+    pub fn from_buttplug(sensor_type: &SensorType) -> Option<TkSensorType> {
+        match sensor_type {
+            SensorType::RSSI => Some(TkSensorType::Rssi),
+            SensorType::Pressure => Some(TkSensorType::Pressure),
+            SensorType::Button => Some(TkSensorType::Button),
+            _ => None,
+        }
+    }
+}
+
+impl Display for TkSensorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TkSensorType::Rssi => write!(f, "rssi"),
+            TkSensorType::Pressure => write!(f, "pressure"),
+            TkSensorType::Button => write!(f, "button"),
+        }
+    }
+}
+
+/// Sensors are opt-in, subscriptions and polling cost battery and bandwidth
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkSensorSettings {
+    /// Subscribe to pressure and button sensors of connected devices
+    pub subscribe: bool,
+    /// Rssi is polled, 0 disables polling
+    pub rssi_poll_interval_secs: u64,
+    pub thresholds: Vec<TkSensorThreshold>,
+}
+
+/// Emits `Tele_SensorThreshold` whenever a reading crosses `value` (in any direction)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TkSensorThreshold {
+    pub sensor: TkSensorType,
+    pub value: i32,
+}
+
+impl Default for TkSensorSettings {
+    fn default() -> Self {
+        Self {
+            subscribe: false,
+            rssi_poll_interval_secs: 0,
+            thresholds: vec![],
+        }
+    }
+}
+
+/// Remembers the last reading of each sensor (by device and sensor index)
+/// to detect threshold crossings and button state changes
+#[derive(Debug, Default)]
+pub struct SensorMonitor {
+    last: HashMap<(u32, u32), i32>,
+}
+
+impl SensorMonitor {
+    /// Returns the events caused by a new reading, the reading itself included
+    pub fn update(
+        &mut self,
+        device: &Arc<ButtplugClientDevice>,
+        sensor: TkSensorType,
+        index: u32,
+        value: i32,
+        settings: &TkSensorSettings,
+    ) -> Vec<TkConnectionEvent> {
+        let previous = self.last.insert((device.index(), index), value);
+        let mut events = vec![TkConnectionEvent::SensorReading(
+            device.clone(),
+            sensor,
+            index,
+            value,
+        )];
+        if sensor == TkSensorType::Button {
+            let pressed = value != 0;
+            if previous.map(|x| (x != 0) != pressed).unwrap_or(pressed) {
+                events.push(TkConnectionEvent::ButtonPressed(device.clone(), pressed));
+            }
+        }
+        for threshold in settings.thresholds.iter().filter(|x| x.sensor == sensor) {
+            if let Some(above) = crossed(previous, value, threshold.value) {
+                events.push(TkConnectionEvent::SensorThreshold(
+                    device.clone(),
+                    sensor,
+                    value,
+                    above,
+                ));
+            }
+        }
+        events
+    }
+}
+
+/// Index of the rssi sensor, rssi is read with its own command but
+/// stored like the readings of subscribed sensors
+pub fn rssi_sensor_index(device: &ButtplugClientDevice) -> u32 {
+    device
+        .message_attributes()
+        .sensor_read_cmd()
+        .as_ref()
+        .and_then(|x| x.iter().position(|x| *x.sensor_type() == SensorType::RSSI))
+        .unwrap_or(0) as u32
+}
+
+/// `Some(true)` if the value rose to or above the threshold, `Some(false)` if it fell below
+fn crossed(previous: Option<i32>, value: i32, threshold: i32) -> Option<bool> {
+    let above = value >= threshold;
+    match previous {
+        Some(previous) if (previous >= threshold) == above => None,
+        None if !above => None,
+        _ => Some(above),
+    }
+}
+
+/// Subscribes to all pressure and button sensors of the device and
+/// forwards their readings until the device is removed
+pub async fn subscribe_sensors<F>(
+    device: Arc<ButtplugClientDevice>,
+    settings: TkSensorSettings,
+    monitor: Arc<Mutex<SensorMonitor>>,
+    send_events: F,
+) where
+    F: Fn(TkConnectionEvent) + Send + 'static,
+{
+    let sensors = device
+        .message_attributes()
+        .sensor_subscribe_cmd()
+        .clone()
+        .unwrap_or_default();
+    let mut subscribed = false;
+    for (index, sensor) in sensors.iter().enumerate() {
+        if TkSensorType::from_buttplug(sensor.sensor_type()).is_none() {
+            continue;
+        }
+        match device
+            .subscribe_sensor(index as u32, *sensor.sensor_type())
+            .await
+        {
+            Ok(()) => subscribed = true,
+            Err(err) => error!(
+                name = device.name(),
+                index, "failed subscribing sensor. Error: {}.", err
+            ),
+        }
+    }
+    if !subscribed {
+        return;
+    }
+    info!(name = device.name(), "subscribed sensors");
+    let mut events = device.event_stream();
+    Handle::current().spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                ButtplugClientDeviceEvent::Message(
+                    ButtplugCurrentSpecServerMessage::SensorReading(reading),
+                ) => {
+                    let Some(sensor) = TkSensorType::from_buttplug(reading.sensor_type()) else {
+                        continue;
+                    };
+                    let index = reading.sensor_index();
+                    let value = reading.data().first().copied().unwrap_or(0);
+                    debug!(name = device.name(), %sensor, index, value, "sensor reading");
+                    let result = match monitor.lock() {
+                        Ok(mut monitor) => monitor.update(&device, sensor, index, value, &settings),
+                        Err(_) => vec![],
+                    };
+                    for event in result {
+                        send_events(event);
+                    }
+                }
+                ButtplugClientDeviceEvent::DeviceRemoved
+                | ButtplugClientDeviceEvent::ClientDisconnect => break,
+                _ => {}
+            }
+        }
+        debug!(name = device.name(), "sensor stream closed");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_crossing_upwards() {
+        assert_eq!(crossed(Some(10), 60, 50), Some(true));
+        assert_eq!(crossed(Some(60), 70, 50), None);
+    }
+
+    #[test]
+    fn threshold_crossing_downwards() {
+        assert_eq!(crossed(Some(60), 10, 50), Some(false));
+        assert_eq!(crossed(Some(10), 20, 50), None);
+    }
+
+    #[test]
+    fn sensors_are_opt_in() {
+        let settings = TkSensorSettings::default();
+        assert!(!settings.subscribe);
+        assert_eq!(settings.rssi_poll_interval_secs, 0);
+    }
+
+    #[test]
+    fn threshold_first_reading() {
+        assert_eq!(crossed(None, 60, 50), Some(true));
+        assert_eq!(crossed(None, 10, 50), None);
+    }
+}
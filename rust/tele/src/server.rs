@@ -0,0 +1,708 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info};
+
+use buttplug::{
+    client::{
+        ButtplugClient, ButtplugClientDevice, ButtplugClientEvent, LinearCommand,
+        ScalarValueCommand,
+    },
+    core::message::{ActuatorType, LinearCmd, ScalarCmd},
+};
+
+use bp_scheduler::actuator::get_actuators;
+
+use crate::{api::Api, TkApi};
+
+static SERVER_NAME: &str = "Telekinesis";
+static MESSAGE_VERSION: u32 = 3;
+
+// buttplug spec error codes
+static ERROR_MSG: u32 = 3;
+static ERROR_DEVICE: u32 = 4;
+
+/// Time before retrying to stop pre-empted game tasks when the api was busy
+static PREEMPT_RETRY: Duration = Duration::from_millis(100);
+/// Time an external command waits for the pre-empted game tasks to end
+static PREEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Exposes the devices of the (in-process) connection to external buttplug clients.
+/// Device commands are parsed with the message types of the buttplug crate and
+/// forwarded to the shared client, so the game and external clients see the same devices.
+///
+/// Only a subset of the v3 messages is served: `RequestServerInfo`, `Ping`,
+/// `StartScanning`, `StopScanning`, `RequestDeviceList`, `ScalarCmd`, `LinearCmd`,
+/// `StopDeviceCmd` and `StopAllDevices`. Other messages (`RotateCmd`, sensor and raw
+/// commands) are answered with an error
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkServerSettings {
+    pub enabled: bool,
+    pub bind_address: String,
+    pub port: u16,
+    pub priority: TkServerPriority,
+}
+
+impl Default for TkServerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: String::from("127.0.0.1"),
+            port: 12345,
+            priority: TkServerPriority::Game,
+        }
+    }
+}
+
+/// Decides who gets an actuator when the game and external clients want it at the same time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TkServerPriority {
+    /// External commands are rejected while a game task runs on the actuator
+    Game,
+    /// Game tasks skip actuators that are currently driven by an external client,
+    /// running game tasks are stopped when an external client takes their actuator
+    External,
+}
+
+/// Shared bookkeeping of which actuators are used by game tasks or external clients
+#[derive(Clone, Debug, Default)]
+pub struct TkDeviceAccess {
+    state: Arc<Mutex<DeviceAccessState>>,
+    preempted: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct DeviceAccessState {
+    game: HashMap<i32, Vec<String>>,
+    external: HashSet<String>,
+    preempted: Vec<i32>,
+}
+
+impl TkDeviceAccess {
+    pub fn game_started(&self, handle: i32, actuator_ids: Vec<String>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.game.insert(handle, actuator_ids);
+        }
+    }
+
+    pub fn game_stopped(&self, handle: i32) {
+        if let Ok(mut state) = self.state.lock() {
+            state.game.remove(&handle);
+        }
+    }
+
+    /// Handles of the running game tasks with the actuators they use
+    pub fn game_tasks(&self) -> Vec<(i32, Vec<String>)> {
+        match self.state.lock() {
+            Ok(state) => state
+                .game
+                .iter()
+                .map(|(handle, actuator_ids)| (*handle, actuator_ids.clone()))
+                .sorted_by_key(|(handle, _)| *handle)
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    pub fn used_by_game(&self, actuator_id: &str) -> bool {
+        match self.state.lock() {
+            Ok(state) => state.game.values().flatten().any(|x| x == actuator_id),
+            Err(_) => false,
+        }
+    }
+
+    pub fn set_external(&self, actuator_id: &str, active: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            if active {
+                state.external.insert(actuator_id.into());
+            } else {
+                state.external.remove(actuator_id);
+            }
+        }
+    }
+
+    pub fn used_by_external(&self, actuator_id: &str) -> bool {
+        match self.state.lock() {
+            Ok(state) => state.external.contains(actuator_id),
+            Err(_) => false,
+        }
+    }
+
+    /// Takes the actuator away from the game tasks that use it, they are
+    /// stopped by `stop_preempted_tasks`. Returns their handles
+    pub fn preempt(&self, actuator_id: &str) -> Vec<i32> {
+        let Ok(mut state) = self.state.lock() else {
+            return vec![];
+        };
+        let handles = state
+            .game
+            .iter()
+            .filter(|(_, actuator_ids)| actuator_ids.iter().any(|x| x == actuator_id))
+            .map(|(handle, _)| *handle)
+            .sorted()
+            .collect::<Vec<i32>>();
+        let new = handles
+            .iter()
+            .filter(|x| !state.preempted.contains(x))
+            .copied()
+            .collect::<Vec<i32>>();
+        if !new.is_empty() {
+            state.preempted.extend(new);
+            self.preempted.notify_one();
+        }
+        handles
+    }
+
+    /// Waits until the game tasks ended, so their final output cannot
+    /// overwrite the external command. Returns `false` on timeout
+    async fn await_game_stopped(&self, handles: &[i32]) -> bool {
+        let started = tokio::time::Instant::now();
+        loop {
+            let running = match self.state.lock() {
+                Ok(state) => handles.iter().any(|x| state.game.contains_key(x)),
+                Err(_) => false,
+            };
+            if !running {
+                return true;
+            }
+            if started.elapsed() > PREEMPT_TIMEOUT {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Waits until game tasks were pre-empted and returns their handles
+    async fn next_preempted(&self) -> Vec<i32> {
+        loop {
+            if let Ok(mut state) = self.state.lock() {
+                if !state.preempted.is_empty() {
+                    return state.preempted.drain(..).collect();
+                }
+            }
+            self.preempted.notified().await;
+        }
+    }
+}
+
+/// Stops the game tasks whose actuators were taken by an external client,
+/// stops that fail because the api is busy are retried
+pub async fn stop_preempted_tasks(access: TkDeviceAccess, mut api: TkApi) {
+    let mut pending: Vec<i32> = vec![];
+    loop {
+        if pending.is_empty() {
+            pending = access.next_preempted().await;
+        } else {
+            tokio::time::sleep(PREEMPT_RETRY).await;
+        }
+        pending.retain(|handle| {
+            let stopped = api.try_exec(|tk| tk.stop(*handle), false);
+            if stopped {
+                info!(handle, "game task pre-empted by external client");
+            }
+            !stopped
+        });
+    }
+}
+
+pub async fn run_server(
+    settings: TkServerSettings,
+    client: Arc<ButtplugClient>,
+    access: TkDeviceAccess,
+) {
+    let address = format!("{}:{}", settings.bind_address, settings.port);
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not start server on {}. Error: {}.", address, err);
+            return;
+        }
+    };
+    info!(address, "buttplug server listening");
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let session = TkServerSession::new(client.clone(), access.clone(), settings.priority);
+                tokio::spawn(async move {
+                    session.run(stream, peer).await;
+                });
+            }
+            Err(err) => error!("Failed accepting client. Error: {}.", err),
+        }
+    }
+}
+
+struct TkServerSession {
+    client: Arc<ButtplugClient>,
+    access: TkDeviceAccess,
+    priority: TkServerPriority,
+    used_devices: HashSet<u32>,
+}
+
+impl TkServerSession {
+    fn new(client: Arc<ButtplugClient>, access: TkDeviceAccess, priority: TkServerPriority) -> Self {
+        TkServerSession {
+            client,
+            access,
+            priority,
+            used_devices: HashSet::new(),
+        }
+    }
+
+    async fn run(mut self, stream: TcpStream, peer: SocketAddr) {
+        let websocket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(websocket) => websocket,
+            Err(err) => {
+                error!(?peer, "websocket handshake failed. Error: {}.", err);
+                return;
+            }
+        };
+        info!(?peer, "external client connected");
+        let (mut sink, mut stream) = websocket.split();
+        let mut events = self.client.event_stream();
+        loop {
+            let replies = tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Text(text))) => self.handle_text(&text).await,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+                evt = events.next() => match evt {
+                    Some(ButtplugClientEvent::DeviceAdded(device)) => vec![device_added(&device)],
+                    Some(ButtplugClientEvent::DeviceRemoved(device)) => {
+                        vec![json!({ "DeviceRemoved": { "Id": 0, "DeviceIndex": device.index() } })]
+                    }
+                    Some(ButtplugClientEvent::ScanningFinished) => vec![json!({ "ScanningFinished": { "Id": 0 } })],
+                    Some(_) => continue,
+                    None => break,
+                }
+            };
+            if let Err(err) = sink.send(Message::Text(Value::Array(replies).to_string())).await {
+                error!(?peer, "failed sending reply. Error: {}.", err);
+                break;
+            }
+        }
+        info!(?peer, "external client disconnected");
+        self.stop_used_devices().await;
+    }
+
+    async fn handle_text(&mut self, text: &str) -> Vec<Value> {
+        debug!(text, "external message");
+        let messages = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(messages)) => messages,
+            _ => return vec![error_msg(0, ERROR_MSG, "Message is not a json array")],
+        };
+        let mut replies = vec![];
+        for message in messages {
+            replies.extend(self.handle_message(&message).await);
+        }
+        replies
+    }
+
+    async fn handle_message(&mut self, message: &Value) -> Vec<Value> {
+        let Some((name, body)) = message.as_object().and_then(|x| x.iter().next()) else {
+            return vec![error_msg(0, ERROR_MSG, "Invalid message")];
+        };
+        let id = body["Id"].as_u64().unwrap_or(0) as u32;
+        match name.as_str() {
+            "RequestServerInfo" => vec![json!({
+                "ServerInfo": {
+                    "Id": id,
+                    "ServerName": SERVER_NAME,
+                    "MessageVersion": MESSAGE_VERSION,
+                    "MaxPingTime": 0
+                }
+            })],
+            "Ping" => vec![ok_msg(id)],
+            "StartScanning" => vec![match self.client.start_scanning().await {
+                Ok(()) => ok_msg(id),
+                Err(err) => error_msg(id, ERROR_DEVICE, &err.to_string()),
+            }],
+            "StopScanning" => vec![match self.client.stop_scanning().await {
+                Ok(()) => ok_msg(id),
+                Err(err) => error_msg(id, ERROR_DEVICE, &err.to_string()),
+            }],
+            "RequestDeviceList" => {
+                let devices = self.client.devices().iter().map(device_info).collect::<Vec<Value>>();
+                vec![json!({ "DeviceList": { "Id": id, "Devices": devices } })]
+            }
+            "ScalarCmd" => vec![self.scalar_cmd(id, body).await],
+            "LinearCmd" => vec![self.linear_cmd(id, body).await],
+            "StopDeviceCmd" => vec![match self.get_device(body) {
+                Some(device) => self.stop_device(id, &device).await,
+                None => unknown_device(id, body),
+            }],
+            "StopAllDevices" => {
+                self.stop_used_devices().await;
+                vec![ok_msg(id)]
+            }
+            _ => vec![error_msg(id, ERROR_MSG, &format!("{} is not supported", name))],
+        }
+    }
+
+    async fn scalar_cmd(&mut self, id: u32, body: &Value) -> Value {
+        let cmd = match serde_json::from_value::<ScalarCmd>(body.clone()) {
+            Ok(cmd) => cmd,
+            Err(err) => return error_msg(id, ERROR_MSG, &format!("Invalid ScalarCmd: {}", err)),
+        };
+        let Some(device) = self.get_device(body) else {
+            return unknown_device(id, body);
+        };
+        let mut scalars = HashMap::new();
+        let mut used = vec![];
+        for scalar in cmd.scalars() {
+            let level = scalar.scalar().clamp(0.0, 1.0);
+            match self.check_access(&device, scalar.index(), false) {
+                Ok(actuator_id) => used.push((actuator_id, level > 0.0)),
+                Err(err) => return error_msg(id, ERROR_DEVICE, &err),
+            }
+            scalars.insert(scalar.index(), (level, scalar.actuator()));
+        }
+        if scalars.is_empty() {
+            return error_msg(id, ERROR_MSG, "ScalarCmd without scalars");
+        }
+        self.mark_used(&device, &used);
+        self.preempt_game(&used).await;
+        match device.scalar(&ScalarValueCommand::ScalarValueMap(scalars)).await {
+            Ok(()) => ok_msg(id),
+            Err(err) => error_msg(id, ERROR_DEVICE, &err.to_string()),
+        }
+    }
+
+    async fn linear_cmd(&mut self, id: u32, body: &Value) -> Value {
+        let cmd = match serde_json::from_value::<LinearCmd>(body.clone()) {
+            Ok(cmd) => cmd,
+            Err(err) => return error_msg(id, ERROR_MSG, &format!("Invalid LinearCmd: {}", err)),
+        };
+        let Some(device) = self.get_device(body) else {
+            return unknown_device(id, body);
+        };
+        let mut vectors = HashMap::new();
+        let mut used = vec![];
+        for vector in cmd.vectors() {
+            match self.check_access(&device, vector.index(), true) {
+                Ok(actuator_id) => used.push((actuator_id, true)),
+                Err(err) => return error_msg(id, ERROR_DEVICE, &err),
+            }
+            vectors.insert(vector.index(), (vector.duration(), vector.position().clamp(0.0, 1.0)));
+        }
+        if vectors.is_empty() {
+            return error_msg(id, ERROR_MSG, "LinearCmd without vectors");
+        }
+        self.mark_used(&device, &used);
+        self.preempt_game(&used).await;
+        match device.linear(&LinearCommand::LinearMap(vectors)).await {
+            Ok(()) => ok_msg(id),
+            Err(err) => error_msg(id, ERROR_DEVICE, &err.to_string()),
+        }
+    }
+
+    /// Applies the priority rule to the actuator at `index`, nothing is marked as used
+    /// so a command can be rejected as a whole if any of its entries is rejected
+    fn check_access(
+        &self,
+        device: &Arc<ButtplugClientDevice>,
+        index: u32,
+        linear: bool,
+    ) -> Result<String, String> {
+        let actuator_id = get_actuators(vec![device.clone()])
+            .iter()
+            .find(|x| x.index_in_device == index && (x.actuator == ActuatorType::Position) == linear)
+            .map(|x| x.identifier().to_owned())
+            .ok_or_else(|| format!("{} has no actuator {}", device.name(), index))?;
+        if self.priority == TkServerPriority::Game && self.access.used_by_game(&actuator_id) {
+            debug!(actuator_id, "external command rejected, actuator used by game");
+            return Err(format!("{} is in use by the game", actuator_id));
+        }
+        Ok(actuator_id)
+    }
+
+    /// Marks the actuators of an accepted command as (no longer) externally used
+    fn mark_used(&mut self, device: &Arc<ButtplugClientDevice>, used: &[(String, bool)]) {
+        for (actuator_id, active) in used {
+            self.access.set_external(actuator_id, *active);
+        }
+        self.used_devices.insert(device.index());
+    }
+
+    /// With external priority the game tasks on the actuators of an accepted
+    /// command are stopped before the command is sent
+    async fn preempt_game(&self, used: &[(String, bool)]) {
+        if self.priority != TkServerPriority::External {
+            return;
+        }
+        let handles = used
+            .iter()
+            .flat_map(|(actuator_id, _)| self.access.preempt(actuator_id))
+            .collect::<Vec<i32>>();
+        if !handles.is_empty() && !self.access.await_game_stopped(&handles).await {
+            error!(?handles, "pre-empted game tasks did not stop");
+        }
+    }
+
+    async fn stop_device(&self, id: u32, device: &Arc<ButtplugClientDevice>) -> Value {
+        let actuators = get_actuators(vec![device.clone()]);
+        for actuator in &actuators {
+            self.access.set_external(actuator.identifier(), false);
+        }
+        if self.priority == TkServerPriority::Game
+            && actuators.iter().any(|x| self.access.used_by_game(x.identifier()))
+        {
+            // game keeps control, nothing to stop from the external side
+            return ok_msg(id);
+        }
+        match device.stop().await {
+            Ok(()) => ok_msg(id),
+            Err(err) => error_msg(id, ERROR_DEVICE, &err.to_string()),
+        }
+    }
+
+    async fn stop_used_devices(&mut self) {
+        let devices = self
+            .client
+            .devices()
+            .into_iter()
+            .filter(|x| self.used_devices.contains(&x.index()))
+            .collect::<Vec<Arc<ButtplugClientDevice>>>();
+        for device in devices {
+            self.stop_device(0, &device).await;
+        }
+        self.used_devices.clear();
+    }
+
+    fn get_device(&self, body: &Value) -> Option<Arc<ButtplugClientDevice>> {
+        let index = body["DeviceIndex"].as_u64()? as u32;
+        self.client.devices().into_iter().find(|x| x.index() == index)
+    }
+}
+
+fn device_info(device: &Arc<ButtplugClientDevice>) -> Value {
+    json!({
+        "DeviceIndex": device.index(),
+        "DeviceName": device.name(),
+        "DeviceMessages": serde_json::to_value(device.message_attributes()).unwrap_or_default()
+    })
+}
+
+fn device_added(device: &Arc<ButtplugClientDevice>) -> Value {
+    let mut info = device_info(device);
+    info["Id"] = json!(0);
+    json!({ "DeviceAdded": info })
+}
+
+fn ok_msg(id: u32) -> Value {
+    json!({ "Ok": { "Id": id } })
+}
+
+fn error_msg(id: u32, code: u32, message: &str) -> Value {
+    json!({ "Error": { "Id": id, "ErrorMessage": message, "ErrorCode": code } })
+}
+
+fn unknown_device(id: u32, body: &Value) -> Value {
+    error_msg(id, ERROR_DEVICE, &format!("Unknown device {}", body["DeviceIndex"]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use super::*;
+    use crate::{
+        api::ApiCaller, fake_connection::connected_fakes, settings::TkSettings,
+        telekinesis::ERROR_HANDLE,
+    };
+    use bp_fakes::*;
+    use tokio_tungstenite::connect_async;
+
+    fn session(priority: TkServerPriority) -> TkServerSession {
+        TkServerSession::new(
+            Arc::new(ButtplugClient::new("Test")),
+            TkDeviceAccess::default(),
+            priority,
+        )
+    }
+
+    #[test]
+    fn server_info_and_ping() {
+        let mut session = session(TkServerPriority::Game);
+        let replies = tokio_test::block_on(session.handle_text(
+            r#"[{"RequestServerInfo":{"Id":1,"ClientName":"Player","MessageVersion":3}},{"Ping":{"Id":2}}]"#,
+        ));
+        assert_eq!(replies[0]["ServerInfo"]["Id"], 1);
+        assert_eq!(replies[0]["ServerInfo"]["MessageVersion"], 3);
+        assert_eq!(replies[1]["Ok"]["Id"], 2);
+    }
+
+    #[test]
+    fn invalid_and_unsupported_messages_return_errors() {
+        let mut session = session(TkServerPriority::Game);
+        let invalid = tokio_test::block_on(session.handle_text("not json"));
+        assert_eq!(invalid[0]["Error"]["ErrorCode"], ERROR_MSG);
+
+        let unsupported = tokio_test::block_on(session.handle_text(r#"[{"RawReadCmd":{"Id":5}}]"#));
+        assert_eq!(unsupported[0]["Error"]["Id"], 5);
+    }
+
+    #[test]
+    fn command_on_unknown_device_fails() {
+        let mut session = session(TkServerPriority::Game);
+        let replies = tokio_test::block_on(session.handle_text(
+            r#"[{"ScalarCmd":{"Id":3,"DeviceIndex":7,"Scalars":[{"Index":0,"Scalar":0.5,"ActuatorType":"Vibrate"}]}}]"#,
+        ));
+        assert_eq!(replies[0]["Error"]["ErrorCode"], ERROR_DEVICE);
+    }
+
+    #[test]
+    fn commands_with_missing_fields_are_rejected() {
+        let mut session = session(TkServerPriority::Game);
+        let replies = tokio_test::block_on(session.handle_text(
+            r#"[{"ScalarCmd":{"Id":3,"DeviceIndex":0,"Scalars":[{"Index":0,"ActuatorType":"Vibrate"}]}},
+                {"ScalarCmd":{"Id":4,"DeviceIndex":0,"Scalars":[{"Scalar":0.5,"ActuatorType":"Vibrate"}]}},
+                {"LinearCmd":{"Id":5,"DeviceIndex":0,"Vectors":[{"Index":0,"Position":0.5}]}}]"#,
+        ));
+        assert_eq!(replies.len(), 3);
+        for reply in replies {
+            assert_eq!(reply["Error"]["ErrorCode"], ERROR_MSG);
+        }
+    }
+
+    #[test]
+    fn device_access_tracks_game_handles() {
+        let access = TkDeviceAccess::default();
+        access.game_started(1, vec![String::from("vib1 (Vibrate)")]);
+        assert!(access.used_by_game("vib1 (Vibrate)"));
+        assert!(!access.used_by_game("vib2 (Vibrate)"));
+        access.game_stopped(1);
+        assert!(!access.used_by_game("vib1 (Vibrate)"));
+    }
+
+    #[test]
+    fn device_access_tracks_external_outputs() {
+        let access = TkDeviceAccess::default();
+        access.set_external("vib1 (Vibrate)", true);
+        assert!(access.used_by_external("vib1 (Vibrate)"));
+        access.set_external("vib1 (Vibrate)", false);
+        assert!(!access.used_by_external("vib1 (Vibrate)"));
+    }
+
+    fn connect_with_server(
+        port: u16,
+        priority: TkServerPriority,
+    ) -> (TkApi, FakeConnectorCallRegistry) {
+        let mut settings = TkSettings::default();
+        settings.server = TkServerSettings {
+            enabled: true,
+            port,
+            priority,
+            ..Default::default()
+        };
+        let (mut tk, call_registry) =
+            connected_fakes(vec![scalar(1, "vib1", ActuatorType::Vibrate)], settings);
+        tk.settings.set_enabled("vib1 (Vibrate)", true);
+        let mut api = TkApi {
+            state: Arc::new(Mutex::new(Some(tk))),
+            caller: ApiCaller::Game,
+        };
+        assert!(api.start_services());
+        (api, call_registry)
+    }
+
+    fn game_uses_vib1(api: &mut TkApi) -> bool {
+        api.try_exec(|tk| tk.active_tasks(), vec![])
+            .iter()
+            .any(|(_, actuator_ids)| actuator_ids.iter().any(|x| x == "vib1 (Vibrate)"))
+    }
+
+    fn wait_until<F: FnMut() -> bool>(mut condition: F) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(3) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Sends a `ScalarCmd` for the first actuator of `vib1` and returns its reply
+    async fn external_vibrate<S>(websocket: &mut S, level: f64) -> Value
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>>
+            + SinkExt<Message>
+            + Unpin,
+    {
+        let cmd = json!([{ "ScalarCmd": { "Id": 1, "DeviceIndex": 1, "Scalars": [
+            { "Index": 0, "Scalar": level, "ActuatorType": "Vibrate" }
+        ]}}]);
+        let _ = websocket.send(Message::Text(cmd.to_string())).await;
+        loop {
+            match websocket.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    let replies = serde_json::from_str::<Value>(&text).unwrap();
+                    if replies[0].get("Ok").is_some() || replies[0].get("Error").is_some() {
+                        return replies[0].clone();
+                    }
+                }
+                Some(_) => continue,
+                None => panic!("server closed the connection"),
+            }
+        }
+    }
+
+    #[test]
+    fn game_priority_rejects_external_commands_on_game_actuators() {
+        let (mut api, _) = connect_with_server(12461, TkServerPriority::Game);
+        let handle = api.tk_control_list("vibrate", 100, 5.0, "", &[]);
+        assert_ne!(handle, ERROR_HANDLE);
+        assert!(wait_until(|| game_uses_vib1(&mut api)));
+
+        tokio_test::block_on(async {
+            let (mut websocket, _) = connect_async("ws://127.0.0.1:12461").await.unwrap();
+            let rejected = external_vibrate(&mut websocket, 0.5).await;
+            assert_eq!(rejected["Error"]["ErrorCode"], ERROR_DEVICE);
+
+            assert!(api.tk_stop(handle));
+            assert!(wait_until(|| !game_uses_vib1(&mut api)));
+            let accepted = external_vibrate(&mut websocket, 0.5).await;
+            assert_eq!(accepted["Ok"]["Id"], 1);
+        });
+    }
+
+    #[test]
+    fn external_priority_preempts_running_game_tasks() {
+        let (mut api, call_registry) = connect_with_server(12462, TkServerPriority::External);
+        let handle = api.tk_control_list("vibrate", 100, 5.0, "", &[]);
+        assert_ne!(handle, ERROR_HANDLE);
+        assert!(wait_until(|| game_uses_vib1(&mut api)));
+
+        tokio_test::block_on(async {
+            let (mut websocket, _) = connect_async("ws://127.0.0.1:12462").await.unwrap();
+            let accepted = external_vibrate(&mut websocket, 0.5).await;
+            assert_eq!(accepted["Ok"]["Id"], 1);
+            assert!(!game_uses_vib1(&mut api));
+            call_registry
+                .get_device(1)
+                .last()
+                .unwrap()
+                .assert_strenth(0.5);
+
+            // new game tasks skip the actuator while the external client drives it
+            api.tk_control_list("vibrate", 100, 5.0, "", &[]);
+            assert!(!game_uses_vib1(&mut api));
+        });
+    }
+}
@@ -1,7 +1,9 @@
 use std::{
+    collections::BTreeMap,
     fmt::{self, Display},
     fs::{self},
     path::PathBuf,
+    time::Duration,
 };
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
@@ -11,11 +13,25 @@ use buttplug::core::message::ActuatorType;
 
 use bp_scheduler::{actuator::Actuator, settings::{ActuatorSettings, LinearRange, LinearSpeedScaling, ScalarRange}};
 
-use crate::input::sanitize_name_list;
+use crate::{
+    body_parts::{body_part_weight, sanitize_body_parts, TkBodyPartExpr, TkBodyPartGroups},
+    http::TkHttpSettings,
+    input::matches_wildcard,
+    mqtt::TkMqttSettings,
+    osc::TkOscSettings,
+    player_sync::{TkSyncSettings, TkSyncSource},
+    recorder::TkRecorderSettings,
+    sensor::TkSensorSettings,
+    spatial::TkPosition,
+    server::TkServerSettings,
+    trace::TkTraceSettings,
+    websocket::TkWebSocketSettings,
+};
 
 pub static DEFAULT_PATTERN_PATH: &str = "Data\\SKSE\\Plugins\\Telekinesis\\Patterns";
 pub static SETTINGS_PATH: &str = "Data\\SKSE\\Plugins";
 pub static SETTINGS_FILE: &str = "Telekinesis.v2.json";
+static REDACTED: &str = "<redacted>";
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum TkConnectionType {
@@ -34,6 +50,135 @@ impl Display for TkConnectionType {
     }
 }
 
+/// Device communication managers used by the in-process server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkInProcessSettings {
+    pub bluetooth: bool,
+    pub serial_port: bool,
+    pub lovense_hid_dongle: bool,
+    pub lovense_serial_dongle: bool,
+    pub xinput: bool,
+    /// Optional buttplug user device configuration (json) for custom or unknown toys
+    pub device_config_file: Option<String>,
+}
+
+impl Default for TkInProcessSettings {
+    fn default() -> Self {
+        Self {
+            bluetooth: true,
+            serial_port: false,
+            lovense_hid_dongle: false,
+            lovense_serial_dongle: false,
+            xinput: false,
+            device_config_file: None,
+        }
+    }
+}
+
+impl TkInProcessSettings {
+    fn manager(&mut self, name: &str) -> Option<&mut bool> {
+        match name.to_lowercase().trim() {
+            "bluetooth" => Some(&mut self.bluetooth),
+            "serial_port" => Some(&mut self.serial_port),
+            "lovense_hid_dongle" => Some(&mut self.lovense_hid_dongle),
+            "lovense_serial_dongle" => Some(&mut self.lovense_serial_dongle),
+            "xinput" => Some(&mut self.xinput),
+            _ => {
+                error!("unknown communication manager {:?}", name);
+                None
+            }
+        }
+    }
+
+    pub fn set_manager_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        if let Some(manager) = self.manager(name) {
+            *manager = enabled;
+            return true;
+        }
+        false
+    }
+
+    pub fn get_manager_enabled(&mut self, name: &str) -> bool {
+        self.manager(name).map(|x| *x).unwrap_or(false)
+    }
+}
+
+/// When and how long to scan for devices
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkScanSettings {
+    /// Start scanning as soon as the connection is established
+    pub on_connect: bool,
+    /// Stop scanning after the given time, scan indefinitely if `None`
+    pub duration_secs: Option<u64>,
+    /// Periodically rescan while enabled devices are missing
+    pub rescan_interval_secs: Option<u64>,
+}
+
+/// Battery polling and low battery warnings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TkBatterySettings {
+    pub poll_interval_secs: u64,
+    /// Battery level (0.0 - 1.0) below which `Tele_BatteryLow` is sent, 0.0 disables warnings
+    pub low_level: f64,
+    /// Overrides for devices that match the name pattern
+    pub devices: Vec<TkDeviceBatterySettings>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TkDeviceBatterySettings {
+    /// Device name, `*` matches any sequence of characters
+    pub name: String,
+    #[serde(default)]
+    pub poll_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub low_level: Option<f64>,
+}
+
+impl Default for TkBatterySettings {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 300,
+            low_level: 0.2,
+            devices: vec![],
+        }
+    }
+}
+
+impl TkBatterySettings {
+    fn get_device(&self, device_name: &str) -> Option<&TkDeviceBatterySettings> {
+        self.devices.iter().find(|x| matches_wildcard(&x.name, device_name))
+    }
+
+    pub fn poll_interval(&self, device_name: &str) -> Duration {
+        let secs = self
+            .get_device(device_name)
+            .and_then(|x| x.poll_interval_secs)
+            .unwrap_or(self.poll_interval_secs);
+        Duration::from_secs(secs.max(1))
+    }
+
+    pub fn low_level(&self, device_name: &str) -> f64 {
+        self.get_device(device_name)
+            .and_then(|x| x.low_level)
+            .unwrap_or(self.low_level)
+    }
+
+    /// Shortest configured interval, used as the tick of the battery thread
+    pub fn min_poll_interval(&self) -> Duration {
+        let secs = self
+            .devices
+            .iter()
+            .filter_map(|x| x.poll_interval_secs)
+            .chain([self.poll_interval_secs])
+            .min()
+            .unwrap_or(self.poll_interval_secs);
+        Duration::from_secs(secs.max(1))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum TkLogLevel {
     Trace = 0,
@@ -55,11 +200,65 @@ impl From<TkLogLevel> for Level {
     }
 }
 
+/// Log file rotation and per-module log levels, i.e. `"connection": "Trace"`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TkLogSettings {
+    pub modules: BTreeMap<String, TkLogLevel>,
+    /// Number of log files of previous sessions that are kept
+    pub keep_files: usize,
+    /// The log file is rotated once it reaches this size, `0` disables the limit
+    pub max_file_size_mb: u64,
+}
+
+impl Default for TkLogSettings {
+    fn default() -> Self {
+        Self {
+            modules: BTreeMap::new(),
+            keep_files: 3,
+            max_file_size_mb: 50,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TkSettings {
     pub version: u32,
     pub log_level: TkLogLevel,
+    #[serde(default)]
+    pub logging: TkLogSettings,
     pub connection: TkConnectionType,
+    #[serde(default)]
+    pub websocket: TkWebSocketSettings,
+    #[serde(default)]
+    pub in_process: TkInProcessSettings,
+    #[serde(default)]
+    pub server: TkServerSettings,
+    #[serde(default)]
+    pub http: TkHttpSettings,
+    #[serde(default)]
+    pub osc: TkOscSettings,
+    #[serde(default)]
+    pub mqtt: TkMqttSettings,
+    #[serde(default)]
+    pub sync: TkSyncSettings,
+    #[serde(default)]
+    pub scan: TkScanSettings,
+    #[serde(default)]
+    pub battery: TkBatterySettings,
+    #[serde(default)]
+    pub sensors: TkSensorSettings,
+    #[serde(default)]
+    pub device_rules: Vec<TkDeviceRule>,
+    /// Body part groups that can be referenced with `@name`
+    #[serde(default)]
+    pub body_part_groups: TkBodyPartGroups,
+    #[serde(default)]
+    pub event_rules: Vec<TkEventRule>,
+    #[serde(default)]
+    pub recorder: TkRecorderSettings,
+    #[serde(default)]
+    pub trace: TkTraceSettings,
     pub devices: Vec<TkDeviceSettings>,
     #[serde(skip)]
     pub pattern_path: String,
@@ -72,6 +271,9 @@ pub struct TkDeviceSettings {
     pub events: Vec<String>,
     #[serde(default = "ActuatorSettings::default")]
     pub actuator_settings: ActuatorSettings,
+    /// Optional position on the body for directional effects
+    #[serde(default)]
+    pub position: Option<TkPosition>,
 }
 
 impl TkDeviceSettings {
@@ -81,6 +283,7 @@ impl TkDeviceSettings {
             enabled: false,
             events: vec![],
             actuator_settings: ActuatorSettings::None,
+            position: None,
         }
     }
     pub fn from_actuator(actuator: &Actuator) -> TkDeviceSettings {
@@ -97,8 +300,131 @@ impl TkDeviceSettings {
                 ActuatorType::Position => ActuatorSettings::Linear(LinearRange::default()),
                 _ => ActuatorSettings::None,
             },
+            position: None,
         }
     }
+
+    /// Scalar settings scaled by the weight of the selected body parts
+    pub fn weighted_actuator_settings(
+        &self,
+        actuator: &Actuator,
+        body_parts: &[TkBodyPartExpr],
+        groups: &TkBodyPartGroups,
+    ) -> ActuatorSettings {
+        let weight = body_part_weight(body_parts, &self.events, groups);
+        scale_actuator_settings(&self.actuator_settings, actuator, weight)
+    }
+}
+
+/// Multiplies the factor of scalar actuators, linear settings stay untouched
+pub fn scale_actuator_settings(settings: &ActuatorSettings, actuator: &Actuator, factor: f64) -> ActuatorSettings {
+    if factor == 1.0 {
+        return settings.clone();
+    }
+    let scalar = match settings {
+        ActuatorSettings::Scalar(scalar) => scalar.clone(),
+        ActuatorSettings::None if actuator.actuator != ActuatorType::Position => ScalarRange::default(),
+        settings => return settings.clone(),
+    };
+    ActuatorSettings::Scalar(ScalarRange {
+        factor: scalar.factor * factor,
+        ..scalar
+    })
+}
+
+/// Applied to actuators the first time they are discovered, the first matching rule wins
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TkDeviceRule {
+    /// Device name, `*` matches any sequence of characters
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub actuator_type: Option<ActuatorType>,
+    /// Index of the actuator within its device, starting at 0
+    #[serde(default)]
+    pub index: Option<u32>,
+    pub action: TkDeviceRuleAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TkDeviceRuleAction {
+    /// Enable the actuator and assign the given body parts
+    Enable(Vec<String>),
+    /// Assign the given body parts but leave the actuator disabled
+    Events(Vec<String>),
+    /// Never use, list or stop the actuator
+    Ignore,
+}
+
+impl TkDeviceRule {
+    pub fn matches(&self, actuator: &Actuator) -> bool {
+        self.name.as_ref().map(|x| matches_wildcard(x, actuator.device.name())).unwrap_or(true)
+            && self.actuator_type.map(|x| x == actuator.actuator).unwrap_or(true)
+            && self.index.map(|x| x == actuator.index_in_device).unwrap_or(true)
+    }
+
+    pub fn find<'a>(rules: &'a [TkDeviceRule], actuator: &Actuator) -> Option<&'a TkDeviceRule> {
+        rules.iter().find(|x| x.matches(actuator))
+    }
+
+    /// Settings for a newly discovered actuator, `None` if it is ignored
+    pub fn create_settings(&self, actuator: &Actuator) -> Option<TkDeviceSettings> {
+        let mut settings = TkDeviceSettings::from_actuator(actuator);
+        match &self.action {
+            TkDeviceRuleAction::Enable(events) => {
+                settings.enabled = true;
+                settings.events = sanitize_body_parts(events);
+            }
+            TkDeviceRuleAction::Events(events) => {
+                settings.events = sanitize_body_parts(events);
+            }
+            TkDeviceRuleAction::Ignore => return None,
+        }
+        Some(settings)
+    }
+}
+
+/// Maps a named game event to an action, the first matching rule wins
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TkEventRule {
+    /// Event name, `*` matches any sequence of characters
+    pub event: String,
+    pub action: TkEventAction,
+    /// Speed (0-100) at full intensity
+    #[serde(default = "default_event_speed")]
+    pub speed: i32,
+    /// Runs until stopped if not set
+    #[serde(default)]
+    pub duration_secs: Option<f32>,
+    #[serde(default)]
+    pub body_parts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TkEventAction {
+    Vibrate,
+    /// Scalar actuator type, i.e. `Oscillate`
+    Scalar(String),
+    /// Name of a vibrator pattern
+    VibratePattern(String),
+    /// Name of a linear pattern
+    LinearPattern(String),
+    LinearStroke,
+}
+
+fn default_event_speed() -> i32 {
+    100
+}
+
+impl TkEventRule {
+    pub fn find<'a>(rules: &'a [TkEventRule], event: &str) -> Option<&'a TkEventRule> {
+        rules.iter().find(|x| matches_wildcard(&x.event, event))
+    }
+
+    /// Speed of the rule scaled by the intensity (0-100) of the event
+    pub fn scaled_speed(&self, intensity: i32) -> i32 {
+        (self.speed * intensity.clamp(0, 100) / 100).clamp(0, 100)
+    }
 }
 
 impl TkSettings {
@@ -106,11 +432,38 @@ impl TkSettings {
         TkSettings {
             version: 2,
             log_level: TkLogLevel::Debug,
+            logging: TkLogSettings::default(),
             connection: TkConnectionType::InProcess,
+            websocket: TkWebSocketSettings::default(),
+            in_process: TkInProcessSettings::default(),
+            server: TkServerSettings::default(),
+            http: TkHttpSettings::default(),
+            osc: TkOscSettings::default(),
+            mqtt: TkMqttSettings::default(),
+            sync: TkSyncSettings::default(),
+            scan: TkScanSettings::default(),
+            battery: TkBatterySettings::default(),
+            sensors: TkSensorSettings::default(),
+            device_rules: vec![],
+            body_part_groups: TkBodyPartGroups::new(),
+            event_rules: vec![],
+            recorder: TkRecorderSettings::default(),
+            trace: TkTraceSettings::default(),
             devices: vec![],
             pattern_path: String::from(DEFAULT_PATTERN_PATH),
         }
     }
+    /// Copy without tokens and passwords, for settings that leave the game (http api, diagnostics)
+    pub fn redacted(&self) -> TkSettings {
+        let mut settings = self.clone();
+        if !settings.http.token.is_empty() {
+            settings.http.token = String::from(REDACTED);
+        }
+        if let TkSyncSource::Vlc { password, .. } = &mut settings.sync.source {
+            *password = String::from(REDACTED);
+        }
+        settings
+    }
     pub fn try_read_or_default(settings_path: &str, settings_file: &str) -> Self {
         let path = [settings_path, settings_file].iter().collect::<PathBuf>();
         match fs::read_to_string(path) {
@@ -245,7 +598,7 @@ impl TkSettings {
         debug!("set_events");
 
         let mut device = self.get_or_create(actuator_id);
-        device.events = sanitize_name_list(events);
+        device.events = sanitize_body_parts(events);
         self.update_device(device);
     }
 
@@ -256,6 +609,19 @@ impl TkSettings {
     pub fn get_enabled(&mut self, actuator_id: &str) -> bool {
         self.get_or_create(actuator_id).enabled
     }
+
+    #[instrument]
+    pub fn set_position(&mut self, actuator_id: &str, position: Option<TkPosition>) {
+        debug!("set_position");
+
+        let mut device = self.get_or_create(actuator_id);
+        device.position = position;
+        self.update_device(device);
+    }
+
+    pub fn get_position(&mut self, actuator_id: &str) -> Option<TkPosition> {
+        self.get_or_create(actuator_id).position
+    }
 }
 
 #[cfg(test)]
@@ -264,6 +630,19 @@ mod tests {
     use tempfile::{tempdir, TempDir};
     use tokio_test::assert_ok;
 
+    #[test]
+    fn redacted_settings_hide_secrets() {
+        let mut settings = TkSettings::default();
+        settings.http.token = String::from("http-secret");
+        settings.sync.source = TkSyncSource::Vlc {
+            url: String::from("http://127.0.0.1:8080"),
+            password: String::from("vlc-secret"),
+        };
+        let json = serde_json::to_string(&settings.redacted()).unwrap();
+        assert!(!json.contains("secret"));
+        assert!(json.contains("127.0.0.1:8080"));
+    }
+
     #[test]
     fn serialize_deserialize_works() {
         // Arrange
@@ -412,6 +791,100 @@ mod tests {
         }
     }
 
+    #[test]
+    fn in_process_defaults_to_bluetooth_only() {
+        let mut settings = TkSettings::default();
+        assert!(settings.in_process.get_manager_enabled("bluetooth"));
+        assert!(!settings.in_process.get_manager_enabled("serial_port"));
+        assert!(!settings.in_process.get_manager_enabled("xinput"));
+    }
+
+    #[test]
+    fn in_process_enable_managers() {
+        let mut settings = TkSettings::default();
+        assert!(settings.in_process.set_manager_enabled("Lovense_HID_Dongle", true));
+        assert!(settings.in_process.set_manager_enabled("bluetooth", false));
+        assert!(!settings.in_process.set_manager_enabled("bogus", true));
+        assert!(settings.in_process.lovense_hid_dongle);
+        assert!(!settings.in_process.bluetooth);
+    }
+
+    #[test]
+    fn in_process_settings_missing_in_file() {
+        let json = r#"{ "version": 2, "log_level": "Debug", "connection": "InProcess", "devices": [] }"#;
+        let settings: TkSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.in_process, TkInProcessSettings::default());
+    }
+
+    #[test]
+    fn device_rules_deserialize() {
+        let json = r#"[
+            { "name": "Lovense*", "action": { "Enable": [ "Vaginal" ] } },
+            { "actuator_type": "Rotate", "index": 1, "action": "Ignore" }
+        ]"#;
+        let rules: Vec<TkDeviceRule> = serde_json::from_str(json).unwrap();
+        assert_eq!(rules[0].name, Some(String::from("Lovense*")));
+        assert_eq!(rules[0].action, TkDeviceRuleAction::Enable(vec![String::from("Vaginal")]));
+        assert_eq!(rules[1].actuator_type, Some(ActuatorType::Rotate));
+        assert_eq!(rules[1].action, TkDeviceRuleAction::Ignore);
+    }
+
+    #[test]
+    fn event_rules_deserialize_and_match() {
+        let json = r#"[
+            { "event": "dd.vibrate.strong", "action": "Vibrate", "duration_secs": 5.0, "body_parts": [ "@genitals" ] },
+            { "event": "sexlab.stage.*", "action": { "VibratePattern": "Tease" }, "speed": 60 }
+        ]"#;
+        let rules: Vec<TkEventRule> = serde_json::from_str(json).unwrap();
+        let strong = TkEventRule::find(&rules, "DD.Vibrate.Strong").unwrap();
+        assert_eq!(strong.action, TkEventAction::Vibrate);
+        assert_eq!(strong.speed, 100);
+        assert_eq!(strong.duration_secs, Some(5.0));
+        let stage = TkEventRule::find(&rules, "sexlab.stage.3").unwrap();
+        assert_eq!(stage.action, TkEventAction::VibratePattern(String::from("Tease")));
+        assert_eq!(stage.duration_secs, None);
+        assert!(TkEventRule::find(&rules, "dd.vibrate.weak").is_none());
+    }
+
+    #[test]
+    fn event_rule_speed_scales_with_intensity() {
+        let rule = TkEventRule {
+            event: String::from("*"),
+            action: TkEventAction::Vibrate,
+            speed: 60,
+            duration_secs: None,
+            body_parts: vec![],
+        };
+        assert_eq!(rule.scaled_speed(100), 60);
+        assert_eq!(rule.scaled_speed(50), 30);
+        assert_eq!(rule.scaled_speed(500), 60);
+        assert_eq!(rule.scaled_speed(-1), 0);
+    }
+
+    #[test]
+    fn wildcard_matches() {
+        assert!(matches_wildcard("lovense*", "Lovense Lush"));
+        assert!(matches_wildcard("*lush*", "Lovense Lush 3"));
+        assert!(matches_wildcard("*", "anything"));
+        assert!(matches_wildcard("Lovense Lush", " lovense lush "));
+        assert!(!matches_wildcard("lovense*", "We-Vibe"));
+        assert!(!matches_wildcard("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn battery_settings_device_overrides() {
+        let mut settings = TkBatterySettings::default();
+        settings.devices.push(TkDeviceBatterySettings {
+            name: String::from("lovense*"),
+            poll_interval_secs: Some(60),
+            low_level: None,
+        });
+        assert_eq!(settings.poll_interval("Lovense Lush"), Duration::from_secs(60));
+        assert_eq!(settings.poll_interval("We-Vibe"), Duration::from_secs(300));
+        assert_eq!(settings.low_level("Lovense Lush"), 0.2);
+        assert_eq!(settings.min_poll_interval(), Duration::from_secs(60));
+    }
+
     fn create_temp_file(name: &str, content: &str) -> (String, TempDir) {
         let tmp_path = tempdir().unwrap();
         assert_ok!(fs::create_dir_all(tmp_path.path().to_str().unwrap()));
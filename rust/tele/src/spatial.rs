@@ -0,0 +1,284 @@
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use funscript::{FSPoint, FScript};
+use serde::{Deserialize, Serialize};
+
+use crate::pattern::{interpolate, script_length};
+
+/// Position of an actuator on the body
+/// - `x`: left (`-1.0`) to right (`1.0`)
+/// - `y`: back (`-1.0`) to front (`1.0`)
+/// - `z`: feet (`0.0`) to head (`1.0`)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct TkPosition {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl TkPosition {
+    pub fn new(x: f64, y: f64, z: f64) -> TkPosition {
+        TkPosition { x, y, z }
+    }
+
+    /// Accepts `x,y,z` coordinates or named directions (`left`, `right`,
+    /// `front`, `back`, `up`, `down`, `center`)
+    pub fn parse(input: &str) -> Result<TkPosition, String> {
+        let input = input.trim().to_lowercase();
+        let named = match input.as_str() {
+            "left" => Some(TkPosition::new(-1.0, 0.0, 0.5)),
+            "right" => Some(TkPosition::new(1.0, 0.0, 0.5)),
+            "front" => Some(TkPosition::new(0.0, 1.0, 0.5)),
+            "back" => Some(TkPosition::new(0.0, -1.0, 0.5)),
+            "up" => Some(TkPosition::new(0.0, 0.0, 1.0)),
+            "down" => Some(TkPosition::new(0.0, 0.0, 0.0)),
+            "center" => Some(TkPosition::new(0.0, 0.0, 0.5)),
+            _ => None,
+        };
+        if let Some(position) = named {
+            return Ok(position);
+        }
+        let coordinates = input
+            .split(',')
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| format!("Invalid position '{}'", input))?;
+        match coordinates[..] {
+            [x, y, z] if coordinates.iter().all(|x| x.is_finite()) => Ok(TkPosition::new(x, y, z)),
+            _ => Err(format!("Invalid position '{}', expected x,y,z", input)),
+        }
+    }
+
+    pub fn distance(&self, other: &TkPosition) -> f64 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2))
+            .sqrt()
+    }
+
+    /// Linear interpolation between `self` (`t = 0.0`) and `other` (`t = 1.0`)
+    pub fn lerp(&self, other: &TkPosition, t: f64) -> TkPosition {
+        TkPosition::new(
+            self.x + (other.x - self.x) * t,
+            self.y + (other.y - self.y) * t,
+            self.z + (other.z - self.z) * t,
+        )
+    }
+}
+
+impl Display for TkPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.x, self.y, self.z)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TkSpatialEffect {
+    /// Actuators close to the target are driven strongest
+    Direction { target: TkPosition, radius: f64 },
+    /// The hit point moves from `from` to `to` once per `period`
+    Sweep {
+        from: TkPosition,
+        to: TkPosition,
+        radius: f64,
+        period: Duration,
+    },
+}
+
+pub static DEFAULT_RADIUS: f64 = 1.0;
+/// Longest script created by `modulated_pattern`
+static MAX_MODULATED_MS: u64 = 60_000;
+pub static DEFAULT_SWEEP_PERIOD: Duration = Duration::from_secs(2);
+static SWEEP_RESOLUTION_MS: u64 = 100;
+
+impl TkSpatialEffect {
+    /// Parses `left` or `x,y,z` directions
+    pub fn parse_direction(input: &str) -> Result<TkSpatialEffect, String> {
+        Ok(TkSpatialEffect::Direction {
+            target: TkPosition::parse(input)?,
+            radius: DEFAULT_RADIUS,
+        })
+    }
+
+    /// Parses sweeps like `left>right` or `0,1,0.5>0,-1,0.5`
+    pub fn parse_sweep(input: &str) -> Result<TkSpatialEffect, String> {
+        let (from, to) = input
+            .split_once('>')
+            .ok_or_else(|| format!("Invalid sweep '{}', expected from>to", input))?;
+        Ok(TkSpatialEffect::Sweep {
+            from: TkPosition::parse(from)?,
+            to: TkPosition::parse(to)?,
+            radius: DEFAULT_RADIUS,
+            period: DEFAULT_SWEEP_PERIOD,
+        })
+    }
+
+    /// Intensity (`0.0 - 1.0`) of a static direction, sweeps are `None`
+    pub fn intensity(&self, position: &TkPosition) -> Option<f64> {
+        match self {
+            TkSpatialEffect::Direction { target, radius } => {
+                Some(intensity(position, target, *radius))
+            }
+            TkSpatialEffect::Sweep { .. } => None,
+        }
+    }
+
+    /// Vibration pattern of one sweep period for an actuator at `position`
+    pub fn pattern(&self, position: &TkPosition) -> FScript {
+        let (from, to, radius, period) = match self {
+            TkSpatialEffect::Direction { target, radius } => {
+                (*target, *target, *radius, DEFAULT_SWEEP_PERIOD)
+            }
+            TkSpatialEffect::Sweep {
+                from,
+                to,
+                radius,
+                period,
+            } => (*from, *to, *radius, *period),
+        };
+        let period_ms = (period.as_millis() as u64).max(SWEEP_RESOLUTION_MS);
+        let actions = (0..=period_ms / SWEEP_RESOLUTION_MS)
+            .map(|step| {
+                let at = step * SWEEP_RESOLUTION_MS;
+                let hit = from.lerp(&to, at as f64 / period_ms as f64);
+                FSPoint {
+                    pos: (intensity(position, &hit, radius) * 100.0).round() as i32,
+                    at: at as i32,
+                }
+            })
+            .collect();
+        FScript {
+            actions,
+            ..Default::default()
+        }
+    }
+
+    /// The vibration pattern `base` with the sweep applied to it, both repeat until
+    /// they end at the same time (or after `MAX_MODULATED_MS`)
+    pub fn modulated_pattern(&self, position: &TkPosition, base: &FScript) -> FScript {
+        let envelope = self.pattern(position);
+        let base_ms = script_length(base).max(1);
+        let envelope_ms = script_length(&envelope).max(1);
+        let length = (base_ms / gcd(base_ms, envelope_ms) * envelope_ms)
+            .min(MAX_MODULATED_MS.max(base_ms).max(envelope_ms));
+        let mut times = BTreeSet::from([length]);
+        for (script, script_ms) in [(base, base_ms), (&envelope, envelope_ms)] {
+            for offset in (0..length).step_by(script_ms as usize) {
+                times.extend(
+                    script
+                        .actions
+                        .iter()
+                        .map(|x| offset + x.at.max(0) as u64)
+                        .filter(|x| *x <= length),
+                );
+            }
+        }
+        // the end of a cycle is played at the end of the script, not its start
+        let cycle_at = |at: u64, script_ms: u64| match at % script_ms {
+            0 if at > 0 => script_ms as i32,
+            at => at as i32,
+        };
+        let actions = times
+            .into_iter()
+            .map(|at| FSPoint {
+                pos: interpolate(&base.actions, cycle_at(at, base_ms))
+                    * interpolate(&envelope.actions, cycle_at(at, envelope_ms))
+                    / 100,
+                at: at as i32,
+            })
+            .collect();
+        FScript {
+            actions,
+            ..Default::default()
+        }
+    }
+}
+
+/// Greatest common divisor of two script lengths
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+/// Falls off linearly with the distance to the hit point
+fn intensity(position: &TkPosition, hit: &TkPosition, radius: f64) -> f64 {
+    if radius <= 0.0 {
+        return 0.0;
+    }
+    (1.0 - position.distance(hit) / radius).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_named_and_coordinates() {
+        assert_eq!(
+            TkPosition::parse(" Left ").unwrap(),
+            TkPosition::new(-1.0, 0.0, 0.5)
+        );
+        assert_eq!(
+            TkPosition::parse("0.5, -1, 0").unwrap(),
+            TkPosition::new(0.5, -1.0, 0.0)
+        );
+        assert!(TkPosition::parse("1,2").is_err());
+        assert!(TkPosition::parse("sideways").is_err());
+    }
+
+    #[test]
+    fn direction_intensity_falls_off() {
+        let effect = TkSpatialEffect::parse_direction("left").unwrap();
+        let left = TkPosition::parse("left").unwrap();
+        let right = TkPosition::parse("right").unwrap();
+        assert_eq!(effect.intensity(&left), Some(1.0));
+        assert_eq!(effect.intensity(&right), Some(0.0));
+        assert_eq!(
+            effect.intensity(&TkPosition::parse("center").unwrap()),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn sweep_moves_across_actuators() {
+        let effect = TkSpatialEffect::parse_sweep("left>right").unwrap();
+        let left = effect.pattern(&TkPosition::parse("left").unwrap());
+        let right = effect.pattern(&TkPosition::parse("right").unwrap());
+        assert_eq!(left.actions.first().unwrap().pos, 100);
+        assert_eq!(left.actions.last().unwrap().pos, 0);
+        assert_eq!(right.actions.first().unwrap().pos, 0);
+        assert_eq!(right.actions.last().unwrap().pos, 100);
+        assert_eq!(right.actions.last().unwrap().at, 2000);
+    }
+
+    #[test]
+    fn sweep_keeps_the_pattern() {
+        let effect = TkSpatialEffect::parse_sweep("left>right").unwrap();
+        let base = FScript {
+            actions: vec![
+                FSPoint { pos: 100, at: 0 },
+                FSPoint { pos: 0, at: 250 },
+                FSPoint { pos: 100, at: 500 },
+            ],
+            ..Default::default()
+        };
+        let left = effect.modulated_pattern(&TkPosition::parse("left").unwrap(), &base);
+        let pos = |at: i32| left.actions.iter().find(|x| x.at == at).unwrap().pos;
+        assert_eq!(script_length(&left), 2000);
+        assert_eq!(pos(0), 100);
+        assert_eq!(pos(250), 0);
+        assert_eq!(pos(500), 50);
+        assert_eq!(pos(2000), 0);
+    }
+
+    #[test]
+    fn sweep_requires_two_positions() {
+        assert!(TkSpatialEffect::parse_sweep("left").is_err());
+        assert!(TkSpatialEffect::parse_sweep("left>nowhere").is_err());
+    }
+}
@@ -1,30 +1,75 @@
 use std::{
+    collections::HashMap,
     fmt::{self, Display},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use crossbeam_channel::Receiver;
 use itertools::Itertools;
-use tracing::debug;
+use tracing::{debug, info};
 
 use buttplug::client::ButtplugClientDevice;
 
 use bp_scheduler::actuator::{get_actuators, Actuator};
 
-use crate::{connection::TkConnectionEvent, settings::TkSettings};
+use crate::{
+    connection::TkConnectionEvent,
+    sensor::TkSensorType,
+    settings::{TkDeviceRule, TkDeviceSettings, TkSettings},
+};
+
+static BATTERY_HISTORY_SIZE: usize = 64;
 
 /// Its actually device status but this makes it easier to housekeep
 #[derive(Clone, Debug)]
 pub struct ActuatorStatus {
     pub actuator: Arc<Actuator>,
     pub connection_status: TkConnectionStatus,
-    pub battery_level: Option<f64>
+    pub battery_level: Option<f64>,
+    pub battery_history: Vec<(Instant, f64)>,
+}
+
+impl ActuatorStatus {
+    /// Battery change in percent per hour
+    pub fn battery_trend(&self) -> Option<f64> {
+        battery_trend(&self.battery_history)
+    }
+
+    /// Estimated time until the battery is empty, if it is discharging
+    pub fn battery_remaining(&self) -> Option<Duration> {
+        battery_remaining(&self.battery_history)
+    }
+}
+
+fn battery_trend(history: &[(Instant, f64)]) -> Option<f64> {
+    let (first, last) = (history.first()?, history.last()?);
+    let hours = last.0.duration_since(first.0).as_secs_f64() / 3600.0;
+    if hours <= 0.0 {
+        return None;
+    }
+    Some((last.1 - first.1) * 100.0 / hours)
+}
+
+fn battery_remaining(history: &[(Instant, f64)]) -> Option<Duration> {
+    let trend = battery_trend(history)?;
+    let (_, level) = history.last()?;
+    if trend >= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(level * 100.0 / -trend * 3600.0))
 }
 
 pub struct Status {
     status_events: Receiver<TkConnectionEvent>,
     connection: TkConnectionStatus,
+    scanning: bool,
     actuators: Vec<ActuatorStatus>,
-    known_actuators: Vec<String>
+    known_actuators: Vec<String>,
+    device_rules: Vec<TkDeviceRule>,
+    ignored_actuators: Vec<Arc<Actuator>>,
+    discovered_settings: Vec<TkDeviceSettings>,
+    /// Latest reading by device and sensor index
+    sensors: HashMap<(u32, u32), (TkSensorType, i32)>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -39,12 +84,17 @@ impl Status {
         Status {
             status_events: receiver,
             connection: TkConnectionStatus::NotConnected,
+            scanning: false,
             actuators: vec![],
             known_actuators: settings
                 .devices
                 .iter()
                 .map(|x| x.actuator_id.clone())
                 .collect(),
+            device_rules: settings.device_rules.clone(),
+            ignored_actuators: vec![],
+            discovered_settings: vec![],
+            sensors: HashMap::new(),
         }
     }
 
@@ -53,6 +103,11 @@ impl Status {
         self.connection.clone()
     }
 
+    pub fn is_scanning(&mut self) -> bool {
+        self.process_status_events();
+        self.scanning
+    }
+
     pub fn actuators(&mut self) -> Vec<Arc<Actuator>> {
         self.process_status_events();
         self.actuators.iter().map(|x| x.actuator.clone()).collect()
@@ -95,14 +150,39 @@ impl Status {
 
     pub fn get_known_actuator_ids(&mut self) -> Vec<String> {
         let known_ids = self.known_actuators.clone();
+        let ignored = self.ignored_actuators();
         self.actuators()
             .iter()
             .map(|x| String::from(x.identifier()))
             .chain(known_ids)
+            .filter(|x| !ignored.iter().any(|y| y.identifier() == x))
             .unique()
             .collect()
     }
 
+    /// Latest reading of a sensor on the device of the given actuator, the
+    /// first sensor of that type if the device has several
+    pub fn get_sensor(&mut self, actuator_id: &str, sensor: TkSensorType) -> Option<i32> {
+        let device_index = self.get_actuator(actuator_id)?.device.index();
+        self.sensors
+            .iter()
+            .filter(|((device, _), (sensor_type, _))| *device == device_index && *sensor_type == sensor)
+            .min_by_key(|((_, index), _)| *index)
+            .map(|(_, (_, value))| *value)
+    }
+
+    /// Actuators that were excluded by an `Ignore` device rule
+    pub fn ignored_actuators(&mut self) -> Vec<Arc<Actuator>> {
+        self.process_status_events();
+        self.ignored_actuators.clone()
+    }
+
+    /// Settings created by device rules for actuators that were seen for the first time
+    pub fn take_discovered_settings(&mut self) -> Vec<TkDeviceSettings> {
+        self.process_status_events();
+        self.discovered_settings.drain(..).collect()
+    }
+
     pub fn process_status_events(&mut self) {
         while let Ok(evt) = self.status_events.try_recv() {
             debug!("processing status event {:?}", evt);
@@ -111,7 +191,10 @@ impl Status {
                 TkConnectionEvent::ConnectionFailure(err) => {
                     self.connection = TkConnectionStatus::Failed(err)
                 }
+                TkConnectionEvent::ScanStarted => self.scanning = true,
+                TkConnectionEvent::ScanFinished => self.scanning = false,
                 TkConnectionEvent::DeviceAdded(device, battery_level) => {
+                    self.apply_device_rules(&device);
                     self.set_status(device.clone(), TkConnectionStatus::Connected, battery_level);
                 }
                 TkConnectionEvent::BatteryLevel(device, battery_level) => {
@@ -123,19 +206,65 @@ impl Status {
                 TkConnectionEvent::ActionError(actuator, err) => {
                     self.set_status(actuator.device.clone(), TkConnectionStatus::Failed(err), None);
                 }
+                TkConnectionEvent::BatteryLow(_, _) => {}
+                TkConnectionEvent::SensorReading(device, sensor, index, value) => {
+                    self.sensors.insert((device.index(), index), (sensor, value));
+                }
+                TkConnectionEvent::SensorThreshold(_, _, _, _) => {}
+                TkConnectionEvent::ButtonPressed(_, _) => {}
                 TkConnectionEvent::ActionStarted(_, _, _, _) => {}
                 TkConnectionEvent::ActionDone(_, _, _) => {}
             };
         }
     }
 
+    fn apply_device_rules(&mut self, device: &Arc<ButtplugClientDevice>) {
+        for actuator in get_actuators(vec![device.clone()]) {
+            let actuator_id = actuator.identifier().to_owned();
+            let Some(rule) = TkDeviceRule::find(&self.device_rules, &actuator) else {
+                continue;
+            };
+            match rule.create_settings(&actuator) {
+                None => {
+                    info!(actuator_id, "ignoring actuator");
+                    self.ignored_actuators.retain(|x| x.identifier() != actuator_id);
+                    self.ignored_actuators.push(actuator);
+                }
+                Some(settings) => {
+                    let is_new = !self.known_actuators.contains(&actuator_id)
+                        && !self.actuators.iter().any(|x| x.actuator.identifier() == actuator_id);
+                    if is_new {
+                        info!(?settings, "device rule applied");
+                        self.known_actuators.push(actuator_id);
+                        self.discovered_settings.push(settings);
+                    }
+                }
+            }
+        }
+    }
+
     fn set_status(&mut self, device: Arc<ButtplugClientDevice>, connection_status: TkConnectionStatus, battery_level: Option<f64>) {
+        let ignored = self.ignored_actuators.clone();
+        let mut battery_history = self
+            .actuators
+            .iter()
+            .find(|x| x.actuator.device.index() == device.index())
+            .map(|x| x.battery_history.clone())
+            .unwrap_or_default();
+        if let Some(level) = battery_level {
+            battery_history.push((Instant::now(), level));
+            if battery_history.len() > BATTERY_HISTORY_SIZE {
+                battery_history.remove(0);
+            }
+        }
         let new_actuators = get_actuators(vec![device.clone()])
             .into_iter()
+            .filter(|x| !ignored.iter().any(|y| y.identifier() == x.identifier()))
             .map(|actuator| ActuatorStatus { 
                 actuator, 
                 connection_status: connection_status.clone(), 
-                battery_level
+                battery_level,
+                battery_history: battery_history.clone()
             });
         self.actuators = self
             .actuators
@@ -157,3 +286,35 @@ impl Display for TkConnectionStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn battery_trend_discharging() {
+        let start = Instant::now();
+        let history = vec![
+            (start, 0.8),
+            (start + Duration::from_secs(1800), 0.7),
+            (start + Duration::from_secs(3600), 0.6),
+        ];
+        let trend = battery_trend(&history).unwrap();
+        assert!((trend + 20.0).abs() < 0.001);
+        let remaining = battery_remaining(&history).unwrap();
+        assert!((remaining.as_secs_f64() - 3.0 * 3600.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn battery_trend_needs_history() {
+        assert_eq!(battery_trend(&[]), None);
+        assert_eq!(battery_trend(&[(Instant::now(), 0.5)]), None);
+    }
+
+    #[test]
+    fn battery_remaining_when_charging_is_none() {
+        let start = Instant::now();
+        let history = vec![(start, 0.5), (start + Duration::from_secs(600), 0.6)];
+        assert_eq!(battery_remaining(&history), None);
+    }
+}
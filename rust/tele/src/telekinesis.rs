@@ -2,40 +2,66 @@ use anyhow::Error;
 use anyhow::anyhow;
 
 use std::{
+    collections::HashMap,
     fmt::{self},
-    time::Instant,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use futures::Future;
+use futures::{future::join_all, Future};
+use funscript::FScript;
 use tracing::{debug, error, info};
 
 use tokio::sync::mpsc::Sender;
-use tokio::{runtime::Runtime, sync::mpsc::channel};
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::{broadcast, mpsc::channel},
+};
 
 use buttplug::{
     client::ButtplugClient,
     core::{
-        connector::{
-            new_json_ws_client_connector, ButtplugConnector,
-            ButtplugInProcessClientConnectorBuilder,
-        },
-        message::{ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage},
+        connector::{ButtplugConnector, ButtplugInProcessClientConnectorBuilder},
+        message::{ActuatorType, ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage},
     },
     server::{
-        device::hardware::communication::btleplug::BtlePlugCommunicationManagerBuilder,
-        ButtplugServerBuilder,
+        device::hardware::communication::{
+            btleplug::BtlePlugCommunicationManagerBuilder,
+            lovense_dongle::{
+                LovenseHIDDongleCommunicationManagerBuilder,
+                LovenseSerialDongleCommunicationManagerBuilder,
+            },
+            serialport::SerialPortCommunicationManagerBuilder,
+        },
+        ButtplugServer, ButtplugServerBuilder,
     },
 };
 
+#[cfg(target_os = "windows")]
+use buttplug::server::device::hardware::communication::xinput::XInputDeviceCommunicationManagerBuilder;
+
+use bp_scheduler::actuator::Actuator;
 use bp_scheduler::settings::*;
 use bp_scheduler::speed::*;
 use bp_scheduler::*;
 
 use crate::{
+    body_parts::TkBodyPartExpr,
     connection::*,
+    export::*,
+    audio::{audio_duration, get_audio_pattern_name, load_audio_pattern},
+    pattern::read_pattern_playback,
+    player_sync::TkPlayerSync,
+    spatial::TkSpatialEffect,
     settings::*,
     input::*,
-    status::*
+    metrics::*,
+    server::*,
+    status::*,
+    trace::*,
+    websocket::*
 };
 
 #[cfg(feature = "testing")]
@@ -43,13 +69,29 @@ use bp_fakes::FakeDeviceConnector;
 
 pub static ERROR_HANDLE: i32 = -1;
 
+/// Produces the pattern of a task that is too slow to read on the game thread
+type TkPatternLoader = Box<dyn FnOnce() -> Result<FScript, String> + Send>;
+
 pub struct Telekinesis {
     pub settings: TkSettings,
     pub connection_events: crossbeam_channel::Receiver<TkConnectionEvent>,
     pub status: Status,
+    /// Commands that were sent to the devices, empty unless `settings.trace` is enabled
+    pub trace: TkOutputTrace,
+    /// Funscript that follows an external media player
+    pub sync: TkPlayerSync,
     runtime: Runtime,
     command_sender: Sender<ConnectionCommand>,
     scheduler: ButtplugScheduler,
+    device_access: TkDeviceAccess,
+    /// Device, battery and task events for integrations (OSC, MQTT)
+    events: broadcast::Sender<TkConnectionEvent>,
+    /// Effects that run on several players (sweeps) are controlled through the first handle
+    linked_handles: Arc<Mutex<HashMap<i32, Vec<i32>>>>,
+    /// Enabled actuators of the current settings, missing ones are rescanned
+    enabled_actuator_ids: Arc<Mutex<Vec<String>>>,
+    /// Recent tasks that can be exported as patterns
+    task_history: TkTaskHistory,
     client_event_sender: crossbeam_channel::Sender<TkConnectionEvent>,
     status_event_sender: crossbeam_channel::Sender<TkConnectionEvent>,
 }
@@ -65,6 +107,26 @@ impl Telekinesis {
         Fut: Future<Output = T> + Send,
         T: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
             + 'static,
+    {
+        Telekinesis::try_connect_with(
+            || async move { Ok(connect_action().await) },
+            provided_settings,
+            type_name,
+        )
+    }
+
+    /// Like `connect_with` but the connector creation may fail, which
+    /// is reported as `TkConnectionEvent::ConnectionFailure`
+    pub fn try_connect_with<T, Fn, Fut>(
+        connect_action: Fn,
+        provided_settings: Option<TkSettings>,
+        type_name: TkConnectionType,
+    ) -> Result<Telekinesis, anyhow::Error>
+    where
+        Fn: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, String>> + Send,
+        T: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+            + 'static,
     {
         let settings = provided_settings.unwrap_or_else(TkSettings::default);
         let (event_sender_client, event_receiver) = crossbeam_channel::unbounded();
@@ -74,8 +136,23 @@ impl Telekinesis {
             scalar_resolution_ms: 100,
         });
 
+        let device_access = TkDeviceAccess::default();
+        let access = device_access.clone();
+        let server_settings = settings.server.clone();
+        let connection_settings = settings.clone();
+        let enabled_actuator_ids = Arc::new(Mutex::new(get_enabled_actuator_ids(&settings)));
+        let connection_enabled_ids = enabled_actuator_ids.clone();
+        let trace = TkOutputTrace::new(&settings.trace);
+        let connector_trace = trace.clone();
+        let (events, _) = broadcast::channel(256);
+        let connection_events = events.clone();
         let telekinesis = Telekinesis {
             command_sender: command_sender.clone(),
+            device_access,
+            linked_handles: Arc::new(Mutex::new(HashMap::new())),
+            enabled_actuator_ids,
+            task_history: TkTaskHistory::default(),
+            events,
             connection_events: event_receiver,
             runtime: Runtime::new()?,
             settings: settings.clone(),
@@ -83,17 +160,45 @@ impl Telekinesis {
             client_event_sender: event_sender_client.clone(),
             status_event_sender: event_sender_internal.clone(),
             status: Status::new(event_receiver_internal, &settings),
+            trace,
+            sync: TkPlayerSync::default(),
         };
         info!(?telekinesis, "connecting...");    
         telekinesis.runtime.spawn(async move {
-            let client = with_connector(connect_action().await).await;
+            let connector = match connect_action().await {
+                Ok(connector) => connector,
+                Err(err) => {
+                    error!("Could not create connector. Error: {}.", err);
+                    let failure = TkConnectionEvent::ConnectionFailure(err);
+                    let _ = event_sender_internal.send(failure.clone());
+                    let _ = event_sender_client.send(failure);
+                    return;
+                }
+            };
+            let connector = TkTracingConnector::new(connector, connector_trace);
+            let client = Arc::new(match with_connector(connector).await {
+                Ok(client) => client,
+                Err((client, err)) => {
+                    let failure = TkConnectionEvent::ConnectionFailure(
+                        format!("Could not connect to {}: {}", type_name, err));
+                    let _ = event_sender_internal.send(failure.clone());
+                    let _ = event_sender_client.send(failure);
+                    client
+                }
+            });
+            if server_settings.enabled {
+                Handle::current().spawn(run_server(server_settings, client.clone(), access));
+            }
             handle_connection(
                 event_sender_client,
                 event_sender_internal,
+                connection_events,
                 command_sender,
                 command_receiver,
                 client,
                 type_name,
+                connection_settings,
+                connection_enabled_ids,
             )
             .await;
             debug!("connection handling stopped");
@@ -126,15 +231,16 @@ impl Telekinesis {
         let settings_clone = settings.clone();
         match settings.connection {
             TkConnectionType::WebSocket(endpoint) => {
-                let uri = format!("ws://{}", endpoint);
-                Telekinesis::connect_with(
-                    || async move { new_json_ws_client_connector(&uri) },
+                let uri = endpoint.clone();
+                let websocket = settings.websocket.clone();
+                Telekinesis::try_connect_with(
+                    || async move { TkWebSocketEndpoint::connect(&uri, &websocket).await },
                     Some(settings_clone),
                     TkConnectionType::WebSocket(endpoint),
                 )
             }
-            TkConnectionType::InProcess => Telekinesis::connect_with(
-                || async move { in_process_connector() },
+            TkConnectionType::InProcess => Telekinesis::try_connect_with(
+                || async move { in_process_connector(&settings_clone.in_process) },
                 Some(settings),
                 TkConnectionType::InProcess,
             ),
@@ -165,25 +271,112 @@ impl Telekinesis {
     pub fn update(&mut self, handle: i32, speed: Speed) -> bool {
         info!("update");
         self.scheduler.clean_finished_tasks();
-        self.scheduler.update_task(handle, speed)
+        let mut updated = self.scheduler.update_task(handle, speed);
+        for linked in self.get_linked_handles(handle, false) {
+            updated = self.scheduler.update_task(linked, speed) || updated;
+        }
+        updated
     }
 
     pub fn stop(&mut self, handle: i32) -> bool {
         info!("stop");
         self.scheduler.stop_task(handle);
+        for linked in self.get_linked_handles(handle, true) {
+            self.scheduler.stop_task(linked);
+        }
         true
     }
 
+    fn get_linked_handles(&self, handle: i32, remove: bool) -> Vec<i32> {
+        match self.linked_handles.lock() {
+            Ok(mut linked) if remove => linked.remove(&handle).unwrap_or_default(),
+            Ok(linked) => linked.get(&handle).cloned().unwrap_or_default(),
+            Err(_) => vec![],
+        }
+    }
+
     pub fn stop_all(&mut self) -> bool {
         info!("stop all");
         self.scheduler.stop_all();
-        if self.command_sender.try_send(ConnectionCommand::StopAll).is_err() {
+        let ignored = self.status.ignored_actuators();
+        if self.command_sender.try_send(ConnectionCommand::StopAll(ignored)).is_err() {
             error!("Failed to queue stop_all");
             return false;
         }
         true
     }
 
+    /// Stores the settings that device rules created for newly discovered actuators
+    pub fn process_status(&mut self) {
+        for settings in self.status.take_discovered_settings() {
+            if self.settings.get_device(&settings.actuator_id).is_none() {
+                self.settings.update_device(settings);
+            }
+        }
+        if let Ok(mut ids) = self.enabled_actuator_ids.lock() {
+            *ids = get_enabled_actuator_ids(&self.settings);
+        }
+    }
+
+    /// Traced commands of the actuator, `None` if it is not connected
+    pub fn get_trace(&mut self, actuator_id: &str) -> Option<Vec<TkTraceEntry>> {
+        let actuator = self.status.get_actuator(actuator_id)?;
+        Some(self.trace.get_entries(
+            actuator.device.index(),
+            actuator.index_in_device,
+            actuator.actuator == ActuatorType::Position,
+        ))
+    }
+
+    /// Writes the output of a recently started task into the pattern folder
+    pub fn export_task(&self, handle: i32, pattern_name: &str) -> Result<PathBuf, String> {
+        let cmd = self
+            .task_history
+            .get(handle)
+            .ok_or_else(|| format!("Task {} is unknown", handle))?;
+        export_task(&self.settings.pattern_path, pattern_name, cmd)
+    }
+
+    /// Writes the traced output of the actuator into the pattern folder
+    pub fn export_output(&mut self, actuator_id: &str, pattern_name: &str) -> Result<PathBuf, String> {
+        let entries = self
+            .get_trace(actuator_id)
+            .ok_or_else(|| format!("Actuator '{}' is not connected", actuator_id))?;
+        let linear = self
+            .status
+            .get_actuator(actuator_id)
+            .map(|x| x.actuator == ActuatorType::Position)
+            .unwrap_or(false);
+        export_output(&self.settings.pattern_path, pattern_name, &entries, linear)
+    }
+
+    /// Actuators used by game tasks and external clients of the server
+    pub fn device_access(&self) -> TkDeviceAccess {
+        self.device_access.clone()
+    }
+
+    /// Handles and actuators of the running tasks
+    pub fn active_tasks(&self) -> Vec<(i32, Vec<String>)> {
+        self.device_access.game_tasks()
+    }
+
+    /// Connection commands that were queued but not executed yet
+    pub fn pending_commands(&self) -> usize {
+        self.command_sender.max_capacity() - self.command_sender.capacity()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<TkConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Runs `future` on the runtime of this connection, it is cancelled on disconnect
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime.spawn(future);
+    }
+
     pub fn disconnect(&mut self) {
         info!("disconnect");
         if self.command_sender.try_send(ConnectionCommand::Disconect).is_err() {
@@ -192,32 +385,339 @@ impl Telekinesis {
     }
 
     pub fn dispatch_cmd(&mut self, cmd: DeviceCommand) -> i32 {
+        self.dispatch_loaded(cmd, None)
+    }
+
+    /// Vibrates along with an audio file, the handle is returned right away and the
+    /// audio is analyzed before the task starts (see `TkAudioAnalysis`)
+    pub fn dispatch_audio(&mut self, mut cmd: DeviceCommand, audio_input: &str) -> i32 {
+        let pattern_path = self.settings.pattern_path.clone();
+        let input = audio_input.to_owned();
+        cmd.fscript = None;
+        self.dispatch_loaded(
+            cmd,
+            Some(Box::new(move || load_audio_pattern(&pattern_path, &input))),
+        )
+    }
+
+    /// Generates the pattern of an audio file in the background, so that
+    /// `dispatch_audio` can start without delay
+    pub fn prepare_audio(&self, audio_input: &str) -> bool {
+        if let Err(err) = get_audio_pattern_name(audio_input) {
+            error!("{}", err);
+            return false;
+        }
+        let pattern_path = self.settings.pattern_path.clone();
+        let input = audio_input.to_owned();
+        self.runtime.spawn_blocking(move || {
+            if let Err(err) = load_audio_pattern(&pattern_path, &input) {
+                error!("{}", err);
+            }
+        });
+        true
+    }
+
+    fn dispatch_loaded(&mut self, cmd: DeviceCommand, load: Option<TkPatternLoader>) -> i32 {
         self.scheduler.clean_finished_tasks();
-        let task_clone = cmd.task.clone();
+        self.process_status();
+        let Some(body_parts) = parse_body_parts(&cmd.body_parts) else {
+            return ERROR_HANDLE;
+        };
+        let devices = self.select_devices(&body_parts, &cmd.actuator_types);
+        let settings = devices
+            .iter()
+            .map(|x| {
+                self.settings
+                    .get_or_create(x.identifier())
+                    .weighted_actuator_settings(x, &body_parts, &self.settings.body_part_groups)
+            })
+            .collect();
+        self.play_loaded(devices, settings, cmd, load)
+    }
+
+    /// Runs the action of the first event rule that matches `event`, the rule
+    /// speed is scaled by `intensity` (0-100). Body parts and duration of the
+    /// rule are used unless they are given explicitly
+    pub fn fire_event(&mut self, event: &str, intensity: i32, time_sec: f32, body_parts: Vec<String>) -> i32 {
+        let Some(rule) = TkEventRule::find(&self.settings.event_rules, event).cloned() else {
+            info!(event, "no event rule");
+            return ERROR_HANDLE;
+        };
+        debug!(event, ?rule, intensity, "firing event");
+        let speed = Speed::new(rule.scaled_speed(intensity).into());
+        let duration = match rule.duration_secs {
+            Some(secs) if time_sec <= 0.0 => get_duration_from_secs(secs),
+            _ => get_duration_from_secs(time_sec),
+        };
+        let body_parts = if body_parts.is_empty() { rule.body_parts.clone() } else { body_parts };
+        self.dispatch_action(&rule.action, speed, duration, body_parts)
+    }
+
+    /// Starts the task that corresponds to the action of an event rule
+    pub fn dispatch_action(
+        &mut self,
+        action: &TkEventAction,
+        speed: Speed,
+        duration: Duration,
+        body_parts: Vec<String>,
+    ) -> i32 {
+        let (task, actuator_types, fscript, duration) = match action {
+            TkEventAction::Vibrate => (Task::Scalar(speed), vec![ActuatorType::Vibrate], None, duration),
+            TkEventAction::Scalar(actuator) => {
+                (Task::Scalar(speed), vec![read_scalar_actuator(actuator)], None, duration)
+            }
+            TkEventAction::VibratePattern(pattern) => {
+                let Some((fscript, duration)) =
+                    read_pattern_playback(&self.settings.pattern_path, pattern, true, duration) else {
+                    return ERROR_HANDLE;
+                };
+                (
+                    Task::Pattern(speed, ActuatorType::Vibrate, pattern.clone()),
+                    vec![ActuatorType::Vibrate],
+                    Some(fscript),
+                    duration,
+                )
+            }
+            TkEventAction::LinearPattern(pattern) => {
+                let Some((fscript, duration)) =
+                    read_pattern_playback(&self.settings.pattern_path, pattern, false, duration) else {
+                    return ERROR_HANDLE;
+                };
+                (
+                    Task::Linear(speed, pattern.clone()),
+                    vec![ActuatorType::Position],
+                    Some(fscript),
+                    duration,
+                )
+            }
+            TkEventAction::LinearStroke => (
+                Task::LinearStroke(speed, String::new()),
+                vec![ActuatorType::Position],
+                None,
+                duration,
+            ),
+        };
+        self.dispatch_cmd(DeviceCommand {
+            task,
+            duration,
+            fscript,
+            body_parts,
+            actuator_types,
+        })
+    }
+
+    /// Like `dispatch_cmd` but the speed of each actuator depends on its distance
+    /// to the effect, actuators without a position are not used
+    pub fn dispatch_spatial(&mut self, cmd: DeviceCommand, effect: TkSpatialEffect) -> i32 {
+        self.scheduler.clean_finished_tasks();
+        self.process_status();
+        let Some(body_parts) = parse_body_parts(&cmd.body_parts) else {
+            return ERROR_HANDLE;
+        };
+        let positioned = self
+            .select_devices(&body_parts, &cmd.actuator_types)
+            .into_iter()
+            .filter_map(|actuator| {
+                let device = self.settings.get_or_create(actuator.identifier());
+                let settings = device.weighted_actuator_settings(
+                    &actuator,
+                    &body_parts,
+                    &self.settings.body_part_groups,
+                );
+                device.position.map(|position| (actuator, settings, position))
+            })
+            .collect::<Vec<_>>();
+        debug!(?effect, positioned = positioned.len(), "spatial effect");
+
+        let speed = match &cmd.task {
+            Task::Scalar(speed) | Task::Pattern(speed, _, _) => *speed,
+            _ => return self.play(vec![], vec![], cmd),
+        };
+        if positioned.iter().all(|(_, _, position)| effect.intensity(position).is_some()) {
+            let (devices, settings) = positioned
+                .into_iter()
+                .filter_map(|(actuator, settings, position)| {
+                    let intensity = effect.intensity(&position).unwrap_or(0.0);
+                    (intensity > 0.0).then(|| {
+                        let scaled = scale_actuator_settings(&settings, &actuator, intensity);
+                        (actuator, scaled)
+                    })
+                })
+                .unzip();
+            return self.play(devices, settings, cmd);
+        }
+
+        let parts = positioned
+            .into_iter()
+            .map(|(actuator, settings, position)| {
+                let fscript = match &cmd.fscript {
+                    Some(pattern) => effect.modulated_pattern(&position, pattern),
+                    None => effect.pattern(&position),
+                };
+                (actuator, settings, fscript)
+            })
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            return self.play(vec![], vec![], cmd);
+        }
+        self.play_linked(parts, speed, cmd)
+    }
+
+    /// Plays a pattern on each actuator, controlled through the handle of the first one.
+    /// Events are only sent for that handle, once all patterns are done
+    fn play_linked(
+        &mut self,
+        parts: Vec<(Arc<Actuator>, ActuatorSettings, FScript)>,
+        speed: Speed,
+        cmd: DeviceCommand,
+    ) -> i32 {
+        let players = parts
+            .into_iter()
+            .map(|(actuator, settings, fscript)| {
+                let player = self
+                    .scheduler
+                    .create_player_with_settings(vec![actuator], vec![settings]);
+                self.device_access.game_started(
+                    player.handle,
+                    player.actuators.iter().map(|x| x.identifier().to_owned()).collect(),
+                );
+                self.trace.dispatched(player.handle, get_output_keys(&player.actuators));
+                (player, fscript)
+            })
+            .collect::<Vec<_>>();
+        let handles = players.iter().map(|(player, _)| player.handle).collect::<Vec<i32>>();
+        let handle = handles[0];
+        self.task_history.push(handle, &cmd);
+        if let Ok(mut linked) = self.linked_handles.lock() {
+            linked.insert(handle, handles[1..].to_vec());
+        }
+
+        info!(handle, linked = ?&handles[1..], "dispatching {:?}", cmd.task);
+        let client_sender_clone = self.client_event_sender.clone();
+        let status_sender_clone = self.status_event_sender.clone();
+        let access = self.device_access.clone();
+        let task_events = self.events.clone();
+        let linked_handles = self.linked_handles.clone();
+        let trace = self.trace.clone();
+        self.runtime.spawn(async move {
+            let now = Instant::now();
+            let started = TkConnectionEvent::ActionStarted(
+                cmd.task.clone(),
+                players.iter().flat_map(|(player, _)| player.actuators.clone()).collect(),
+                cmd.body_parts.clone(),
+                handle,
+            );
+            inc_counter(TASKS_STARTED, &[]);
+            let _ = task_events.send(started.clone());
+            client_sender_clone.send(started).expect("never full");
+            let results = join_all(players.into_iter().map(|(player, fscript)| async move {
+                player.play_scalar_pattern(cmd.duration, fscript, speed).await
+            }))
+            .await;
+            info!(handle, "done");
+            for linked in handles.iter() {
+                access.game_stopped(*linked);
+                trace.dispatch_done(*linked);
+            }
+            if let Ok(mut linked) = linked_handles.lock() {
+                linked.remove(&handle);
+            }
+            let event = match results.into_iter().find_map(|x| x.err()) {
+                None => TkConnectionEvent::ActionDone(cmd.task, now.elapsed(), handle),
+                Some(err) => {
+                    inc_counter(TASKS_FAILED, &[]);
+                    TkConnectionEvent::ActionError(err.actuator, err.bp_error.to_string())
+                }
+            };
+            let _ = task_events.send(event.clone());
+            client_sender_clone.send(event.clone()).expect("never full");
+            status_sender_clone.send(event.clone()).expect("never full");
+        });
+        handle
+    }
+
+    fn select_devices(
+        &mut self,
+        body_parts: &[TkBodyPartExpr],
+        actuator_types: &[ActuatorType],
+    ) -> Vec<Arc<Actuator>> {
         let actuators = self.status.connected_actuators();
-        let devices = TkParams::filter_devices(
+        let mut devices = TkParams::filter_devices(
             &actuators,
-            &cmd.body_parts,
-            &cmd.actuator_types,
+            body_parts,
+            actuator_types,
             &self.settings.devices,
+            &self.settings.body_part_groups,
         );
-        let settings = devices.iter().map(|x| self.settings.get_or_create(x.identifier()).actuator_settings ).collect();
+        if self.settings.server.enabled && self.settings.server.priority == TkServerPriority::External {
+            devices.retain(|x| !self.device_access.used_by_external(x.identifier()));
+        }
+        devices
+    }
+
+    fn play(&mut self, devices: Vec<Arc<Actuator>>, settings: Vec<ActuatorSettings>, cmd: DeviceCommand) -> i32 {
+        self.play_loaded(devices, settings, cmd, None)
+    }
+
+    /// Like `play`, but the pattern is produced by `load` on a blocking thread
+    /// after the handle was returned
+    fn play_loaded(
+        &mut self,
+        devices: Vec<Arc<Actuator>>,
+        settings: Vec<ActuatorSettings>,
+        mut cmd: DeviceCommand,
+        load: Option<TkPatternLoader>,
+    ) -> i32 {
+        let task_clone = cmd.task.clone();
         let player = self.scheduler.create_player_with_settings(devices, settings);
         let handle = player.handle;
+        self.task_history.push(handle, &cmd);
+        self.device_access.game_started(
+            handle,
+            player.actuators.iter().map(|x| x.identifier().to_owned()).collect(),
+        );
+        self.trace.dispatched(handle, get_output_keys(&player.actuators));
 
         info!(handle, "dispatching {:?}", cmd.task);
         let client_sender_clone = self.client_event_sender.clone();
         let status_sender_clone = self.status_event_sender.clone();
+        let access = self.device_access.clone();
+        let task_events = self.events.clone();
+        let trace = self.trace.clone();
         self.runtime.spawn(async move {
             let now = Instant::now();
-            client_sender_clone
-                .send(TkConnectionEvent::ActionStarted(
-                    task_clone.clone(),
-                    player.actuators.clone(),
-                    cmd.body_parts,
-                    player.handle,
-                ))
-                .expect("never full");
+            let started = TkConnectionEvent::ActionStarted(
+                task_clone.clone(),
+                player.actuators.clone(),
+                cmd.body_parts,
+                player.handle,
+            );
+            inc_counter(TASKS_STARTED, &[]);
+            let _ = task_events.send(started.clone());
+            client_sender_clone.send(started).expect("never full");
+            if let Some(load) = load {
+                let loaded = tokio::task::spawn_blocking(load)
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|x| x);
+                match loaded {
+                    Ok(fscript) => {
+                        cmd.duration = audio_duration(&fscript, cmd.duration);
+                        cmd.fscript = Some(fscript);
+                    }
+                    Err(err) => {
+                        error!(handle, "{}", err);
+                        inc_counter(TASKS_FAILED, &[]);
+                        access.game_stopped(handle);
+                        trace.dispatch_done(handle);
+                        let event = TkConnectionEvent::ActionDone(task_clone, now.elapsed(), handle);
+                        let _ = task_events.send(event.clone());
+                        client_sender_clone.send(event.clone()).expect("never full");
+                        status_sender_clone.send(event).expect("never full");
+                        return;
+                    }
+                }
+            }
             let result = match cmd.task {
                 Task::Scalar(speed) => player.play_scalar(cmd.duration, speed).await,
                 Task::Pattern(speed, _, _) => {
@@ -229,10 +729,16 @@ impl Telekinesis {
                 Task::LinearStroke(speed, _) => player.play_linear_stroke(cmd.duration, speed, LinearRange::max()).await,
             };
             info!(handle, "done");
+            access.game_stopped(handle);
+            trace.dispatch_done(handle);
             let event = match result {
                 Ok(()) => TkConnectionEvent::ActionDone(task_clone, now.elapsed(), handle),
-                Err(err) => TkConnectionEvent::ActionError(err.actuator, err.bp_error.to_string()),
+                Err(err) => {
+                    inc_counter(TASKS_FAILED, &[]);
+                    TkConnectionEvent::ActionError(err.actuator, err.bp_error.to_string())
+                }
             };
+            let _ = task_events.send(event.clone());
             client_sender_clone.send(event.clone()).expect("never full");
             status_sender_clone.send(event.clone()).expect("never full");
         });
@@ -240,19 +746,73 @@ impl Telekinesis {
     }
 }
 
-pub fn in_process_connector() 
-    -> impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage> {
-    ButtplugInProcessClientConnectorBuilder::default()
-        .server(
-            ButtplugServerBuilder::default()
-                .comm_manager(BtlePlugCommunicationManagerBuilder::default())
-                .finish()
-                .expect("Could not create in-process-server."),
-        )
-        .finish()
+fn get_output_keys(actuators: &[Arc<Actuator>]) -> Vec<TkOutputKey> {
+    actuators
+        .iter()
+        .map(|x| (x.device.index(), x.index_in_device, x.actuator == ActuatorType::Position))
+        .collect()
 }
 
-async fn with_connector<T>(connector: T) -> ButtplugClient
+fn get_enabled_actuator_ids(settings: &TkSettings) -> Vec<String> {
+    settings
+        .get_enabled_devices()
+        .into_iter()
+        .map(|x| x.actuator_id)
+        .collect()
+}
+
+/// `None` if any expression is invalid, ignoring it could select every device
+fn parse_body_parts(body_parts: &[String]) -> Option<Vec<TkBodyPartExpr>> {
+    let (body_parts, invalid) = TkBodyPartExpr::parse_all(body_parts);
+    if !invalid.is_empty() {
+        error!("invalid body parts: {}", invalid.join(", "));
+        return None;
+    }
+    Some(body_parts)
+}
+
+pub fn in_process_connector(settings: &TkInProcessSettings) 
+    -> Result<impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>, String> {
+    let server = in_process_server(settings)
+        .map_err(|err| format!("Could not create in-process-server: {}", err))?;
+    Ok(ButtplugInProcessClientConnectorBuilder::default()
+        .server(server)
+        .finish())
+}
+
+pub fn in_process_server(settings: &TkInProcessSettings) -> Result<ButtplugServer, Error> {
+    info!(?settings, "creating in-process server");
+    let mut builder = ButtplugServerBuilder::default();
+    if settings.bluetooth {
+        builder.comm_manager(BtlePlugCommunicationManagerBuilder::default());
+    }
+    if settings.serial_port {
+        builder.comm_manager(SerialPortCommunicationManagerBuilder::default());
+    }
+    if settings.lovense_hid_dongle {
+        builder.comm_manager(LovenseHIDDongleCommunicationManagerBuilder::default());
+    }
+    if settings.lovense_serial_dongle {
+        builder.comm_manager(LovenseSerialDongleCommunicationManagerBuilder::default());
+    }
+    if settings.xinput {
+        #[cfg(target_os = "windows")]
+        builder.comm_manager(XInputDeviceCommunicationManagerBuilder::default());
+        #[cfg(not(target_os = "windows"))]
+        error!("xinput is only supported on windows");
+    }
+    if let Some(file) = &settings.device_config_file {
+        match fs::read_to_string(file) {
+            Ok(json) => {
+                builder.user_device_configuration_json(Some(json));
+            }
+            Err(err) => error!("Device configuration '{}' could not be read. Error: {}.", file, err),
+        }
+    }
+    Ok(builder.finish()?)
+}
+
+async fn with_connector<T>(connector: T) -> Result<ButtplugClient, (ButtplugClient, String)>
 where
     T: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
         + 'static,
@@ -260,8 +820,9 @@ where
     let buttplug = ButtplugClient::new("Telekinesis");
     if let Err(err) = buttplug.connect(connector).await {
         error!("Could not connect client. Error: {}.", err);
+        return Err((buttplug, err.to_string()));
     }
-    buttplug
+    Ok(buttplug)
 }
 
 impl fmt::Debug for Telekinesis {
@@ -282,9 +843,11 @@ mod tests {
     use crate::*;
     use bp_fakes::*;
     use bp_scheduler::speed::Speed;
-    use crate::pattern::read_pattern;
+    use crate::pattern::{get_pattern_names, read_pattern};
     use crate::status::TkConnectionStatus;
     use crate::telekinesis::in_process_connector;
+    use crate::fake_connection::connect_fakes;
+    use crate::spatial::{TkPosition, TkSpatialEffect};
     use super::Telekinesis;
 
     macro_rules! assert_timeout {
@@ -473,8 +1036,8 @@ mod tests {
         let settings = TkSettings::default();
         let pattern_path =
             String::from("../deploy/Data/SKSE/Plugins/Telekinesis/Patterns");
-        let mut tk = Telekinesis::connect_with(
-            || async move { in_process_connector() },
+        let mut tk = Telekinesis::try_connect_with(
+            || async move { in_process_connector(&TkInProcessSettings::default()) },
             Some(settings),
             TkConnectionType::Test,
         )
@@ -545,6 +1108,97 @@ mod tests {
         };
     }
 
+    #[test]
+    fn websocket_invalid_url_connection_status_error() {
+        let mut settings = TkSettings::default();
+        settings.connection = TkConnectionType::WebSocket(String::from("http://localhost:12345"));
+
+        let mut tk = Telekinesis::connect(settings).unwrap();
+        assert_timeout!(
+            matches!(tk.status.connection_status(), TkConnectionStatus::Failed(_)),
+            "Awaiting connection failure"
+        );
+    }
+
+    #[test]
+    fn vibrate_audio_returns_before_analysis() {
+        let dir = tempfile::tempdir().unwrap();
+        let audio = dir.path().join("tone.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&audio, spec).unwrap();
+        for i in 0..4000 {
+            writer.write_sample(((i % 40) as i16 - 20) * 800).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (mut tk, call_registry) =
+            wait_for_connection(vec![scalar(1, "vib1", ActuatorType::Vibrate)], None);
+        let patterns = dir.path().join("patterns");
+        tk.settings.pattern_path = patterns.to_str().unwrap().to_owned();
+        let cmd = |name: &str| {
+            DeviceCommand::from_inputs(
+                Task::Pattern(Speed::max(), ActuatorType::Vibrate, name.to_owned()),
+                &[ActuatorType::Vibrate],
+                0.0,
+                &[],
+                None,
+            )
+        };
+        assert_ne!(tk.dispatch_audio(cmd("tone"), audio.to_str().unwrap()), ERROR_HANDLE);
+        assert_ne!(tk.dispatch_audio(cmd("missing"), "missing.wav"), ERROR_HANDLE);
+        thread::sleep(Duration::from_secs(1));
+
+        assert!(!call_registry.get_device(1).is_empty());
+        assert_eq!(get_pattern_names(patterns.to_str().unwrap(), true).len(), 1);
+    }
+
+    #[test]
+    fn in_process_server_error_connection_status_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("devices.json");
+        std::fs::write(&config, "{ invalid").unwrap();
+        let mut settings = TkSettings::default();
+        settings.connection = TkConnectionType::InProcess;
+        settings.in_process.device_config_file = Some(config.to_str().unwrap().to_string());
+
+        let mut tk = Telekinesis::connect(settings).unwrap();
+        assert_timeout!(
+            matches!(tk.status.connection_status(), TkConnectionStatus::Failed(_)),
+            "Awaiting connection failure"
+        );
+    }
+
+    #[test]
+    fn scan_on_connect_stops_after_duration() {
+        let mut settings = TkSettings::default();
+        settings.scan.on_connect = true;
+        settings.scan.duration_secs = Some(1);
+
+        let (mut tk, _) =
+            wait_for_connection(vec![scalar(1, "vib1", ActuatorType::Vibrate)], Some(settings));
+        assert_timeout!(tk.status.is_scanning(), "Awaiting scan start");
+        assert_timeout!(!tk.status.is_scanning(), "Awaiting scan timeout");
+    }
+
+    #[test]
+    fn rescan_uses_current_settings() {
+        let mut settings = TkSettings::default();
+        settings.scan.rescan_interval_secs = Some(1);
+        settings.set_enabled("vib2 (Vibrate)", true);
+
+        let (mut tk, _) =
+            wait_for_connection(vec![scalar(1, "vib1", ActuatorType::Vibrate)], Some(settings));
+        assert_timeout!(tk.status.is_scanning(), "Awaiting rescan");
+        tk.settings.set_enabled("vib2 (Vibrate)", false);
+        tk.process_status();
+        assert_timeout!(!tk.status.is_scanning(), "Awaiting rescan stop");
+    }
+
     /// Settings
 
     #[test]
@@ -567,6 +1221,70 @@ mod tests {
         call_registry.get_device(1)[1].assert_strenth(0.0);
     }
 
+    #[test]
+    fn device_rules_enable_new_devices() {
+        let mut settings = TkSettings::default();
+        settings.device_rules.push(TkDeviceRule {
+            name: Some(String::from("vib*")),
+            actuator_type: None,
+            index: None,
+            action: TkDeviceRuleAction::Enable(vec![String::from("Nipples")]),
+        });
+        let (connector, call_registry) =
+            FakeDeviceConnector::new(vec![scalar(1, "vib1", ActuatorType::Vibrate)]);
+        let mut tk = Telekinesis::connect_with(
+            || async move { connector },
+            Some(settings),
+            TkConnectionType::Test,
+        )
+        .unwrap();
+        tk.await_connect(1);
+
+        test_cmd(
+            &mut tk,
+            Task::Scalar(Speed::max()),
+            Duration::from_millis(1),
+            vec![String::from("nipples")],
+            None,
+            &[ActuatorType::Vibrate],
+        );
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(tk.settings.get_enabled("vib1 (Vibrate)"));
+        call_registry.get_device(1)[0].assert_strenth(1.0);
+    }
+
+    #[test]
+    fn device_rules_ignore_devices() {
+        let mut settings = TkSettings::default();
+        settings.device_rules.push(TkDeviceRule {
+            name: Some(String::from("ignored")),
+            actuator_type: None,
+            index: None,
+            action: TkDeviceRuleAction::Ignore,
+        });
+        let (connector, call_registry) = FakeDeviceConnector::new(vec![
+            scalar(1, "vib1", ActuatorType::Vibrate),
+            scalar(2, "ignored", ActuatorType::Vibrate),
+        ]);
+        let mut tk = Telekinesis::connect_with(
+            || async move { connector },
+            Some(settings),
+            TkConnectionType::Test,
+        )
+        .unwrap();
+        tk.await_connect(1);
+        thread::sleep(Duration::from_millis(500));
+        assert!(!tk
+            .status
+            .get_known_actuator_ids()
+            .contains(&String::from("ignored (Vibrate)")));
+
+        tk.stop_all();
+        thread::sleep(Duration::from_millis(500));
+        call_registry.assert_unused(2);
+    }
+
     #[test]
     fn get_devices_contains_connected_devices() {
         // arrange
@@ -658,6 +1376,222 @@ mod tests {
         call_registry.assert_unused(2);
     }
 
+    #[test]
+    fn event_group_with_exclusion() {
+        let (mut tk, call_registry) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+                scalar(3, "vib3", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        tk.settings.body_part_groups.insert(
+            String::from("genitals"),
+            vec![String::from("vaginal"), String::from("anal")],
+        );
+        tk.settings.set_events("vib1 (Vibrate)", &[String::from("vaginal")]);
+        tk.settings.set_events("vib2 (Vibrate)", &[String::from("anal")]);
+        tk.settings.set_events("vib3 (Vibrate)", &[String::from("nipple_left")]);
+
+        test_cmd(
+            &mut tk,
+            Task::Scalar(Speed::max()),
+            Duration::from_millis(1),
+            vec![String::from("@genitals"), String::from("!anal")],
+            None,
+            &[ActuatorType::Vibrate],
+        );
+        thread::sleep(Duration::from_secs(1));
+
+        call_registry.get_device(1)[0].assert_strenth(1.0);
+        call_registry.assert_unused(2);
+        call_registry.assert_unused(3);
+    }
+
+    #[test]
+    fn event_weights_scale_speed() {
+        let (mut tk, call_registry) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        tk.settings.set_events("vib1 (Vibrate)", &[String::from("nipples:0.5")]);
+        tk.settings.set_events("vib2 (Vibrate)", &[String::from("clit:1.0")]);
+
+        test_cmd(
+            &mut tk,
+            Task::Scalar(Speed::max()),
+            Duration::from_millis(1),
+            vec![String::from("nipples"), String::from("clit")],
+            None,
+            &[ActuatorType::Vibrate],
+        );
+        thread::sleep(Duration::from_secs(1));
+
+        call_registry.get_device(1)[0].assert_strenth(0.5);
+        call_registry.get_device(2)[0].assert_strenth(1.0);
+    }
+
+    #[test]
+    fn direction_only_vibrates_close_devices() {
+        let (mut tk, call_registry) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        tk.settings.set_position("vib1 (Vibrate)", Some(TkPosition::parse("left").unwrap()));
+        tk.settings.set_position("vib2 (Vibrate)", Some(TkPosition::parse("right").unwrap()));
+
+        tk.dispatch_spatial(
+            DeviceCommand {
+                task: Task::Scalar(Speed::max()),
+                duration: Duration::from_millis(1),
+                fscript: None,
+                body_parts: vec![],
+                actuator_types: vec![ActuatorType::Vibrate],
+            },
+            TkSpatialEffect::parse_direction("left").unwrap(),
+        );
+        thread::sleep(Duration::from_secs(1));
+
+        call_registry.get_device(1)[0].assert_strenth(1.0);
+        call_registry.assert_unused(2);
+    }
+
+    #[test]
+    fn sweep_is_stopped_on_all_devices() {
+        let (mut tk, call_registry) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        tk.settings.set_position("vib1 (Vibrate)", Some(TkPosition::parse("left").unwrap()));
+        tk.settings.set_position("vib2 (Vibrate)", Some(TkPosition::parse("right").unwrap()));
+
+        let handle = tk.dispatch_spatial(
+            DeviceCommand {
+                task: Task::Scalar(Speed::max()),
+                duration: Duration::MAX,
+                fscript: None,
+                body_parts: vec![],
+                actuator_types: vec![ActuatorType::Vibrate],
+            },
+            TkSpatialEffect::parse_sweep("left>right").unwrap(),
+        );
+        thread::sleep(Duration::from_secs(3));
+        tk.stop(handle);
+        thread::sleep(Duration::from_secs(1));
+
+        assert!(!call_registry.get_device(1).is_empty());
+        assert!(!call_registry.get_device(2).is_empty());
+        call_registry.get_device(1).last().unwrap().assert_strenth(0.0);
+        call_registry.get_device(2).last().unwrap().assert_strenth(0.0);
+    }
+
+    #[test]
+    fn sweep_sends_events_for_the_first_handle() {
+        let (mut tk, _) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        tk.settings.set_position("vib1 (Vibrate)", Some(TkPosition::parse("left").unwrap()));
+        tk.settings.set_position("vib2 (Vibrate)", Some(TkPosition::parse("right").unwrap()));
+        let mut events = tk.subscribe_events();
+
+        let handle = tk.dispatch_spatial(
+            DeviceCommand {
+                task: Task::Scalar(Speed::max()),
+                duration: Duration::from_millis(500),
+                fscript: None,
+                body_parts: vec![],
+                actuator_types: vec![ActuatorType::Vibrate],
+            },
+            TkSpatialEffect::parse_sweep("left>right").unwrap(),
+        );
+        thread::sleep(Duration::from_secs(2));
+
+        let mut started = vec![];
+        let mut done = vec![];
+        while let Ok(event) = events.try_recv() {
+            match event {
+                TkConnectionEvent::ActionStarted(_, actuators, _, handle) => {
+                    started.push((handle, actuators.len()))
+                }
+                TkConnectionEvent::ActionDone(_, _, handle) => done.push(handle),
+                _ => {}
+            }
+        }
+        assert_eq!(started, vec![(handle, 2)]);
+        assert_eq!(done, vec![handle]);
+        assert!(tk.linked_handles.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fire_event_runs_matching_rule() {
+        let (mut tk, call_registry) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        tk.settings.set_events("vib1 (Vibrate)", &[String::from("clit")]);
+        tk.settings.event_rules.push(TkEventRule {
+            event: String::from("dd.vibrate.*"),
+            action: TkEventAction::Vibrate,
+            speed: 100,
+            duration_secs: Some(0.001),
+            body_parts: vec![String::from("clit")],
+        });
+
+        assert_eq!(tk.fire_event("unknown", 100, -1.0, vec![]), ERROR_HANDLE);
+        tk.fire_event("dd.vibrate.strong", 50, -1.0, vec![]);
+        thread::sleep(Duration::from_secs(1));
+
+        call_registry.get_device(1)[0].assert_strenth(0.5);
+        call_registry.assert_unused(2);
+    }
+
+    #[test]
+    fn trace_contains_sent_commands() {
+        let mut settings = TkSettings::default();
+        settings.trace.enabled = true;
+        let (mut tk, _) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            Some(settings),
+        );
+        tk.settings.set_events("vib1 (Vibrate)", &[String::from("clit")]);
+
+        test_cmd(
+            &mut tk,
+            Task::Scalar(Speed::new(50)),
+            Duration::from_millis(1),
+            vec![String::from("clit")],
+            None,
+            &[ActuatorType::Vibrate],
+        );
+        thread::sleep(Duration::from_secs(1));
+
+        let trace = tk.get_trace("vib1 (Vibrate)").unwrap();
+        assert_eq!(trace.first().unwrap().value, 0.5);
+        assert_eq!(trace.last().unwrap().value, 0.0);
+        assert!(tk.get_trace("vib2 (Vibrate)").unwrap().is_empty());
+        assert!(tk.get_trace("not connected").is_none());
+    }
+
     #[test]
     fn event_is_trimmed_and_ignores_casing() {
         let (mut tk, call_registry) =
@@ -678,6 +1612,31 @@ mod tests {
         call_registry.get_device(1)[1].assert_strenth(0.0);
     }
 
+    #[test]
+    fn invalid_body_parts_select_no_devices() {
+        let (mut tk, call_registry) = wait_for_connection(
+            vec![
+                scalar(1, "vib1", ActuatorType::Vibrate),
+                scalar(2, "vib2", ActuatorType::Vibrate),
+            ],
+            None,
+        );
+        for body_parts in [vec![String::from("a++b")], vec![String::from("clit"), String::from("!")]] {
+            let handle = test_cmd(
+                &mut tk,
+                Task::Scalar(Speed::max()),
+                Duration::from_millis(100),
+                body_parts,
+                None,
+                &[ActuatorType::Vibrate],
+            );
+            assert_eq!(handle, ERROR_HANDLE);
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert!(call_registry.get_device(1).is_empty());
+        assert!(call_registry.get_device(2).is_empty());
+    }
+
     /// Device Status
     #[test]
     fn get_device_connected() {
@@ -699,8 +1658,8 @@ mod tests {
 
     #[test]
     fn process_next_events_after_action_returns_1() {
-        let mut tk = Telekinesis::connect_with(
-            || async move { in_process_connector() },
+        let mut tk = Telekinesis::try_connect_with(
+            || async move { in_process_connector(&TkInProcessSettings::default()) },
             None,
             TkConnectionType::Test,
         )
@@ -718,8 +1677,8 @@ mod tests {
 
     #[test]
     fn process_next_events_works() {
-        let mut tk = Telekinesis::connect_with(
-            || async move { in_process_connector() },
+        let mut tk = Telekinesis::try_connect_with(
+            || async move { in_process_connector(&TkInProcessSettings::default()) },
             None,
             TkConnectionType::Test,
         )
@@ -748,19 +1707,13 @@ mod tests {
         devices: Vec<DeviceAdded>,
         settings: Option<TkSettings>,
     ) -> (Telekinesis, FakeConnectorCallRegistry) {
-        let (connector, call_registry) = FakeDeviceConnector::new(devices);
-        let count = connector.devices.len();
+        let count = devices.len();
 
         // act
         let mut settings = settings.unwrap_or(TkSettings::default());
         settings.pattern_path =
             String::from("../deploy/Data/SKSE/Plugins/Telekinesis/Patterns");
-        let mut tk = Telekinesis::connect_with(
-            || async move { connector },
-            Some(settings),
-            TkConnectionType::Test,
-        )
-        .unwrap();
+        let (mut tk, call_registry) = connect_fakes(devices, settings);
         tk.await_connect(count);
 
         for actuator in tk.status.actuators() {
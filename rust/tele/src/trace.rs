@@ -0,0 +1,482 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use buttplug::core::{
+    connector::{ButtplugConnector, ButtplugConnectorError, ButtplugConnectorResultFuture},
+    message::{
+        ActuatorType, ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage,
+        ButtplugDeviceMessage,
+    },
+};
+use funscript::{FSPoint, FScript};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc::Sender};
+
+use crate::{
+    metrics::{observe_duration, DISPATCH_LATENCY},
+    pattern::funscript_json,
+};
+
+/// Device index, index in the device and whether it is a linear output
+pub type TkOutputKey = (u32, u32, bool);
+
+/// Folder that `device.trace.export` writes into
+pub static TRACE_EXPORT_FOLDER: &str = "Data\\SKSE\\Plugins\\Telekinesis.traces";
+
+/// Opt-in trace of the commands that are sent to the devices
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TkTraceSettings {
+    pub enabled: bool,
+    /// Number of commands that are kept, older ones are dropped
+    pub capacity: usize,
+}
+
+impl Default for TkTraceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TkTraceEntry {
+    /// Milliseconds since the connection was created
+    pub at_ms: u64,
+    /// `None` for `StopAllDevices`
+    pub device_index: Option<u32>,
+    /// Index of the scalar or linear actuator, `None` for stop commands
+    pub index: Option<u32>,
+    pub linear: bool,
+    pub value: f64,
+    /// Movement duration of linear commands
+    pub duration_ms: Option<u32>,
+}
+
+impl TkTraceEntry {
+    pub fn applies_to(&self, device_index: u32, index: u32, linear: bool) -> bool {
+        self.device_index.map(|x| x == device_index).unwrap_or(true)
+            && self
+                .index
+                .map(|x| x == index && self.linear == linear)
+                .unwrap_or(true)
+    }
+}
+
+/// Ring buffer with the most recent device commands, they are also
+/// passed on to subscribers (OSC) regardless of the capacity
+#[derive(Debug, Clone)]
+pub struct TkOutputTrace {
+    started: Instant,
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<TkTraceEntry>>>,
+    /// Last value of each output, always tracked for metrics
+    levels: Arc<Mutex<HashMap<TkOutputKey, f64>>>,
+    /// Tasks by handle that did not send a device command yet, with the time of
+    /// their dispatch and their outputs
+    dispatches: Arc<Mutex<HashMap<i32, (Instant, Vec<TkOutputKey>)>>>,
+    outputs: broadcast::Sender<TkTraceEntry>,
+}
+
+impl TkOutputTrace {
+    pub fn new(settings: &TkTraceSettings) -> Self {
+        TkOutputTrace {
+            started: Instant::now(),
+            capacity: if settings.enabled {
+                settings.capacity
+            } else {
+                0
+            },
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            levels: Arc::new(Mutex::new(HashMap::new())),
+            dispatches: Arc::new(Mutex::new(HashMap::new())),
+            outputs: broadcast::channel(256).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TkTraceEntry> {
+        self.outputs.subscribe()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&self, mut entry: TkTraceEntry) {
+        self.update_level(&entry);
+        self.complete_dispatches(&entry);
+        let subscribed = self.outputs.receiver_count() > 0;
+        if !self.is_enabled() && !subscribed {
+            return;
+        }
+        entry.at_ms = self.started.elapsed().as_millis() as u64;
+        if subscribed {
+            let _ = self.outputs.send(entry.clone());
+        }
+        if !self.is_enabled() {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            while entries.len() >= self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+
+    fn update_level(&self, entry: &TkTraceEntry) {
+        let Ok(mut levels) = self.levels.lock() else {
+            return;
+        };
+        match (entry.device_index, entry.index) {
+            (Some(device_index), Some(index)) => {
+                levels.insert((device_index, index, entry.linear), entry.value);
+            }
+            _ => {
+                for ((device_index, index, linear), level) in levels.iter_mut() {
+                    if entry.applies_to(*device_index, *index, *linear) {
+                        *level = entry.value;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Measures the time until the first device command of the task
+    pub fn dispatched(&self, handle: i32, outputs: Vec<TkOutputKey>) {
+        if let Ok(mut dispatches) = self.dispatches.lock() {
+            dispatches.insert(handle, (Instant::now(), outputs));
+        }
+    }
+
+    /// The task ended, tasks that never send a command (i.e. speed 0) are not measured
+    pub fn dispatch_done(&self, handle: i32) {
+        if let Ok(mut dispatches) = self.dispatches.lock() {
+            dispatches.remove(&handle);
+        }
+    }
+
+    /// Tasks that are waiting for their first device command
+    pub fn pending_dispatches(&self) -> usize {
+        match self.dispatches.lock() {
+            Ok(dispatches) => dispatches.len(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Stops can't be attributed to a task, they are ignored
+    fn complete_dispatches(&self, entry: &TkTraceEntry) {
+        let (Some(device_index), Some(index)) = (entry.device_index, entry.index) else {
+            return;
+        };
+        let Ok(mut dispatches) = self.dispatches.lock() else {
+            return;
+        };
+        dispatches.retain(|_, (dispatched, outputs)| {
+            if !outputs.contains(&(device_index, index, entry.linear)) {
+                return true;
+            }
+            observe_duration(DISPATCH_LATENCY, &[], dispatched.elapsed());
+            false
+        });
+    }
+
+    /// Last value that was sent to the actuator, 0 if it did not receive any commands
+    pub fn get_level(&self, device_index: u32, index: u32, linear: bool) -> f64 {
+        match self.levels.lock() {
+            Ok(levels) => levels
+                .get(&(device_index, index, linear))
+                .copied()
+                .unwrap_or(0.0),
+            Err(_) => 0.0,
+        }
+    }
+
+    pub fn trace_message(&self, msg: &ButtplugCurrentSpecClientMessage) {
+        match msg {
+            ButtplugCurrentSpecClientMessage::ScalarCmd(cmd) => {
+                for scalar in cmd.scalars() {
+                    self.push(TkTraceEntry {
+                        at_ms: 0,
+                        device_index: Some(cmd.device_index()),
+                        index: Some(scalar.index()),
+                        linear: scalar.actuator_type() == ActuatorType::Position,
+                        value: scalar.scalar(),
+                        duration_ms: None,
+                    });
+                }
+            }
+            ButtplugCurrentSpecClientMessage::LinearCmd(cmd) => {
+                for vector in cmd.vectors() {
+                    self.push(TkTraceEntry {
+                        at_ms: 0,
+                        device_index: Some(cmd.device_index()),
+                        index: Some(vector.index()),
+                        linear: true,
+                        value: vector.position(),
+                        duration_ms: Some(vector.duration()),
+                    });
+                }
+            }
+            ButtplugCurrentSpecClientMessage::StopDeviceCmd(cmd) => self.push(TkTraceEntry {
+                at_ms: 0,
+                device_index: Some(cmd.device_index()),
+                index: None,
+                linear: false,
+                value: 0.0,
+                duration_ms: None,
+            }),
+            ButtplugCurrentSpecClientMessage::StopAllDevices(_) => self.push(TkTraceEntry {
+                at_ms: 0,
+                device_index: None,
+                index: None,
+                linear: false,
+                value: 0.0,
+                duration_ms: None,
+            }),
+            _ => {}
+        }
+    }
+
+    /// All traced commands that reached the given actuator, oldest first
+    pub fn get_entries(&self, device_index: u32, index: u32, linear: bool) -> Vec<TkTraceEntry> {
+        match self.entries.lock() {
+            Ok(entries) => entries
+                .iter()
+                .filter(|x| x.applies_to(device_index, index, linear))
+                .cloned()
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// `time_ms,value,duration_ms` lines
+pub fn trace_to_csv_lines(entries: &[TkTraceEntry]) -> Vec<String> {
+    entries
+        .iter()
+        .map(|x| {
+            format!(
+                "{},{},{}",
+                x.at_ms,
+                x.value,
+                x.duration_ms.map(|x| x.to_string()).unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+/// Funscript actions start at 0, linear moves are placed at the time their target is reached
+pub fn trace_to_fscript(entries: &[TkTraceEntry]) -> FScript {
+    let start = entries.first().map(|x| x.at_ms).unwrap_or(0);
+    FScript {
+        actions: entries
+            .iter()
+            .map(|x| FSPoint {
+                at: (x.at_ms - start + x.duration_ms.unwrap_or(0) as u64) as i32,
+                pos: (x.value * 100.0).round() as i32,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+pub fn trace_to_funscript(entries: &[TkTraceEntry]) -> String {
+    funscript_json(&trace_to_fscript(entries)).to_string()
+}
+
+/// Writes a `.funscript`, any other extension is written as csv. Traces can only be
+/// written into `folder`, the file name must not contain a path
+pub fn export_trace(
+    entries: &[TkTraceEntry],
+    folder: &str,
+    file_name: &str,
+) -> Result<PathBuf, String> {
+    let name = file_name.trim();
+    if name.is_empty() || name.contains(['/', '\\', ':']) || name.contains("..") {
+        return Err(format!("Invalid file name '{}'", file_name));
+    }
+    fs::create_dir_all(folder).map_err(|err| format!("Could not create '{}': {}", folder, err))?;
+    let path = Path::new(folder).join(name);
+    let content = if name.to_lowercase().ends_with(".funscript") {
+        trace_to_funscript(entries)
+    } else {
+        let mut lines = vec![String::from("time_ms,value,duration_ms")];
+        lines.extend(trace_to_csv_lines(entries));
+        lines.join("\n")
+    };
+    fs::write(&path, content)
+        .map_err(|err| format!("Could not write '{}': {}", path.display(), err))?;
+    Ok(path)
+}
+
+/// Passes all messages to the wrapped connector and traces device commands
+pub struct TkTracingConnector<T> {
+    inner: T,
+    trace: TkOutputTrace,
+}
+
+impl<T> TkTracingConnector<T> {
+    pub fn new(inner: T, trace: TkOutputTrace) -> Self {
+        TkTracingConnector { inner, trace }
+    }
+}
+
+impl<T> ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+    for TkTracingConnector<T>
+where
+    T: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>,
+{
+    fn connect(
+        &mut self,
+        message_sender: Sender<ButtplugCurrentSpecServerMessage>,
+    ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+        self.inner.connect(message_sender)
+    }
+
+    fn disconnect(&self) -> ButtplugConnectorResultFuture {
+        self.inner.disconnect()
+    }
+
+    fn send(&self, msg: ButtplugCurrentSpecClientMessage) -> ButtplugConnectorResultFuture {
+        self.trace.trace_message(&msg);
+        self.inner.send(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scalar(index: u32, value: f64) -> TkTraceEntry {
+        TkTraceEntry {
+            at_ms: 0,
+            device_index: Some(1),
+            index: Some(index),
+            linear: false,
+            value,
+            duration_ms: None,
+        }
+    }
+
+    #[test]
+    fn ring_buffer_drops_oldest() {
+        let trace = TkOutputTrace::new(&TkTraceSettings {
+            enabled: true,
+            capacity: 2,
+        });
+        trace.push(scalar(0, 0.1));
+        trace.push(scalar(0, 0.2));
+        trace.push(scalar(0, 0.3));
+        let values: Vec<f64> = trace
+            .get_entries(1, 0, false)
+            .iter()
+            .map(|x| x.value)
+            .collect();
+        assert_eq!(values, vec![0.2, 0.3]);
+    }
+
+    #[test]
+    fn disabled_trace_is_empty() {
+        let trace = TkOutputTrace::new(&TkTraceSettings::default());
+        trace.push(scalar(0, 1.0));
+        assert!(trace.get_entries(1, 0, false).is_empty());
+    }
+
+    #[test]
+    fn stop_applies_to_all_actuators() {
+        let trace = TkOutputTrace::new(&TkTraceSettings {
+            enabled: true,
+            capacity: 10,
+        });
+        trace.push(scalar(0, 1.0));
+        trace.push(scalar(1, 0.5));
+        trace.push(TkTraceEntry {
+            index: None,
+            ..scalar(0, 0.0)
+        });
+        assert_eq!(trace.get_entries(1, 0, false).len(), 2);
+        assert_eq!(trace.get_entries(1, 1, false).len(), 2);
+        assert!(trace.get_entries(2, 0, false).is_empty());
+        assert_eq!(trace.get_entries(1, 0, true).len(), 1);
+    }
+
+    #[test]
+    fn levels_are_tracked_when_disabled() {
+        let trace = TkOutputTrace::new(&TkTraceSettings::default());
+        trace.push(scalar(0, 0.5));
+        trace.push(scalar(1, 0.7));
+        assert_eq!(trace.get_level(1, 0, false), 0.5);
+        trace.push(TkTraceEntry {
+            device_index: Some(1),
+            index: None,
+            ..scalar(0, 0.0)
+        });
+        assert_eq!(trace.get_level(1, 0, false), 0.0);
+        assert_eq!(trace.get_level(1, 1, false), 0.0);
+    }
+
+    #[test]
+    fn dispatches_wait_for_their_first_command() {
+        let trace = TkOutputTrace::new(&TkTraceSettings::default());
+        trace.dispatched(1, vec![(1, 0, false), (1, 1, false)]);
+        trace.dispatched(2, vec![(2, 0, false)]);
+        trace.dispatched(3, vec![(3, 0, false)]);
+        assert_eq!(trace.pending_dispatches(), 3);
+        trace.push(TkTraceEntry {
+            index: None,
+            ..scalar(0, 0.0)
+        });
+        assert_eq!(trace.pending_dispatches(), 3);
+        trace.push(scalar(1, 0.5));
+        assert_eq!(trace.pending_dispatches(), 2);
+        trace.dispatch_done(3);
+        assert_eq!(trace.pending_dispatches(), 1);
+    }
+
+    #[test]
+    fn export_csv_and_funscript() {
+        let entries = vec![
+            TkTraceEntry {
+                at_ms: 100,
+                ..scalar(0, 0.5)
+            },
+            TkTraceEntry {
+                at_ms: 300,
+                linear: true,
+                duration_ms: Some(200),
+                ..scalar(0, 1.0)
+            },
+        ];
+        assert_eq!(trace_to_csv_lines(&entries), vec!["100,0.5,", "300,1,200"]);
+        let funscript: serde_json::Value =
+            serde_json::from_str(&trace_to_funscript(&entries)).unwrap();
+        assert_eq!(funscript["actions"][0]["at"], 0);
+        assert_eq!(funscript["actions"][0]["pos"], 50);
+        assert_eq!(funscript["actions"][1]["at"], 400);
+        assert_eq!(funscript["actions"][1]["pos"], 100);
+    }
+
+    #[test]
+    fn export_is_limited_to_the_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("traces");
+        let folder = folder.to_str().unwrap();
+        let entries = vec![scalar(0, 0.5)];
+        let path = export_trace(&entries, folder, "vib1.funscript").unwrap();
+        assert_eq!(path, Path::new(folder).join("vib1.funscript"));
+        assert!(path.exists());
+        assert!(export_trace(&entries, folder, "../vib1.csv").is_err());
+        assert!(export_trace(&entries, folder, "/tmp/vib1.csv").is_err());
+        assert!(export_trace(&entries, folder, "C:vib1.csv").is_err());
+        assert!(export_trace(&entries, folder, " ").is_err());
+    }
+}
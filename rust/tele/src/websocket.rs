@@ -0,0 +1,374 @@
+use std::{sync::Arc, time::SystemTime};
+
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::mpsc::{Receiver, Sender},
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, ClientConfig, ServerName,
+    },
+    TlsConnector,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+use url::Url;
+
+use buttplug::core::{
+    connector::{
+        transport::{ButtplugConnectorTransport, ButtplugTransportIncomingMessage},
+        ButtplugConnectorError, ButtplugConnectorResultFuture, ButtplugRemoteClientConnector,
+        ButtplugWebsocketClientTransport,
+    },
+    message::serializer::{ButtplugClientJSONSerializer, ButtplugSerializedMessage},
+};
+
+pub type TkWebSocketConnector =
+    ButtplugRemoteClientConnector<TkWebSocketTransport, ButtplugClientJSONSerializer>;
+
+/// Transport security options for remote (Intiface) connections
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TkWebSocketSettings {
+    /// Accept self-signed or otherwise untrusted certificates on `wss://` endpoints
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// Sha256 fingerprint (hex, optionally `:` separated) the server certificate must match
+    #[serde(default)]
+    pub certificate_fingerprint: Option<String>,
+}
+
+/// A validated websocket url, either `ws://` or `wss://`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TkWebSocketEndpoint {
+    pub url: Url,
+}
+
+impl TkWebSocketEndpoint {
+    /// Accepts full urls (`wss://host:port/path`) or plain `host:port`
+    /// endpoints, which are treated as `ws://` for backwards compatibility
+    pub fn parse(endpoint: &str) -> Result<TkWebSocketEndpoint, String> {
+        let endpoint = endpoint.trim();
+        if endpoint.is_empty() {
+            return Err("Websocket endpoint is empty".into());
+        }
+        let full_url = if endpoint.contains("://") {
+            endpoint.to_owned()
+        } else {
+            format!("ws://{}", endpoint)
+        };
+        let url = Url::parse(&full_url)
+            .map_err(|err| format!("Invalid websocket endpoint '{}': {}", endpoint, err))?;
+        match url.scheme() {
+            "ws" | "wss" => {}
+            scheme => {
+                return Err(format!(
+                    "Invalid websocket endpoint '{}': scheme '{}' is not supported, use ws:// or wss://",
+                    endpoint, scheme
+                ))
+            }
+        }
+        if url.host_str().map(|x| x.is_empty()).unwrap_or(true) {
+            return Err(format!(
+                "Invalid websocket endpoint '{}': missing host",
+                endpoint
+            ));
+        }
+        Ok(TkWebSocketEndpoint { url })
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.url.scheme() == "wss"
+    }
+
+    /// Validates the endpoint against the security settings and creates the buttplug connector.
+    /// Pinned certificates are checked on the connection that buttplug uses
+    pub async fn connect(
+        endpoint: &str,
+        settings: &TkWebSocketSettings,
+    ) -> Result<TkWebSocketConnector, String> {
+        let endpoint = TkWebSocketEndpoint::parse(endpoint)?;
+        let fingerprint = match &settings.certificate_fingerprint {
+            Some(fingerprint) => Some(normalize_fingerprint(fingerprint)?),
+            None => None,
+        };
+        if !endpoint.is_secure() {
+            if fingerprint.is_some() || settings.accept_invalid_certs {
+                info!(
+                    "certificate settings are ignored for unencrypted endpoint {}",
+                    endpoint.url
+                );
+            }
+            return Ok(ButtplugRemoteClientConnector::new(
+                TkWebSocketTransport::Buttplug(
+                    ButtplugWebsocketClientTransport::new_insecure_connector(endpoint.url.as_str()),
+                ),
+            ));
+        }
+        let transport = match fingerprint {
+            Some(fingerprint) => {
+                if settings.accept_invalid_certs {
+                    info!("accept_invalid_certs is ignored, the certificate is pinned");
+                }
+                debug!(url=?endpoint.url, "secure websocket with pinned certificate");
+                TkWebSocketTransport::Pinned(TkPinnedTransport {
+                    url: endpoint.url,
+                    fingerprint,
+                    disconnect: CancellationToken::new(),
+                })
+            }
+            None => {
+                debug!(url=?endpoint.url, bypass_cert_verify=settings.accept_invalid_certs, "secure websocket");
+                TkWebSocketTransport::Buttplug(
+                    ButtplugWebsocketClientTransport::new_secure_connector(
+                        endpoint.url.as_str(),
+                        settings.accept_invalid_certs,
+                    ),
+                )
+            }
+        };
+        Ok(ButtplugRemoteClientConnector::new(transport))
+    }
+}
+
+/// Buttplug's websocket transport, or a transport that only accepts the pinned
+/// certificate (buttplug can only verify against the system roots or not at all)
+pub enum TkWebSocketTransport {
+    Buttplug(ButtplugWebsocketClientTransport),
+    Pinned(TkPinnedTransport),
+}
+
+impl ButtplugConnectorTransport for TkWebSocketTransport {
+    fn connect(
+        &self,
+        outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+        incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+    ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+        match self {
+            TkWebSocketTransport::Buttplug(transport) => {
+                transport.connect(outgoing_receiver, incoming_sender)
+            }
+            TkWebSocketTransport::Pinned(transport) => {
+                transport.connect(outgoing_receiver, incoming_sender)
+            }
+        }
+    }
+
+    fn disconnect(self) -> ButtplugConnectorResultFuture {
+        match self {
+            TkWebSocketTransport::Buttplug(transport) => transport.disconnect(),
+            TkWebSocketTransport::Pinned(transport) => {
+                transport.disconnect.cancel();
+                async { Ok(()) }.boxed()
+            }
+        }
+    }
+}
+
+pub struct TkPinnedTransport {
+    url: Url,
+    fingerprint: String,
+    disconnect: CancellationToken,
+}
+
+impl TkPinnedTransport {
+    fn connect(
+        &self,
+        mut outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+        incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+    ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+        let url = self.url.clone();
+        let fingerprint = self.fingerprint.clone();
+        let disconnect = self.disconnect.clone();
+        async move {
+            let stream = pinned_tls_stream(&url, &fingerprint)
+                .await
+                .map_err(ButtplugConnectorError::ConnectorGenericError)?;
+            let (socket, _) = tokio_tungstenite::client_async(url.as_str(), stream)
+                .await
+                .map_err(|err| ButtplugConnectorError::ConnectorGenericError(err.to_string()))?;
+            let (mut sink, mut stream) = socket.split();
+            tokio::spawn(async move {
+                loop {
+                    select! {
+                        _ = disconnect.cancelled() => {
+                            let _ = sink.close().await;
+                            break;
+                        }
+                        outgoing = outgoing_receiver.recv() => {
+                            let message = match outgoing {
+                                Some(ButtplugSerializedMessage::Text(text)) => Message::Text(text),
+                                Some(ButtplugSerializedMessage::Binary(data)) => Message::Binary(data),
+                                None => break,
+                            };
+                            if let Err(err) = sink.send(message).await {
+                                debug!("websocket send failed: {}", err);
+                                break;
+                            }
+                        }
+                        incoming = stream.next() => {
+                            let message = match incoming {
+                                Some(Ok(Message::Text(text))) => ButtplugSerializedMessage::Text(text),
+                                Some(Ok(Message::Binary(data))) => ButtplugSerializedMessage::Binary(data),
+                                Some(Ok(Message::Close(_))) | None => {
+                                    let _ = incoming_sender
+                                        .send(ButtplugTransportIncomingMessage::Close(String::from("closed")))
+                                        .await;
+                                    break;
+                                }
+                                Some(Ok(_)) => continue,
+                                Some(Err(err)) => {
+                                    let _ = incoming_sender
+                                        .send(ButtplugTransportIncomingMessage::Close(err.to_string()))
+                                        .await;
+                                    break;
+                                }
+                            };
+                            if incoming_sender
+                                .send(ButtplugTransportIncomingMessage::Message(message))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                }
+                debug!("pinned websocket closed");
+            });
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Tls connection that only succeeds if the server certificate matches the fingerprint
+async fn pinned_tls_stream(url: &Url, fingerprint: &str) -> Result<TlsStream<TcpStream>, String> {
+    let host = url.host_str().unwrap_or_default().to_owned();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            fingerprint: fingerprint.to_owned(),
+        }))
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(host.as_str())
+        .map_err(|err| format!("Invalid server name '{}': {}", host, err))?;
+    let stream = TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(|err| format!("Could not reach {}:{}: {}", host, port, err))?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+        .map_err(|err| format!("Certificate of {} rejected: {}", url, err))?;
+    info!(?url, "certificate fingerprint verified");
+    Ok(stream)
+}
+
+struct PinnedCertificate {
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let actual = sha256_fingerprint(&end_entity.0);
+        if actual != self.fingerprint {
+            return Err(tokio_rustls::rustls::Error::General(format!(
+                "fingerprint mismatch, expected {} but got {}",
+                self.fingerprint, actual
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn sha256_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> Result<String, String> {
+    let normalized = fingerprint
+        .chars()
+        .filter(|x| *x != ':' && !x.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if normalized.len() != 64 || !normalized.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Err(format!(
+            "Invalid certificate fingerprint '{}', expected a sha256 hex string",
+            fingerprint
+        ));
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_endpoint_defaults_to_ws() {
+        let endpoint = TkWebSocketEndpoint::parse("127.0.0.1:12345").unwrap();
+        assert_eq!(endpoint.url.as_str(), "ws://127.0.0.1:12345/");
+        assert!(!endpoint.is_secure());
+    }
+
+    #[test]
+    fn secure_endpoint_with_path() {
+        let endpoint = TkWebSocketEndpoint::parse("wss://intiface.lan:12345/buttplug").unwrap();
+        assert_eq!(endpoint.url.path(), "/buttplug");
+        assert!(endpoint.is_secure());
+    }
+
+    #[test]
+    fn unsupported_scheme_is_rejected() {
+        let err = TkWebSocketEndpoint::parse("http://localhost:12345").unwrap_err();
+        assert!(err.contains("http"));
+    }
+
+    #[test]
+    fn empty_endpoint_is_rejected() {
+        assert!(TkWebSocketEndpoint::parse("   ").is_err());
+        assert!(TkWebSocketEndpoint::parse("ws://").is_err());
+    }
+
+    #[test]
+    fn pinned_certificate_must_match() {
+        let certificate = Certificate(b"certificate".to_vec());
+        let verify = |fingerprint: String| {
+            PinnedCertificate { fingerprint }.verify_server_cert(
+                &certificate,
+                &[],
+                &ServerName::try_from("intiface.lan").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+        };
+        assert!(verify(sha256_fingerprint(&certificate.0)).is_ok());
+        assert!(verify("ab".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn fingerprint_is_normalized() {
+        let colons = (0..32).map(|_| "AB").collect::<Vec<_>>().join(":");
+        assert_eq!(normalize_fingerprint(&colons).unwrap(), "ab".repeat(32));
+        assert!(normalize_fingerprint("abcd").is_err());
+    }
+}